@@ -1,10 +1,12 @@
-use std::time::Instant;
+use std::{io, time::Instant};
 
 use geojson::{Feature, FeatureCollection};
-use model::api::{calc_mode::CalculationMode, sort_by::SortBy, Precision, ToFeature};
+use model::api::{calc_mode::CalculationMode, Precision, ToFeature};
 
 use crate::{
-    plugin::{Folder, Plugin},
+    exclusions::Exclusions,
+    plugin::{Folder, Plugin, PluginOptions},
+    routing::RouteOptions,
     stats::Stats,
     utils,
 };
@@ -16,39 +18,55 @@ pub fn main(
     area: FeatureCollection,
     calculation_mode: CalculationMode,
     radius: Precision,
-    sort_by: SortBy,
+    lattice_trials: usize,
+    corridor: radius::Corridor,
     s2_level: u8,
     s2_size: u8,
-    route_split_level: u64,
     stats: &mut Stats,
-    routing_args: &str,
+    route_options: &RouteOptions,
+    exclusions: &Exclusions,
     bootstrapping_rags: &str,
-) -> Vec<Feature> {
+    plugin_options: &PluginOptions,
+) -> io::Result<Vec<Feature>> {
     let mut features = vec![];
 
     for feature in area.features {
         match &calculation_mode {
             CalculationMode::Radius => {
-                let mut new_radius = radius::BootstrapRadius::new(&feature, radius);
-                new_radius.sort(&sort_by, route_split_level, routing_args);
+                let mut new_radius = radius::BootstrapRadius::new(
+                    &feature,
+                    radius,
+                    exclusions,
+                    lattice_trials,
+                    corridor,
+                );
+                new_radius.sort(route_options)?;
 
                 *stats += &new_radius.stats;
-                features.push(new_radius.feature());
+                features.extend(new_radius.features());
             }
             CalculationMode::S2 => {
-                let mut new_s2 = s2::BootstrapS2::new(&feature, s2_level as u64, s2_size);
-                new_s2.sort(&sort_by, route_split_level, routing_args);
+                let mut new_s2 =
+                    s2::BootstrapS2::new(&feature, s2_level as u64, s2_size, exclusions);
+                new_s2.sort(route_options)?;
 
                 *stats += &new_s2.stats;
-                features.push(new_s2.feature());
+                features.extend(new_s2.features());
             }
             CalculationMode::Custom(plugin) => {
                 match Plugin::new(plugin, Folder::Bootstrap, 0, bootstrapping_rags) {
-                    Ok(plugin_manager) => {
+                    Ok(mut plugin_manager) => {
                         let time = Instant::now();
-                        match plugin_manager.run(feature.to_string()) {
+                        let mut plugin_stats = Stats::new(plugin.to_string(), 0);
+                        match plugin_manager
+                            .set_radius(radius)
+                            .set_options(plugin_options)
+                            .run_area(&feature, &mut plugin_stats)
+                        {
                             Ok(sorted_clusters) => {
-                                let mut plugin_stats = Stats::new(plugin.to_string(), 0);
+                                let mut exclusions = exclusions.clone();
+                                exclusions.add_interiors(&feature);
+                                let sorted_clusters = exclusions.snap(sorted_clusters);
                                 plugin_stats.set_cluster_time(time);
                                 plugin_stats.cluster_stats(0., &vec![], &sorted_clusters);
                                 features.push(sorted_clusters.to_feature(None));
@@ -56,21 +74,25 @@ pub fn main(
                             }
                             Err(e) => {
                                 log::error!("Error while running plugin: {}", e);
+                                stats.plugin_errors.push(e.to_string());
+                                return Err(e);
                             }
                         }
                     }
                     Err(e) => {
                         log::error!("Plugin not found: {}", e);
+                        stats.plugin_errors.push(e.to_string());
+                        return Err(e);
                     }
                 }
             }
         }
     }
-    features
+    Ok(features)
 }
 
 pub fn bootstrap_plugins() -> Vec<String> {
-    utils::get_plugin_list("algorithms/src/bootstrap/plugins").unwrap_or(vec![])
+    utils::get_plugin_list(&Folder::Bootstrap.dir()).unwrap_or(vec![])
 }
 
 pub fn all_bootstrap_options() -> Vec<String> {
@@ -79,3 +101,34 @@ pub fn all_bootstrap_options() -> Vec<String> {
     options.push("s2".to_string());
     options
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_plugin_is_an_error() {
+        let area = FeatureCollection {
+            features: vec![Feature::default()],
+            bbox: None,
+            foreign_members: None,
+        };
+        let mut stats = Stats::new(String::new(), 0);
+        let features = main(
+            area,
+            CalculationMode::Custom("does_not_exist".to_string()),
+            70.,
+            1,
+            radius::Corridor::default(),
+            15,
+            9,
+            &mut stats,
+            &RouteOptions::default(),
+            &Exclusions::default(),
+            "",
+            &PluginOptions::default(),
+        );
+        assert!(features.is_err());
+        assert_eq!(stats.plugin_errors.len(), 1);
+    }
+}
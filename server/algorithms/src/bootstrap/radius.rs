@@ -1,34 +1,148 @@
-use std::time::Instant;
+use std::{io, time::Instant};
 
-use crate::{routing, stats::Stats};
+use crate::{
+    exclusions::Exclusions,
+    routing::{self, RouteOptions},
+    stats::Stats,
+};
 
-use geo::{Contains, Destination, Distance, Extremes, Haversine, Point, Polygon};
+use geo::{
+    Bearing, BoundingRect, Contains, Destination, Distance, Haversine, InterpolatePoint,
+    LineString, MultiLineString, Point, Polygon,
+};
 use geojson::{Feature, Geometry, Value};
 use model::{
-    api::{Precision, ToFeature, ToGeometryVec, single_vec::SingleVec, sort_by::SortBy},
+    api::{Precision, ToFeature, ToGeometryVec, single_vec::SingleVec},
     db::sea_orm_active_enums::Type,
 };
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 
+/// Along a meridian, on the same sphere that [Haversine] uses
+const METERS_PER_DEGREE: Precision = 6_371_008.8 * std::f64::consts::PI / 180.;
+/// The lattice is symmetric under a rotation of 60 degrees
+const MAX_ROTATION: Precision = 60.;
+
+/// Shift and rotation of the hex lattice that the circles are placed on
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Lattice {
+    /// Fraction of the column and row spacing
+    pub offset: [Precision; 2],
+    /// Degrees, counterclockwise
+    pub rotation: Precision,
+}
+
+impl Lattice {
+    /// The first trial is the lattice without a shift or rotation, the others are spread evenly over
+    /// the offsets and rotations with additive recurrences so that any number of trials covers them well
+    pub fn trial(trial: usize) -> Self {
+        let trial = trial as Precision;
+        Self {
+            offset: [
+                (trial * 0.754_877_666_246_692_7).fract(),
+                (trial * 0.569_840_290_998_053_2).fract(),
+            ],
+            rotation: (trial * 0.618_033_988_749_894_9).fract() * MAX_ROTATION,
+        }
+    }
+}
+
+/// Placement of the circles along LineString and MultiLineString features
+#[derive(Debug, Clone, Copy)]
+pub struct Corridor {
+    /// Fraction of the diameter that neighboring circles along a lane share, from `0` to `0.9`
+    pub overlap: Precision,
+    /// Width in meters that is covered around the line, `0` only covers the line itself
+    pub width: Precision,
+}
+
+impl Default for Corridor {
+    fn default() -> Self {
+        Corridor {
+            overlap: 0.25,
+            width: 0.,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BootstrapRadius<'a> {
     feature: &'a Feature,
     result: SingleVec,
+    routes: Vec<SingleVec>,
     radius: Precision,
+    exclusions: Exclusions,
+    corridor: Corridor,
     pub stats: Stats,
 }
 
 impl<'a> BootstrapRadius<'a> {
-    pub fn new(feature: &'a Feature, radius: Precision) -> Self {
+    /// Circles are not placed inside the exclusions or the interior rings of the feature.
+    /// Out of `lattice_trials` lattices, the one with the fewest circles is kept.
+    /// LineString features are covered along the `corridor` instead
+    pub fn new(
+        feature: &'a Feature,
+        radius: Precision,
+        exclusions: &Exclusions,
+        lattice_trials: usize,
+        corridor: Corridor,
+    ) -> Self {
+        Self::build(
+            feature,
+            radius,
+            exclusions,
+            lattice_trials,
+            corridor,
+            |circles| -(circles.len() as Precision),
+        )
+    }
+
+    /// Same as [BootstrapRadius::new] but keeps the lattice with the highest `score`,
+    /// the earliest trial wins ties
+    pub fn new_scored<F>(
+        feature: &'a Feature,
+        radius: Precision,
+        exclusions: &Exclusions,
+        lattice_trials: usize,
+        score: F,
+    ) -> Self
+    where
+        F: Fn(&SingleVec) -> Precision,
+    {
+        Self::build(
+            feature,
+            radius,
+            exclusions,
+            lattice_trials,
+            Corridor::default(),
+            score,
+        )
+    }
+
+    fn build<F>(
+        feature: &'a Feature,
+        radius: Precision,
+        exclusions: &Exclusions,
+        lattice_trials: usize,
+        corridor: Corridor,
+        score: F,
+    ) -> Self
+    where
+        F: Fn(&SingleVec) -> Precision,
+    {
+        let mut exclusions = exclusions.clone();
+        exclusions.add_interiors(feature);
         let mut new_bootstrap = Self {
             feature,
             result: vec![],
+            routes: vec![],
             radius,
+            exclusions,
+            corridor,
             stats: Stats::new("BootstrapRadius".to_string(), 0),
         };
 
         let time = Instant::now();
-        new_bootstrap.result = new_bootstrap.run();
+        new_bootstrap.result = new_bootstrap.run(lattice_trials, score);
         new_bootstrap.stats.set_cluster_time(time);
         new_bootstrap
             .stats
@@ -37,27 +151,46 @@ impl<'a> BootstrapRadius<'a> {
         new_bootstrap
     }
 
-    pub fn sort(&mut self, sort_by: &SortBy, route_split_level: u64, routing_args: &str) {
-        self.result = routing::main(
+    pub fn sort(&mut self, route_options: &RouteOptions) -> io::Result<()> {
+        self.routes = routing::multi(
             &vec![],
             self.result.clone(),
-            sort_by,
-            route_split_level,
             self.radius,
             &mut self.stats,
-            routing_args,
-        );
+            route_options,
+        )?;
+        self.result = self.routes.concat();
+        Ok(())
     }
 
     pub fn result(self) -> SingleVec {
         self.result
     }
 
-    pub fn feature(self) -> Feature {
-        let mut new_feature = self.result.to_feature(Some(Type::CirclePokemon));
+    pub fn features(self) -> Vec<Feature> {
+        if self.routes.len() > 1 {
+            self.routes
+                .iter()
+                .enumerate()
+                .map(|(i, route)| self.route_feature(route.clone(), Some(i + 1)))
+                .collect()
+        } else {
+            vec![self.route_feature(self.result.clone(), None)]
+        }
+    }
+
+    fn route_feature(&self, route: SingleVec, device: Option<usize>) -> Feature {
+        let mut new_feature = route.to_feature(Some(Type::CirclePokemon));
 
         if let Some(name) = self.feature.property("__name") {
-            new_feature.set_property("__name", name.clone());
+            if let Some(device) = device {
+                new_feature.set_property(
+                    "__name",
+                    format!("{}-{}", name.as_str().unwrap_or_default(), device),
+                );
+            } else {
+                new_feature.set_property("__name", name.clone());
+            }
         }
         if let Some(geofence_id) = self.feature.property("__id") {
             new_feature.set_property("__geofence_id", geofence_id.clone());
@@ -66,31 +199,86 @@ impl<'a> BootstrapRadius<'a> {
         new_feature
     }
 
-    fn run(&self) -> SingleVec {
-        self.flatten_circles()
-            .into_iter()
-            .map(|p| [p.y(), p.x()])
-            .collect()
+    fn run<F>(&self, lattice_trials: usize, score: F) -> SingleVec
+    where
+        F: Fn(&SingleVec) -> Precision,
+    {
+        // the lattice isn't used along lines
+        let trials = if self.is_corridor() {
+            1
+        } else {
+            lattice_trials.max(1)
+        };
+        let mut best: Option<(Precision, usize, SingleVec)> = None;
+        for trial in 0..trials {
+            let circles: SingleVec = self
+                .flatten_circles(&Lattice::trial(trial))
+                .into_iter()
+                .map(|p| [p.y(), p.x()])
+                .collect();
+            // nothing to compare against with a single trial
+            let circle_score = if trials > 1 { score(&circles) } else { 0. };
+            if best
+                .as_ref()
+                .is_none_or(|(best_score, _, _)| circle_score > *best_score)
+            {
+                best = Some((circle_score, trial, circles));
+            }
+        }
+        let (best_score, trial, circles) = best.unwrap_or_default();
+        if trials > 1 {
+            let lattice = Lattice::trial(trial);
+            log::info!(
+                "[BOOTSTRAP] picked lattice {} of {} with {} circles | offset: [{:.2}, {:.2}] | rotation: {:.1} | score: {:.2}",
+                trial + 1,
+                trials,
+                circles.len(),
+                lattice.offset[0],
+                lattice.offset[1],
+                lattice.rotation,
+                best_score,
+            );
+        }
+        circles
     }
 
-    fn flatten_circles(&self) -> Vec<Point> {
+    fn is_corridor(&self) -> bool {
+        self.feature.geometry.as_ref().is_some_and(|geometry| {
+            matches!(
+                geometry.value,
+                Value::LineString(_) | Value::MultiLineString(_)
+            )
+        })
+    }
+
+    fn flatten_circles(&self, lattice: &Lattice) -> Vec<Point> {
         if let Some(geometry) = self.feature.geometry.clone() {
             match geometry.value {
+                Value::LineString(_) => LineString::<Precision>::try_from(geometry)
+                    .map(|line| self.corridor_circles(&line))
+                    .unwrap_or_default(),
+                Value::MultiLineString(_) => MultiLineString::<Precision>::try_from(geometry)
+                    .map(|lines| {
+                        lines
+                            .0
+                            .par_iter()
+                            .flat_map(|line| self.corridor_circles(line))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
                 Value::MultiPolygon(_) => geometry
                     .to_geometry_vec()
                     .par_iter()
-                    .flat_map(|geo| self.generate_circles(geo))
+                    .flat_map(|geo| self.generate_circles(geo, lattice))
                     .collect(),
-                _ => self.generate_circles(&geometry),
+                _ => self.generate_circles(&geometry, lattice),
             }
         } else {
             vec![]
         }
     }
 
-    fn generate_circles(&self, geometry: &Geometry) -> Vec<Point> {
-        let mut circles: Vec<Point> = vec![];
-
+    fn generate_circles(&self, geometry: &Geometry, lattice: &Lattice) -> Vec<Point> {
         let polygon = Polygon::<Precision>::try_from(geometry).unwrap();
         let external_points = polygon.exterior().points().collect::<Vec<Point>>();
         let internal_points: Vec<_> = polygon
@@ -98,46 +286,109 @@ impl<'a> BootstrapRadius<'a> {
             .into_iter()
             .map(|interior| interior.points().collect::<Vec<Point>>())
             .collect();
+        let covers = |current: &Point| {
+            (polygon.contains(current)
+                || point_line_distance(&external_points, current) <= self.radius
+                || internal_points
+                    .par_iter()
+                    .any(|internal| point_line_distance(internal, current) <= self.radius))
+                && !self.exclusions.contains_point(current)
+        };
 
         let x_mod = 0.75_f64.sqrt();
         let y_mod = 0.568_f64.sqrt();
 
-        let extremes = polygon.extremes().unwrap();
-        let max = Point::new(extremes.x_max.coord.x, extremes.y_max.coord.y);
-        let min = Point::new(extremes.x_min.coord.x, extremes.y_min.coord.y);
+        self.lattice_circles(
+            &polygon,
+            lattice,
+            x_mod * self.radius * 2.,
+            y_mod * self.radius * 2.,
+            covers,
+        )
+    }
 
-        let start = Haversine.destination(max, 90.0, self.radius * 1.5);
-        let end = Haversine.destination(min, 270., self.radius * 1.5);
-        let end = Haversine.destination(end, 180., self.radius);
+    /// Lanes of circles along the line, each lane covers a band as wide as the circles overlap
+    /// and enough parallel lanes are added on both sides to cover the corridor width
+    fn corridor_circles(&self, line: &LineString) -> Vec<Point> {
+        let overlap = self.corridor.overlap.clamp(0., 0.9);
+        let step = self.radius * 2. * (1. - overlap);
+        // half the width of the band that neighboring circles cover together
+        let band = (self.radius.powi(2) - (step / 2.).powi(2)).sqrt();
+        let lanes = if self.corridor.width > 0. && band > 0. {
+            (self.corridor.width / (band * 2.)).ceil().max(1.) as usize
+        } else {
+            1
+        };
+        let points: Vec<Point> = line.points().collect();
 
-        let mut row = 0;
-        let mut bearing = 270.;
-        let mut current = max;
+        (0..lanes)
+            .flat_map(|lane| {
+                let offset = (lane as Precision - (lanes - 1) as Precision / 2.) * band * 2.;
+                sample_line(&offset_line(&points, offset), step)
+            })
+            .filter(|point| !self.exclusions.contains_point(point))
+            .collect()
+    }
 
-        while current.y() > end.y() {
-            while (bearing == 270. && current.x() > end.x())
-                || (bearing == 90. && current.x() < start.x())
-            {
-                if polygon.contains(&current)
-                    || point_line_distance(&external_points, &current) <= self.radius
-                    || internal_points
-                        .par_iter()
-                        .any(|internal| point_line_distance(&internal, &current) <= self.radius)
-                {
+    /// Places the shifted and rotated lattice over the polygon, anchored to the north-east corner
+    /// of its bounding box where the rows of the unshifted lattice have always started.
+    /// Columns are `x_step` apart and every other row is shifted by half a column
+    fn lattice_circles<F>(
+        &self,
+        polygon: &Polygon,
+        lattice: &Lattice,
+        x_step: Precision,
+        y_step: Precision,
+        covers: F,
+    ) -> Vec<Point>
+    where
+        F: Fn(&Point) -> bool,
+    {
+        let Some(rect) = polygon.bounding_rect() else {
+            return vec![];
+        };
+        let anchor = rect.max();
+        let center_scale = rect.center().y.to_radians().cos();
+        // distance from the anchor to the opposite corner, plus the circles along the edges
+        let reach = (rect.width() * center_scale * METERS_PER_DEGREE)
+            .hypot(rect.height() * METERS_PER_DEGREE)
+            + self.radius * 2.;
+        let columns = (reach / x_step).ceil() as i64 + 1;
+        let rows = (reach / y_step).ceil() as i64 + 1;
+        let (sin, cos) = lattice.rotation.to_radians().sin_cos();
+        // skips the corners of the rotated lattice that can't be within one radius of the polygon
+        let lat_margin = self.radius / METERS_PER_DEGREE;
+        let lon_margin = lat_margin
+            / rect
+                .min()
+                .y
+                .abs()
+                .max(rect.max().y.abs())
+                .to_radians()
+                .cos();
+        let near = |point: &Point| {
+            point.y() >= rect.min().y - lat_margin
+                && point.y() <= rect.max().y + lat_margin
+                && point.x() >= rect.min().x - lon_margin
+                && point.x() <= rect.max().x + lon_margin
+        };
+
+        let mut circles = vec![];
+        for row in -rows..=rows {
+            let shift = if row.rem_euclid(2) == 1 { 0.5 } else { 0. };
+            let y = (row as Precision + lattice.offset[1]) * y_step;
+            for column in -columns..=columns {
+                let x = (column as Precision + lattice.offset[0] + shift) * x_step;
+                let east = x * cos - y * sin;
+                let north = x * sin + y * cos;
+                let lat = anchor.y + north / METERS_PER_DEGREE;
+                // scaled at the latitude of each circle so that the spacing holds across large areas
+                let lon = anchor.x + east / (lat.to_radians().cos() * METERS_PER_DEGREE);
+                let current = Point::new(lon, lat);
+                if near(&current) && covers(&current) {
                     circles.push(current);
                 }
-                current = Haversine.destination(current, bearing, x_mod * self.radius * 2.)
-            }
-            current = Haversine.destination(current, 180., y_mod * self.radius * 2.);
-
-            if row % 2 == 1 {
-                bearing = 270.;
-            } else {
-                bearing = 90.;
             }
-            current = Haversine.destination(current, bearing, x_mod * self.radius * 3.);
-
-            row += 1;
         }
         circles
     }
@@ -163,6 +414,78 @@ fn distance_to_segment(p: &Point, a: &Point, b: &Point) -> Precision {
     Haversine.distance(*p, pb)
 }
 
+/// Offsets every vertex of the line by `offset` meters, to the right of the direction of travel
+/// when positive, along the bisector of its segments so that the lanes stay parallel around bends
+fn offset_line(points: &[Point], offset: Precision) -> Vec<Point> {
+    if offset == 0. || points.len() < 2 {
+        return points.to_vec();
+    }
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, point)| {
+            let incoming = i
+                .checked_sub(1)
+                .map(|prev| Haversine.bearing(points[prev], *point));
+            let outgoing = points
+                .get(i + 1)
+                .map(|next| Haversine.bearing(*point, *next));
+            let (bearing, half_turn) = match (incoming, outgoing) {
+                (Some(incoming), Some(outgoing)) => {
+                    let (in_sin, in_cos) = incoming.to_radians().sin_cos();
+                    let (out_sin, out_cos) = outgoing.to_radians().sin_cos();
+                    let bearing = (in_sin + out_sin).atan2(in_cos + out_cos).to_degrees();
+                    let turn = (outgoing - incoming + 540.).rem_euclid(360.) - 180.;
+                    (bearing, turn.to_radians() / 2.)
+                }
+                (Some(bearing), None) | (None, Some(bearing)) => (bearing, 0.),
+                (None, None) => (0., 0.),
+            };
+            // the miter is capped at twice the offset so that sharp turns don't spike outwards
+            let distance = offset / half_turn.cos().max(0.5);
+            if distance > 0. {
+                Haversine.destination(*point, bearing + 90., distance)
+            } else {
+                Haversine.destination(*point, bearing - 90., -distance)
+            }
+        })
+        .collect()
+}
+
+/// Evenly spaced points along the line, including both ends, that are at most `step` meters apart
+fn sample_line(points: &[Point], step: Precision) -> Vec<Point> {
+    let lengths: Vec<Precision> = points
+        .windows(2)
+        .map(|segment| Haversine.distance(segment[0], segment[1]))
+        .collect();
+    let total: Precision = lengths.iter().sum();
+    let Some(first) = points.first() else {
+        return vec![];
+    };
+    if total == 0. {
+        return vec![*first];
+    }
+    let count = (total / step).ceil().max(1.) as usize;
+    let spacing = total / count as Precision;
+
+    let mut samples = Vec::with_capacity(count + 1);
+    let mut segment = 0;
+    let mut segment_start = 0.;
+    for i in 0..=count {
+        let target = i as Precision * spacing;
+        while segment < lengths.len() - 1 && segment_start + lengths[segment] < target {
+            segment_start += lengths[segment];
+            segment += 1;
+        }
+        samples.push(Haversine.point_at_distance_between(
+            points[segment],
+            points[segment + 1],
+            (target - segment_start).clamp(0., lengths[segment]),
+        ));
+    }
+    samples
+}
+
 fn point_line_distance(input: &Vec<Point>, point: &Point) -> Precision {
     let mut distance = Precision::MAX;
     for (i, line) in input.iter().enumerate() {
@@ -175,3 +498,358 @@ fn point_line_distance(input: &Vec<Point>, point: &Point) -> Precision {
     }
     distance
 }
+
+#[cfg(test)]
+mod tests {
+    use geojson::{Feature, FeatureCollection, Geometry, Value};
+
+    use super::*;
+
+    fn square(min: [Precision; 2], max: [Precision; 2]) -> Feature {
+        Feature {
+            geometry: Some(Geometry::new(Value::Polygon(vec![vec![
+                vec![min[1], min[0]],
+                vec![max[1], min[0]],
+                vec![max[1], max[0]],
+                vec![min[1], max[0]],
+                vec![min[1], min[0]],
+            ]]))),
+            ..Default::default()
+        }
+    }
+
+    fn circles(feature: &Feature, lattice_trials: usize) -> SingleVec {
+        BootstrapRadius::new(
+            feature,
+            70.,
+            &Exclusions::default(),
+            lattice_trials,
+            Corridor::default(),
+        )
+        .result()
+    }
+
+    fn line(coords: &[[Precision; 2]]) -> Vec<Vec<Precision>> {
+        coords.iter().map(|c| vec![c[1], c[0]]).collect()
+    }
+
+    fn corridor_circles(
+        feature: &Feature,
+        corridor: Corridor,
+        exclusions: &Exclusions,
+    ) -> Vec<Point> {
+        BootstrapRadius::new(feature, 70., exclusions, 1, corridor)
+            .result()
+            .into_iter()
+            .map(|p| Point::new(p[1], p[0]))
+            .collect()
+    }
+
+    /// About 850m going east
+    fn street() -> Feature {
+        Feature {
+            geometry: Some(Geometry::new(Value::LineString(line(&[
+                [40., -74.],
+                [40., -73.99],
+            ])))),
+            ..Default::default()
+        }
+    }
+
+    /// Number of lanes along [street], the lanes are told apart by their latitude
+    fn lanes(width: Precision) -> usize {
+        let corridor = Corridor {
+            width,
+            ..Default::default()
+        };
+        let mut lats: Vec<Precision> =
+            corridor_circles(&street(), corridor, &Exclusions::default())
+                .iter()
+                .map(|p| p.y())
+                .collect();
+        lats.sort_by(|a, b| a.total_cmp(b));
+        lats.dedup_by(|a, b| (*a - *b).abs() < 1e-5);
+        lats.len()
+    }
+
+    fn covered(circles: &[Point], point: Point) -> bool {
+        circles
+            .iter()
+            .any(|circle| Haversine.distance(*circle, point) <= 70. * 1.01)
+    }
+
+    #[test]
+    fn adds_lanes_for_the_corridor_width() {
+        // neighboring circles 105m apart cover a band of about 92m
+        assert_eq!(lanes(0.), 1);
+        assert_eq!(lanes(90.), 1);
+        assert_eq!(lanes(100.), 2);
+        assert_eq!(lanes(200.), 3);
+
+        let corridor = Corridor {
+            width: 200.,
+            ..Default::default()
+        };
+        let circles = corridor_circles(&street(), corridor, &Exclusions::default());
+        for i in 0..=20 {
+            let along = Point::new(-74. + i as Precision * 0.0005, 40.);
+            for bearing in [0., 180.] {
+                let edge = Haversine.destination(along, bearing, 100.);
+                assert!(covered(&circles, edge), "misses {:?}", edge);
+            }
+        }
+    }
+
+    #[test]
+    fn spaces_the_circles_by_the_overlap() {
+        let length = Haversine.distance(Point::new(-74., 40.), Point::new(-73.99, 40.));
+        let mut previous = 0;
+        for overlap in [0., 0.25, 0.5, 0.9] {
+            let corridor = Corridor { overlap, width: 0. };
+            let circles = corridor_circles(&street(), corridor, &Exclusions::default());
+            let step = 140. * (1. - overlap);
+            // as few circles as possible that are at most one step apart
+            assert_eq!(circles.len(), (length / step).ceil() as usize + 1);
+            for pair in circles.windows(2) {
+                let spacing = Haversine.distance(pair[0], pair[1]);
+                assert!(spacing <= step + 0.01, "{} > {}", spacing, step);
+            }
+            assert!(circles.len() > previous);
+            previous = circles.len();
+        }
+        // the overlap is capped at 0.9
+        let capped = corridor_circles(
+            &street(),
+            Corridor {
+                overlap: 2.,
+                width: 0.,
+            },
+            &Exclusions::default(),
+        );
+        assert_eq!(capped.len(), previous);
+    }
+
+    #[test]
+    fn covers_both_ends() {
+        let bent = Feature {
+            geometry: Some(Geometry::new(Value::LineString(line(&[
+                [40., -74.],
+                [40.003, -73.998],
+                [40.003, -73.99],
+            ])))),
+            ..Default::default()
+        };
+        for width in [0., 150.] {
+            let corridor = Corridor {
+                width,
+                ..Default::default()
+            };
+            let circles = corridor_circles(&bent, corridor, &Exclusions::default());
+            assert!(covered(&circles, Point::new(-74., 40.)));
+            assert!(covered(&circles, Point::new(-73.99, 40.003)));
+        }
+        let circles = corridor_circles(&bent, Corridor::default(), &Exclusions::default());
+        assert!(Haversine.distance(circles[0], Point::new(-74., 40.)) < 0.01);
+        assert!(Haversine.distance(circles[circles.len() - 1], Point::new(-73.99, 40.003)) < 0.01);
+    }
+
+    #[test]
+    fn covers_every_line_of_a_multi_line_string() {
+        let first = [[40., -74.], [40., -73.99]];
+        let second = [[40.02, -74.], [40.02, -73.995]];
+        let multi = Feature {
+            geometry: Some(Geometry::new(Value::MultiLineString(vec![
+                line(&first),
+                line(&second),
+            ]))),
+            ..Default::default()
+        };
+        let single = |coords: &[[Precision; 2]]| {
+            let feature = Feature {
+                geometry: Some(Geometry::new(Value::LineString(line(coords)))),
+                ..Default::default()
+            };
+            corridor_circles(&feature, Corridor::default(), &Exclusions::default()).len()
+        };
+        let circles = corridor_circles(&multi, Corridor::default(), &Exclusions::default());
+        assert_eq!(circles.len(), single(&first) + single(&second));
+        for end in [[40., -74.], [40., -73.99], [40.02, -74.], [40.02, -73.995]] {
+            assert!(covered(&circles, Point::new(end[1], end[0])));
+        }
+    }
+
+    #[test]
+    fn drops_the_circles_inside_exclusions() {
+        let exclusions = Exclusions::new(
+            &FeatureCollection {
+                features: vec![square([39.999, -73.996], [40.001, -73.994])],
+                bbox: None,
+                foreign_members: None,
+            },
+            false,
+        );
+        let all = corridor_circles(&street(), Corridor::default(), &Exclusions::default());
+        let circles = corridor_circles(&street(), Corridor::default(), &exclusions);
+        assert!(circles.len() < all.len());
+        assert!(
+            circles
+                .iter()
+                .all(|circle| !exclusions.contains_point(circle))
+        );
+        // the line is still covered on both sides of the exclusion
+        assert!(circles.iter().any(|circle| circle.x() < -73.996));
+        assert!(circles.iter().any(|circle| circle.x() > -73.994));
+    }
+
+    #[test]
+    fn offsets_to_the_right_of_the_line() {
+        let points = vec![Point::new(-74., 40.), Point::new(-73.99, 40.)];
+        let right = offset_line(&points, 50.);
+        let left = offset_line(&points, -50.);
+        for i in 0..points.len() {
+            assert!(right[i].y() < points[i].y());
+            assert!(left[i].y() > points[i].y());
+            assert!((Haversine.distance(right[i], points[i]) - 50.).abs() < 0.01);
+        }
+        assert_eq!(offset_line(&points, 0.), points);
+    }
+
+    #[test]
+    fn first_trial_is_not_shifted() {
+        assert_eq!(Lattice::trial(0), Lattice::default());
+        for trial in 1..64 {
+            let lattice = Lattice::trial(trial);
+            assert!(
+                lattice
+                    .offset
+                    .iter()
+                    .all(|offset| (0. ..1.).contains(offset))
+            );
+            assert!((0. ..MAX_ROTATION).contains(&lattice.rotation));
+        }
+    }
+
+    /// The row walk that placed the circles before there were lattice trials, starting at the
+    /// north-east corner of the bounding box and going back and forth to the south-west
+    fn row_walk(polygon: &Polygon, radius: Precision) -> Vec<Point> {
+        let external_points = polygon.exterior().points().collect::<Vec<Point>>();
+        let rect = polygon.bounding_rect().unwrap();
+        let max = Point::from(rect.max());
+        let min = Point::from(rect.min());
+        let x_step = 0.75_f64.sqrt() * radius * 2.;
+        let y_step = 0.568_f64.sqrt() * radius * 2.;
+
+        let start = Haversine.destination(max, 90.0, radius * 1.5);
+        let end = Haversine.destination(min, 270., radius * 1.5);
+        let end = Haversine.destination(end, 180., radius);
+
+        let mut circles = vec![];
+        let mut row = 0;
+        let mut bearing = 270.;
+        let mut current = max;
+        while current.y() > end.y() {
+            while (bearing == 270. && current.x() > end.x())
+                || (bearing == 90. && current.x() < start.x())
+            {
+                if polygon.contains(&current)
+                    || point_line_distance(&external_points, &current) <= radius
+                {
+                    circles.push(current);
+                }
+                current = Haversine.destination(current, bearing, x_step)
+            }
+            current = Haversine.destination(current, 180., y_step);
+            bearing = if row % 2 == 1 { 270. } else { 90. };
+            current = Haversine.destination(current, bearing, x_step * 1.5);
+            row += 1;
+        }
+        circles
+    }
+
+    #[test]
+    fn first_trial_keeps_the_row_walk_placement() {
+        let feature = Feature {
+            geometry: Some(Geometry::new(Value::Polygon(vec![line(&[
+                [40., -74.01],
+                [40., -74.],
+                [40.006, -73.998],
+                [40.01, -74.004],
+                [40.007, -74.012],
+                [40., -74.01],
+            ])]))),
+            ..Default::default()
+        };
+        let bootstrap = BootstrapRadius::new(
+            &feature,
+            70.,
+            &Exclusions::default(),
+            1,
+            Corridor::default(),
+        );
+        let polygon = Polygon::<Precision>::try_from(feature.geometry.as_ref().unwrap()).unwrap();
+        let baseline = row_walk(&polygon, 70.);
+        let circles = bootstrap.flatten_circles(&Lattice::trial(0));
+        assert!(!circles.is_empty());
+        assert!(circles.len() <= baseline.len());
+        for circle in &circles {
+            assert!(
+                baseline
+                    .iter()
+                    .any(|placed| Haversine.distance(*placed, *circle) < 1.),
+                "{:?} is off the row walk",
+                circle
+            );
+        }
+    }
+
+    #[test]
+    fn covers_the_area_with_every_lattice() {
+        let feature = square([40., -74.01], [40.01, -74.]);
+        let bootstrap = BootstrapRadius::new(
+            &feature,
+            70.,
+            &Exclusions::default(),
+            1,
+            Corridor::default(),
+        );
+        for trial in 0..8 {
+            let circles = bootstrap.flatten_circles(&Lattice::trial(trial));
+            for i in 0..=20 {
+                for j in 0..=20 {
+                    let point = Point::new(
+                        -74.01 + j as Precision * 0.0005,
+                        40. + i as Precision * 0.0005,
+                    );
+                    assert!(
+                        circles
+                            .iter()
+                            .any(|circle| Haversine.distance(*circle, point) <= 70. * 1.01),
+                        "trial {} misses {:?}",
+                        trial,
+                        point
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn more_trials_never_add_circles() {
+        let feature = square([40., -74.01], [40.01, -74.]);
+        let single = circles(&feature, 1);
+        assert!(!single.is_empty());
+        assert!(circles(&feature, 16).len() <= single.len());
+    }
+
+    #[test]
+    fn keeps_the_highest_score() {
+        let feature = square([40., -74.01], [40.01, -74.]);
+        let most =
+            BootstrapRadius::new_scored(&feature, 70., &Exclusions::default(), 16, |circles| {
+                circles.len() as Precision
+            })
+            .result();
+        assert!(most.len() >= circles(&feature, 1).len());
+        assert!(most.len() >= circles(&feature, 16).len());
+    }
+}
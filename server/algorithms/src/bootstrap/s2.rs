@@ -1,7 +1,9 @@
-use std::time::Instant;
+use std::{io, time::Instant};
 
 use crate::{
-    routing, rtree,
+    exclusions::Exclusions,
+    routing::{self, RouteOptions},
+    rtree,
     s2::{BuildGrid, Dir, ToPointArray, Traverse},
     stats::Stats,
 };
@@ -10,7 +12,7 @@ use geo::{ConvexHull, Intersects, MultiPolygon, Polygon};
 use geojson::{Feature, Value};
 use hashbrown::HashSet;
 use model::{
-    api::{single_vec::SingleVec, sort_by::SortBy, Precision, ToFeature},
+    api::{single_vec::SingleVec, Precision, ToFeature},
     db::sea_orm_active_enums::Type,
 };
 use rayon::{
@@ -23,18 +25,25 @@ use s2::{cell::Cell, cellid::CellID, rect::Rect, region::RegionCoverer};
 pub struct BootstrapS2<'a> {
     feature: &'a Feature,
     result: SingleVec,
+    routes: Vec<SingleVec>,
     level: u64,
     size: u8,
+    exclusions: Exclusions,
     pub stats: Stats,
 }
 
 impl<'a> BootstrapS2<'a> {
-    pub fn new(feature: &'a Feature, level: u64, size: u8) -> Self {
+    /// Cells centered inside the exclusions or the interior rings of the feature are skipped
+    pub fn new(feature: &'a Feature, level: u64, size: u8, exclusions: &Exclusions) -> Self {
+        let mut exclusions = exclusions.clone();
+        exclusions.add_interiors(feature);
         let mut new_bootstrap = Self {
             feature,
             result: vec![],
+            routes: vec![],
             level,
             size,
+            exclusions,
             stats: Stats::new("BootstrapS2".to_string(), 0),
         };
 
@@ -48,27 +57,46 @@ impl<'a> BootstrapS2<'a> {
         new_bootstrap
     }
 
-    pub fn sort(&mut self, sort_by: &SortBy, route_split_level: u64, routing_args: &str) {
-        self.result = routing::main(
+    pub fn sort(&mut self, route_options: &RouteOptions) -> io::Result<()> {
+        self.routes = routing::multi(
             &vec![],
             self.result.clone(),
-            sort_by,
-            route_split_level,
             0.,
             &mut self.stats,
-            routing_args,
-        );
+            route_options,
+        )?;
+        self.result = self.routes.concat();
+        Ok(())
     }
 
     pub fn result(self) -> SingleVec {
         self.result
     }
 
-    pub fn feature(self) -> Feature {
-        let mut new_feature = self.result.to_feature(Some(Type::CirclePokemon));
+    pub fn features(self) -> Vec<Feature> {
+        if self.routes.len() > 1 {
+            self.routes
+                .iter()
+                .enumerate()
+                .map(|(i, route)| self.route_feature(route.clone(), Some(i + 1)))
+                .collect()
+        } else {
+            vec![self.route_feature(self.result.clone(), None)]
+        }
+    }
+
+    fn route_feature(&self, route: SingleVec, device: Option<usize>) -> Feature {
+        let mut new_feature = route.to_feature(Some(Type::CirclePokemon));
 
         if let Some(name) = self.feature.property("__name") {
-            new_feature.set_property("__name", name.clone());
+            if let Some(device) = device {
+                new_feature.set_property(
+                    "__name",
+                    format!("{}-{}", name.as_str().unwrap_or_default(), device),
+                );
+            } else {
+                new_feature.set_property("__name", name.clone());
+            }
         }
         if let Some(geofence_id) = self.feature.property("__id") {
             new_feature.set_property("__geofence_id", geofence_id.clone());
@@ -272,7 +300,12 @@ impl<'a> BootstrapS2<'a> {
                     .find_any(|polygon| polygon.intersects(&grid_poly))
                     .is_some()
                 {
-                    Some(self.find_center_cell(&grid).point_array())
+                    let center = self.find_center_cell(&grid).point_array();
+                    if self.exclusions.contains(&center) {
+                        None
+                    } else {
+                        Some(center)
+                    }
                 } else {
                     None
                 }
@@ -0,0 +1,218 @@
+use std::time::{Duration, Instant};
+
+use model::api::{Precision, cluster_mode::ClusterMode, single_vec::SingleVec};
+use serde::Serialize;
+
+use crate::{exclusions::Exclusions, radii::Radii, stats::Stats, weights::Weights};
+
+use super::{
+    ClusterOptions,
+    greedy::{DENSITY, Greedy},
+    refine::{self, RefineOptions},
+};
+
+/// Densest candidate generation that is tried
+const MAX_DENSITY: usize = 64;
+
+/// Best solution after each round of the time budgeted clustering
+#[derive(Debug, Clone, Serialize)]
+pub struct ScorePoint {
+    /// Seconds since clustering started
+    pub time: Precision,
+    /// [Stats::get_score], or [Stats::get_weighted_score] when the data points are weighted
+    pub score: Precision,
+    pub clusters: usize,
+    /// Candidates between each point and its neighbors in this round
+    pub density: usize,
+}
+
+fn score(
+    stats: &Stats,
+    radius: Precision,
+    weights: &Weights,
+    radii: &Radii,
+    data_points: &SingleVec,
+    clusters: &SingleVec,
+) -> Precision {
+    let mut round = Stats::new(String::new(), stats.min_points);
+    round.weights = weights.clone();
+    round.radii = radii.clone();
+    round.cluster_stats(radius, data_points, clusters);
+    if round.weights.is_weighted() {
+        round.get_weighted_score()
+    } else {
+        round.get_score() as Precision
+    }
+}
+
+/// Runs a quick `Fast` or `Balanced` round of `greedy` and keeps improving on the result until
+/// `options.time_limit_ms` runs out, each round refines its solution and the next one tries again
+/// with denser candidates. Only the first round always completes, rounds that aren't expected to
+/// finish in time are skipped and the one still running at the deadline is dropped
+pub fn run(
+    greedy: &mut Greedy,
+    data_points: &SingleVec,
+    radius: Precision,
+    exclusions: &Exclusions,
+    stats: &Stats,
+    options: &ClusterOptions,
+) -> (SingleVec, Vec<ScorePoint>) {
+    let time = Instant::now();
+    let weights = &options.weights;
+    let radii = &options.radii;
+    let time_limit = Duration::from_millis(options.time_limit_ms);
+    let deadline = time + time_limit;
+    let timed_out = |next_round: Duration| Instant::now() + next_round >= deadline;
+    let refine_options = RefineOptions {
+        iterations: 0,
+        deadline: Some(deadline),
+        ..Default::default()
+    };
+    let refine = |clusters: SingleVec| {
+        if timed_out(Duration::ZERO) {
+            return clusters;
+        }
+        refine::main(
+            data_points,
+            clusters,
+            radius,
+            weights,
+            radii,
+            exclusions,
+            &refine_options,
+        )
+        .0
+    };
+
+    let mut density = DENSITY;
+    // the first round has to finish, whatever the requested mode costs
+    if options.cluster_mode != ClusterMode::Fast {
+        greedy.set_cluster_mode(ClusterMode::Balanced);
+    }
+    greedy.set_density(density);
+    let mut best = refine(exclusions.snap(greedy.run(data_points)));
+    let mut best_score = score(stats, radius, weights, radii, data_points, &best);
+    let mut timeline = vec![ScorePoint {
+        time: time.elapsed().as_secs_f64(),
+        score: best_score,
+        clusters: best.len(),
+        density,
+    }];
+    let mut last_round = time.elapsed();
+
+    greedy
+        .set_cluster_mode(ClusterMode::Balanced)
+        .set_deadline(Some(deadline));
+    while density < MAX_DENSITY {
+        density *= 2;
+        // twice the density means about twice the candidates
+        if timed_out(last_round * 2) {
+            break;
+        }
+        let round_time = Instant::now();
+        greedy.set_density(density);
+        let clusters = greedy.run(data_points);
+        if greedy.timed_out() {
+            break;
+        }
+        let clusters = refine(exclusions.snap(clusters));
+        let round_score = score(stats, radius, weights, radii, data_points, &clusters);
+        if round_score < best_score {
+            best = clusters;
+            best_score = round_score;
+        }
+        timeline.push(ScorePoint {
+            time: time.elapsed().as_secs_f64(),
+            score: best_score,
+            clusters: best.len(),
+            density,
+        });
+        last_round = round_time.elapsed();
+    }
+    log::info!(
+        "[ANYTIME] {} rounds in {:.2}s of {:.2}s | best score: {:.1} with {} clusters",
+        timeline.len(),
+        time.elapsed().as_secs_f32(),
+        time_limit.as_secs_f32(),
+        best_score,
+        best.len()
+    );
+    (best, timeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(size: usize) -> SingleVec {
+        (0..size * size)
+            .map(|i| {
+                [
+                    40. + (i / size) as Precision * 0.0007,
+                    -74. + (i % size) as Precision * 0.0007,
+                ]
+            })
+            .collect()
+    }
+
+    fn run_for(data_points: &SingleVec, options: &ClusterOptions) -> (SingleVec, Vec<ScorePoint>) {
+        let mut greedy = Greedy::default();
+        greedy
+            .set_cluster_mode(options.cluster_mode.clone())
+            .set_radius(70.)
+            .set_min_points(1);
+        let stats = Stats::new(String::new(), 1);
+        run(
+            &mut greedy,
+            data_points,
+            70.,
+            &Exclusions::default(),
+            &stats,
+            options,
+        )
+    }
+
+    #[test]
+    fn first_round_completes_past_the_deadline() {
+        let data_points = grid(30);
+        let options = ClusterOptions {
+            cluster_mode: ClusterMode::Best,
+            time_limit_ms: 1,
+            ..Default::default()
+        };
+        let time = Instant::now();
+        let (clusters, timeline) = run_for(&data_points, &options);
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].density, DENSITY);
+        // the expensive mode was skipped for the first round
+        assert!(time.elapsed() < Duration::from_secs(10));
+
+        let mut stats = Stats::new(String::new(), 1);
+        stats.cluster_stats(70., &data_points, &clusters);
+        assert_eq!(stats.points_covered, data_points.len());
+    }
+
+    #[test]
+    fn improves_until_the_densest_round() {
+        let data_points = grid(10);
+        let options = ClusterOptions {
+            time_limit_ms: 60_000,
+            ..Default::default()
+        };
+        let (clusters, timeline) = run_for(&data_points, &options);
+        assert_eq!(timeline.last().unwrap().density, MAX_DENSITY);
+        assert!(timeline.windows(2).all(|w| w[1].score <= w[0].score));
+        assert_eq!(timeline.last().unwrap().clusters, clusters.len());
+    }
+
+    #[test]
+    fn stops_picking_clusters_at_the_deadline() {
+        let mut greedy = Greedy::default();
+        greedy
+            .set_radius(70.)
+            .set_min_points(2)
+            .set_deadline(Some(Instant::now()));
+        assert!(greedy.timed_out());
+        assert!(greedy.run(&grid(10)).is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+use std::{cmp::Ordering, collections::BinaryHeap, time::Instant};
+
+use hashbrown::HashMap;
+use model::api::{Precision, single_vec::SingleVec};
+use s2::{cellid::CellID, latlng::LatLng};
+
+use crate::{radii::Radii, rtree, weights::Weights};
+
+/// Points covered by each cluster, as indices into the deduplicated data points
+pub struct Coverage {
+    weights: Vec<Precision>,
+    covers: Vec<Vec<usize>>,
+}
+
+impl Coverage {
+    /// Clusters cover the data points within `radius`, or within their own radius from `radii`
+    pub fn radius(
+        data_points: &SingleVec,
+        clusters: &SingleVec,
+        radius: Precision,
+        weights: &Weights,
+        radii: &Radii,
+    ) -> Self {
+        let tree = rtree::spawn_with_radii(radius, data_points, weights, radii);
+        let mut index = HashMap::new();
+        let mut point_weights = vec![];
+        for point in tree.iter() {
+            index.entry(point.cell_id.0).or_insert_with(|| {
+                point_weights.push(point.weight);
+                point_weights.len() - 1
+            });
+        }
+        let covers = clusters
+            .iter()
+            .map(|cluster| {
+                let mut cover: Vec<usize> = tree
+                    .locate_all_at_point(cluster)
+                    .filter_map(|point| index.get(&point.cell_id.0).copied())
+                    .collect();
+                cover.sort_unstable();
+                cover.dedup();
+                cover
+            })
+            .collect();
+        Self {
+            weights: point_weights,
+            covers,
+        }
+    }
+
+    /// Clusters cover the data points within their S2 cell, the same way [super::s2::cluster] counts them
+    pub fn s2(data_points: &SingleVec, clusters: &SingleVec, level: u8, weights: &Weights) -> Self {
+        let cell = |point: &[Precision; 2]| {
+            CellID::from(LatLng::from_degrees(point[0], point[1]))
+                .parent(level as u64)
+                .0
+        };
+        let mut cells = HashMap::<u64, Vec<usize>>::new();
+        for (i, point) in data_points.iter().enumerate() {
+            cells.entry(cell(point)).or_default().push(i);
+        }
+        let covers = clusters
+            .iter()
+            .map(|cluster| cells.get(&cell(cluster)).cloned().unwrap_or_default())
+            .collect();
+        Self {
+            weights: data_points.iter().map(|point| weights.get(point)).collect(),
+            covers,
+        }
+    }
+}
+
+struct Candidate {
+    gain: Precision,
+    index: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    /// Highest gain first, ties go to the earlier cluster
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.gain
+            .total_cmp(&other.gain)
+            .then(other.index.cmp(&self.index))
+    }
+}
+
+/// Picks up to `budget` clusters that cover the most weight, ordered by their marginal gain.
+/// Clusters that would add less than `min_gain` are left out.
+/// Returns the clusters and the fraction of the total weight covered after each of them
+pub fn select(
+    clusters: SingleVec,
+    coverage: &Coverage,
+    budget: usize,
+    min_gain: Precision,
+) -> (SingleVec, Vec<Precision>) {
+    let time = Instant::now();
+    let mut covered = vec![false; coverage.weights.len()];
+    let gain = |cover: &Vec<usize>, covered: &Vec<bool>| -> Precision {
+        cover
+            .iter()
+            .filter(|&&p| !covered[p])
+            .map(|&p| coverage.weights[p])
+            .sum()
+    };
+
+    // gains only shrink as points get covered, so a candidate whose refreshed gain
+    // is still on top of the heap is the best one left
+    let mut heap: BinaryHeap<Candidate> = coverage
+        .covers
+        .iter()
+        .enumerate()
+        .map(|(index, cover)| Candidate {
+            gain: gain(cover, &covered),
+            index,
+        })
+        .collect();
+    let total_weight: Precision = coverage.weights.iter().sum();
+    let mut selected = SingleVec::new();
+    let mut curve = vec![];
+    let mut total = 0.;
+    while selected.len() < budget {
+        let Some(candidate) = heap.pop() else {
+            break;
+        };
+        if candidate.gain <= 0. || candidate.gain < min_gain {
+            break;
+        }
+        let current = gain(&coverage.covers[candidate.index], &covered);
+        if heap.peek().is_some_and(|next| current < next.gain) {
+            heap.push(Candidate {
+                gain: current,
+                index: candidate.index,
+            });
+            continue;
+        }
+        if current <= 0. || current < min_gain {
+            break;
+        }
+        for &point in coverage.covers[candidate.index].iter() {
+            covered[point] = true;
+        }
+        total += current;
+        selected.push(clusters[candidate.index]);
+        curve.push(if total_weight > 0. {
+            total / total_weight
+        } else {
+            0.
+        });
+    }
+    log::info!(
+        "[BUDGET] selected {} of {} clusters, covering {:.1} of {:.1} in {:.2}s",
+        selected.len(),
+        clusters.len(),
+        total,
+        total_weight,
+        time.elapsed().as_secs_f32()
+    );
+    (selected, curve)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` points 20m apart, north of `center`
+    fn group(center: [Precision; 2], count: usize) -> SingleVec {
+        (0..count)
+            .map(|i| [center[0] + i as Precision * 0.0002, center[1]])
+            .collect()
+    }
+
+    fn setup() -> (SingleVec, SingleVec) {
+        let mut data_points = group([40., -74.], 4);
+        data_points.extend(group([40.01, -74.], 2));
+        data_points.extend(group([40.02, -74.], 1));
+        let clusters = vec![[40.02, -74.], [40.0003, -74.], [40.0101, -74.]];
+        (data_points, clusters)
+    }
+
+    #[test]
+    fn picks_the_biggest_gains_first() {
+        let (data_points, clusters) = setup();
+        let coverage = Coverage::radius(
+            &data_points,
+            &clusters,
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+        );
+        let (selected, curve) = select(clusters.clone(), &coverage, 2, 1.);
+        assert_eq!(selected, vec![clusters[1], clusters[2]]);
+        assert_eq!(curve.len(), 2);
+        assert!((curve[0] - 4. / 7.).abs() < 1e-9);
+        assert!((curve[1] - 6. / 7.).abs() < 1e-9);
+
+        let (selected, curve) = select(clusters, &coverage, usize::MAX, 1.);
+        assert_eq!(selected.len(), 3);
+        assert!((curve[2] - 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn skips_gains_below_the_minimum() {
+        let (data_points, clusters) = setup();
+        let coverage = Coverage::radius(
+            &data_points,
+            &clusters,
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+        );
+        let (selected, _) = select(clusters, &coverage, usize::MAX, 2.);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn uses_the_weights() {
+        let (data_points, clusters) = setup();
+        let weights = Weights::new(&data_points, &[1., 1., 1., 1., 1., 1., 10.]);
+        let coverage = Coverage::radius(&data_points, &clusters, 70., &weights, &Radii::default());
+        let (selected, curve) = select(clusters.clone(), &coverage, 1, 1.);
+        assert_eq!(selected, vec![clusters[0]]);
+        assert!((curve[0] - 10. / 16.).abs() < 1e-9);
+    }
+}
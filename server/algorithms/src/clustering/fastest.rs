@@ -1,10 +1,10 @@
 use geo::Coord;
 use hashbrown::HashSet;
-use model::api::single_vec::SingleVec;
+use model::api::{Precision, single_vec::SingleVec};
 use rstar::PointDistance;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::project::Plane;
+use crate::{project::Plane, weights::Weights};
 
 #[derive(Debug, Clone)]
 struct BoundingBox {
@@ -49,7 +49,8 @@ impl ClusterCoords for Coord {
 
 type PointTuple = (i32, i32);
 type PointInfo = (BoundingBox, bool, bool, Vec<String>);
-type ClusterMap = HashMap<PointTuple, PointInfo>;
+// ordered so neighboring cells are merged in the same order on every run
+type ClusterMap = BTreeMap<PointTuple, PointInfo>;
 
 trait FromKey {
     fn from_key(&self) -> [f64; 2];
@@ -64,18 +65,39 @@ impl FromKey for String {
     }
 }
 
-pub fn main(input: &SingleVec, radius: f64, min_points: usize) -> Vec<[f64; 2]> {
+pub fn main(input: &SingleVec, radius: f64, min_points: usize, weights: &Weights) -> Vec<[f64; 2]> {
     let plane = Plane::new(input).radius(radius);
     let output = plane.project();
 
-    let point_map = cluster(output, min_points);
+    let weight_map: HashMap<String, Precision> = if weights.is_weighted() {
+        output
+            .iter()
+            .zip(input.iter())
+            .map(|(coord, point)| (coord.to_key(), weights.get(point)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let weight = |values: &[String]| -> Precision {
+        if weight_map.is_empty() {
+            values.len() as Precision
+        } else {
+            values
+                .iter()
+                .map(|key| weight_map.get(key).copied().unwrap_or(1.))
+                .sum()
+        }
+    };
+    let min_weight = min_points as Precision;
+
+    let point_map = cluster(output, min_weight, weight);
 
     let output = {
         let mut seen_map: HashSet<String> = HashSet::new();
         let return_value: SingleVec = point_map
             .into_iter()
             .filter_map(|(key, values)| {
-                if values.len() >= min_points {
+                if weight(&values) >= min_weight {
                     for point in values.into_iter() {
                         seen_map.insert(point);
                     }
@@ -97,13 +119,16 @@ fn update(point_map: &mut ClusterMap, key: PointTuple, p: Coord) {
     });
 }
 
-fn cluster(points: Vec<Coord>, min_points: usize) -> HashMap<String, Vec<String>> {
+fn cluster<F>(points: Vec<Coord>, min_weight: Precision, weight: F) -> BTreeMap<String, Vec<String>>
+where
+    F: Fn(&[String]) -> Precision,
+{
     let sqrt2: f64 = 2.0_f64.sqrt();
     let additive_factor: f64 = sqrt2 / 2.;
     let sqrt2_x_one_point_five_minus_one: f64 = (sqrt2 * 1.5) - 1.;
     let sqrt2_x_one_point_five_plus_one: f64 = (sqrt2 * 1.5) + 1.;
 
-    let mut udc_point_map: ClusterMap = HashMap::new();
+    let mut udc_point_map: ClusterMap = BTreeMap::new();
 
     for p in points.into_iter() {
         let v = (p.x / sqrt2).floor() as i32;
@@ -174,7 +199,7 @@ fn cluster(points: Vec<Coord>, min_points: usize) -> HashMap<String, Vec<String>
             .entry(key)
             .or_insert((BoundingBox::new(p), true, true, vec![p.to_key()]));
     }
-    let mut point_map_return: HashMap<String, Vec<String>> = HashMap::new();
+    let mut point_map_return: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
     let mut process_final = |coord: Coord, points_to_process: Vec<String>| {
         if points_to_process.len() > 0 {
@@ -216,7 +241,7 @@ fn cluster(points: Vec<Coord>, min_points: usize) -> HashMap<String, Vec<String>
                 if lower_left.distance_2(&upper_right) <= 4. {
                     let mut combined = points.clone();
                     combined.extend(found_cluster.3.clone());
-                    if combined.len() > min_points {
+                    if weight(&combined) > min_weight {
                         let [x, y] = lower_left.midpoint(&upper_right);
                         process_final(Coord { x, y }, combined);
                         udc_point_map
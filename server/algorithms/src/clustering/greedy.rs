@@ -5,28 +5,78 @@ use model::api::{GetBbox, Precision, cluster_mode::ClusterMode, single_vec::Sing
 use ::s2::cellid::CellID;
 use rayon::{
     prelude::{
-        IntoParallelIterator, IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator,
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+        IntoParallelRefMutIterator, ParallelIterator,
     },
     slice::ParallelSliceMut,
 };
 use rstar::RTree;
-use std::{io::Write, time::Instant};
+use std::{cmp::Ordering, collections::BinaryHeap, io::Write, time::Instant};
 use sysinfo::System;
 
 use crate::{
     bootstrap::radius,
     clustering::rtree::{cluster::Cluster, point::Point},
-    rtree::{self, SortDedupe, point::ToPoint},
+    exclusions::Exclusions,
+    radii::Radii,
+    rtree::{self, SortDedupe, point::ToPoint, weight_of},
     s2,
     utils::info_log,
+    weights::Weights,
 };
 
+/// Default number of candidates between a point and each of its neighbors
+pub const DENSITY: usize = 8;
+/// Number of candidates taken off the heap between two progress logs
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// Potential cluster in the greedy heap, ordered by the weight of the points it would newly cover,
+/// then by how many of them there are, by the weight of all of its points and by cell id
+struct Candidate {
+    unique_weight: Precision,
+    unique_count: usize,
+    all_weight: Precision,
+    cell_id: u64,
+    /// Index of the cluster in the associated clusters
+    index: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.unique_weight
+            .total_cmp(&other.unique_weight)
+            .then(self.unique_count.cmp(&other.unique_count))
+            .then(self.all_weight.total_cmp(&other.all_weight))
+            .then(other.cell_id.cmp(&self.cell_id))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
 pub struct Greedy {
     cluster_mode: ClusterMode,
     cluster_split_level: u64,
     max_clusters: usize,
     min_points: usize,
     radius: Precision,
+    density: usize,
+    weights: Weights,
+    radii: Radii,
+    exclusions: Exclusions,
+    lattice_trials: usize,
+    deadline: Option<Instant>,
 }
 
 impl Default for Greedy {
@@ -37,6 +87,12 @@ impl Default for Greedy {
             max_clusters: usize::MAX,
             min_points: 1,
             radius: 70.,
+            density: DENSITY,
+            weights: Weights::default(),
+            radii: Radii::default(),
+            exclusions: Exclusions::default(),
+            lattice_trials: 1,
+            deadline: None,
         }
     }
 }
@@ -50,6 +106,12 @@ impl<'a> Greedy {
         self.radius = radius;
         self
     }
+    /// Number of candidates generated between each point and its neighbors
+    /// by the `Fast` and `Balanced` modes
+    pub fn set_density(&mut self, density: usize) -> &mut Self {
+        self.density = density.max(1);
+        self
+    }
     pub fn set_max_clusters(&mut self, max_clusters: usize) -> &mut Self {
         self.max_clusters = max_clusters;
         self
@@ -58,6 +120,37 @@ impl<'a> Greedy {
         self.min_points = min_points;
         self
     }
+    /// Clusters maximize the covered weight rather than the number of covered points,
+    /// `min_points` is then the minimum weight of a cluster
+    pub fn set_weights(&mut self, weights: Weights) -> &mut Self {
+        self.weights = weights;
+        self
+    }
+    /// Data points with a radius of their own are covered within that radius instead of `radius`
+    pub fn set_radii(&mut self, radii: Radii) -> &mut Self {
+        self.radii = radii;
+        self
+    }
+    /// Potential clusters inside the exclusions are discarded
+    pub fn set_exclusions(&mut self, exclusions: Exclusions) -> &mut Self {
+        self.exclusions = exclusions;
+        self
+    }
+    /// Number of shifted and rotated lattices that are tried by the `Honeycomb` mode
+    pub fn set_lattice_trials(&mut self, lattice_trials: usize) -> &mut Self {
+        self.lattice_trials = lattice_trials.max(1);
+        self
+    }
+    /// Stops picking clusters once the deadline passes, the result is then incomplete
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) -> &mut Self {
+        self.deadline = deadline;
+        self
+    }
+    /// Whether the deadline passed, [Greedy::run] may have stopped early
+    pub fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
     pub fn set_cluster_split_level(&mut self, cluster_split_level: u64) -> &mut Self {
         self.cluster_split_level = cluster_split_level;
         self
@@ -70,7 +163,10 @@ impl<'a> Greedy {
         let return_set = if self.cluster_split_level == 0 {
             self.setup(points)
         } else {
-            let cell_maps = s2::create_cell_map(&points, self.cluster_split_level);
+            let mut cell_maps: Vec<_> = s2::create_cell_map(points, self.cluster_split_level)
+                .into_iter()
+                .collect();
+            cell_maps.sort_by_key(|(key, _)| *key);
 
             let mut return_set = HashSet::new();
             std::thread::scope(|s| {
@@ -104,14 +200,17 @@ impl<'a> Greedy {
         };
 
         log::info!("finished in {:.2}s", time.elapsed().as_secs_f32());
-        return_set.into_iter().map(|p| p.center).collect()
+        let mut clusters: SingleVec = return_set.into_iter().map(|p| p.center).collect();
+        // the order of a hash set changes from run to run
+        clusters.sort_by(|a, b| a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1])));
+        clusters
     }
 
     fn generate_clusters(&self, point: &Point, neighbors: Vec<&Point>) -> HashSet<Point> {
         let mut clusters = HashSet::new();
         for neighbor in neighbors.iter() {
-            for i in 0..=7 {
-                let ratio = i as Precision / 8 as Precision;
+            for i in 0..self.density {
+                let ratio = i as Precision / self.density as Precision;
                 let new_point = point.interpolate(neighbor, ratio, 0., 0.);
                 clusters.insert(new_point);
                 if self.cluster_mode == ClusterMode::Balanced {
@@ -141,18 +240,24 @@ impl<'a> Greedy {
     fn gen_estimated_clusters(&self, tree: &RTree<Point>) -> Vec<Point> {
         let tree_points: Vec<&Point> = tree.iter().map(|p| p).collect();
 
-        let clusters = tree_points
+        let mut clusters: Vec<Point> = tree_points
             .par_iter()
-            .map(|point| {
-                let neighbors = tree
-                    .locate_all_at_point(&point.center)
-                    .into_iter()
-                    .collect();
+            .flat_map_iter(|point| {
+                let neighbors = tree.locate_all_at_point(&point.center).collect();
                 self.generate_clusters(point, neighbors)
             })
-            .reduce(HashSet::new, |a, b| a.union(&b).cloned().collect());
-
-        clusters.into_iter().collect()
+            .collect();
+
+        // candidates in the same cell are duplicates, the same one is kept regardless of the
+        // order or the number of threads they were generated with
+        clusters.par_sort_by(|a, b| {
+            a.cell_id
+                .cmp(&b.cell_id)
+                .then(a.center[0].total_cmp(&b.center[0]))
+                .then(a.center[1].total_cmp(&b.center[1]))
+        });
+        clusters.dedup_by(|a, b| a.cell_id == b.cell_id);
+        clusters
     }
 
     fn flat_map_cells(&self, cell: CellID, point_tree: &'a RTree<Point>) -> Vec<CellID> {
@@ -181,7 +286,7 @@ impl<'a> Greedy {
             .collect()
     }
 
-    fn get_honeycomb_clusters(&self, points: &SingleVec) -> Vec<Point> {
+    fn get_honeycomb_clusters(&self, points: &SingleVec, point_tree: &RTree<Point>) -> Vec<Point> {
         let bbox = points.get_bbox();
         let bbox_unwrap = bbox.clone().unwrap();
 
@@ -200,42 +305,80 @@ impl<'a> Greedy {
             }),
             ..Default::default()
         };
-        radius::BootstrapRadius::new(&feat, self.radius)
-            .result()
-            .into_iter()
-            .map(|p| Point::new(self.radius, 20, p))
-            .collect()
+        // the lattice that covers the most weight per circle that can become a cluster
+        let score = |circles: &SingleVec| {
+            let mut covered = HashSet::new();
+            let mut weight = 0.;
+            let mut useful = 0;
+            for circle in circles {
+                let points: Vec<&Point> = point_tree.locate_all_at_point(circle).collect();
+                if weight_of(&points) < self.min_points as Precision {
+                    continue;
+                }
+                useful += 1;
+                for point in points {
+                    if covered.insert(point.cell_id) {
+                        weight += point.weight;
+                    }
+                }
+            }
+            if useful == 0 {
+                0.
+            } else {
+                weight / useful as Precision
+            }
+        };
+        // spaced for the smallest radius so that no point falls between the circles
+        radius::BootstrapRadius::new_scored(
+            &feat,
+            self.radii.min(self.radius),
+            &self.exclusions,
+            self.lattice_trials,
+            score,
+        )
+        .result()
+        .into_iter()
+        .map(|p| Point::new(self.radius, 20, p))
+        .collect()
     }
 
     fn associate_clusters(
         &'a self,
         points: &'a SingleVec,
         point_tree: &'a RTree<Point>,
-    ) -> Vec<Vec<Cluster<'a>>> {
+    ) -> Vec<Cluster<'a>> {
         let sys = System::new_all();
         let sys_mem = (sys.available_memory() / 1024 / 1024) as usize;
 
         let time = Instant::now();
         let clusters_with_data: Vec<Cluster> = match self.cluster_mode {
-            ClusterMode::Honeycomb => self.get_honeycomb_clusters(points),
+            ClusterMode::Honeycomb => self.get_honeycomb_clusters(points, point_tree),
             ClusterMode::Better | ClusterMode::Best => self.get_s2_clusters(points, point_tree),
             ClusterMode::Fast => self.gen_estimated_clusters(point_tree),
             _ => {
                 let time = Instant::now();
-                let neighbor_tree: RTree<Point> = rtree::spawn(self.radius * 2., points);
+                let neighbor_tree: RTree<Point> = rtree::spawn_with_radii(
+                    self.radius * 2.,
+                    points,
+                    &Weights::default(),
+                    &self.radii.scale(2.),
+                );
                 log::info!("created neighbor tree {:.2}s", time.elapsed().as_secs_f32());
                 self.gen_estimated_clusters(&neighbor_tree)
             }
         }
         .into_par_iter()
         .filter_map(|cluster| {
+            if self.exclusions.contains(&cluster.center) {
+                return None;
+            }
             let mut points: Vec<&Point> = point_tree
                 .locate_all_at_point(&cluster.center)
                 .collect::<Vec<&Point>>();
             if let Some(point) = point_tree.locate_at_point(&cluster.center) {
                 points.push(point);
             }
-            if points.len() < self.min_points {
+            if weight_of(&points) < self.min_points as Precision {
                 // log::debug!("Empty");
                 None
             } else {
@@ -264,35 +407,13 @@ impl<'a> Greedy {
             );
         }
 
-        let time = Instant::now();
-        let max = clusters_with_data
-            .par_iter()
-            .map(|cluster| cluster.all.len())
-            .max()
-            .unwrap_or(100);
-        log::info!(
-            "found best cluster ({}) {:.2}s",
-            max,
-            time.elapsed().as_secs_f32(),
-        );
-
-        let time = Instant::now();
-        let mut clustered_clusters = vec![vec![]; max + 1];
-
-        for cluster in clusters_with_data.into_iter() {
-            clustered_clusters[cluster.all.len()].push(cluster);
-        }
-        log::info!(
-            "sorted clusters by size in {:.2}s",
-            time.elapsed().as_secs_f32(),
-        );
-
-        clustered_clusters
+        clusters_with_data
     }
 
     fn setup(&'a self, points: &SingleVec) -> HashSet<Point> {
         let time = Instant::now();
-        let point_tree: RTree<Point> = rtree::spawn(self.radius, points);
+        let point_tree: RTree<Point> =
+            rtree::spawn_with_radii(self.radius, points, &self.weights, &self.radii);
         log::info!("created point tree in {:.2}s", time.elapsed().as_secs_f32());
 
         let clusters_with_data = self.associate_clusters(points, &point_tree);
@@ -308,108 +429,82 @@ impl<'a> Greedy {
         }
     }
 
-    fn cluster(&'a self, clusters_with_data: Vec<Vec<Cluster<'a>>>) -> HashSet<Cluster<'a>> {
+    /// Lazy greedy: repeatedly takes the candidate covering the most uncovered weight.
+    /// Scores only go down as clusters are picked, so a candidate whose score is still
+    /// up to date when it reaches the top of the heap is the best one left
+    fn cluster(&'a self, clusters_with_data: Vec<Cluster<'a>>) -> HashSet<Cluster<'a>> {
         let time = Instant::now();
         log::info!("starting initial solution",);
         let mut new_clusters = HashSet::<Cluster>::new();
         let mut blocked_points = HashSet::<&Point>::new();
 
-        let mut current = clusters_with_data.len() - 1;
-        let total_iterations = current - self.min_points + 1;
-        let mut current_iteration = 0;
+        let mut heap: BinaryHeap<Candidate> = clusters_with_data
+            .par_iter()
+            .enumerate()
+            .map(|(index, cluster)| {
+                let mut points = cluster.all.clone();
+                points.sort_dedupe();
+                Candidate {
+                    unique_weight: weight_of(&points),
+                    unique_count: points.len(),
+                    all_weight: weight_of(&cluster.all),
+                    cell_id: cluster.point.cell_id.0,
+                    index,
+                }
+            })
+            .collect::<Vec<Candidate>>()
+            .into();
+        let total_candidates = heap.len().max(1);
+        let mut iterations = 0;
         let mut stdout = std::io::stdout();
 
-        let mut clusters_of_interest_time = 0.;
-        let mut local_clusters_time = 0.;
-        let mut sorting_time = 0.;
-        let mut iterating_local_time = 0.;
-        let mut logging_time = 0.;
-
-        'greedy: while current >= self.min_points && new_clusters.len() < self.max_clusters {
-            let mut clusters_of_interest: Vec<&Cluster<'_>> = vec![];
-            current_iteration += 1;
-            let time = Instant::now();
-            for (index, clusters) in clusters_with_data.iter().enumerate() {
-                if index < current {
-                    continue;
-                }
-                clusters_of_interest.extend(clusters);
+        while new_clusters.len() < self.max_clusters {
+            if self.timed_out() {
+                log::warn!("stopped picking clusters at the deadline");
+                break;
             }
-            clusters_of_interest_time += time.elapsed().as_secs_f32();
-
-            let time = Instant::now();
-            let mut local_clusters = clusters_of_interest
-                .into_par_iter()
-                .filter_map(|cluster| {
-                    let mut points: Vec<&Point> = cluster
-                        .all
-                        .iter()
-                        .filter_map(|p| {
-                            if blocked_points.contains(p) {
-                                None
-                            } else {
-                                Some(*p)
-                            }
-                        })
-                        .collect();
-                    if points.len() < current {
-                        None
-                    } else {
-                        points.sort_dedupe();
-
-                        Some(Cluster {
-                            point: cluster.point,
-                            unique: points.into_iter().collect(),
-                            all: cluster.all.iter().map(|p| *p).collect(),
-                        })
-                    }
-                })
-                .collect::<Vec<Cluster>>();
-            local_clusters_time += time.elapsed().as_secs_f32();
-
-            if local_clusters.is_empty() {
-                current -= 1;
-                continue;
+            let Some(candidate) = heap.pop() else {
+                break;
+            };
+            if candidate.unique_weight < self.min_points as Precision {
+                break;
             }
-
-            let time = Instant::now();
-            local_clusters.par_sort_by(|a, b| {
-                if a.unique.len() == b.unique.len() {
-                    b.all.len().cmp(&a.all.len())
-                } else {
-                    b.unique.len().cmp(&a.unique.len())
-                }
-            });
-            sorting_time += time.elapsed().as_secs_f32();
-
-            let time = Instant::now();
-            'cluster: for cluster in local_clusters.into_iter() {
-                if new_clusters.len() >= self.max_clusters {
-                    break 'greedy;
-                }
-                if cluster.unique.len() >= current {
-                    for point in cluster.unique.iter() {
-                        if blocked_points.contains(point) {
-                            continue 'cluster;
-                        }
-                    }
-                    for point in cluster.unique.iter() {
-                        blocked_points.insert(point);
-                    }
-                    new_clusters.insert(cluster);
+            let cluster = &clusters_with_data[candidate.index];
+            let mut points: Vec<&Point> = cluster
+                .all
+                .iter()
+                .filter(|p| !blocked_points.contains(*p))
+                .copied()
+                .collect();
+            points.sort_dedupe();
+
+            if points.len() == candidate.unique_count {
+                blocked_points.extend(points.iter().copied());
+                new_clusters.insert(Cluster {
+                    point: cluster.point,
+                    unique: points,
+                    all: cluster.all.clone(),
+                });
+            } else {
+                let unique_weight = weight_of(&points);
+                if unique_weight >= self.min_points as Precision {
+                    heap.push(Candidate {
+                        unique_weight,
+                        unique_count: points.len(),
+                        ..candidate
+                    });
                 }
             }
-            iterating_local_time += time.elapsed().as_secs_f32();
 
-            let time = Instant::now();
-            if current >= self.min_points {
+            iterations += 1;
+            if iterations % PROGRESS_INTERVAL == 0 {
                 stdout
-                    .write(
+                    .write_all(
                         info_log(
                             "algorithms::clustering::greedy",
                             format!(
                                 "Progress: {:.2}% | Clusters: {}",
-                                (current_iteration as f32 / total_iterations as f32) * 100.,
+                                (1. - heap.len() as f32 / total_candidates as f32) * 100.,
                                 new_clusters.len()
                             ),
                         )
@@ -418,17 +513,8 @@ impl<'a> Greedy {
                     .unwrap();
                 stdout.flush().unwrap();
             }
-            logging_time += time.elapsed().as_secs_f32();
-
-            current -= 1;
         }
-        stdout.write(format!("\n",).as_bytes()).unwrap();
-
-        log::debug!("Interested Clusters Time: {:.4}", clusters_of_interest_time);
-        log::debug!("Local Clusters Time: {:.4}", local_clusters_time);
-        log::debug!("Sorting Time: {:.4}", sorting_time);
-        log::debug!("Iterating Local Time: {:.4}", iterating_local_time);
-        log::debug!("Logging Time: {:.4}", logging_time);
+        stdout.write_all("\n".as_bytes()).unwrap();
 
         log::info!(
             "finished initial solution in {:.2}s",
@@ -444,7 +530,7 @@ impl<'a> Greedy {
         log::info!("updating unique");
 
         let cluster_tree = rtree::spawn(
-            self.radius,
+            self.radii.max(self.radius),
             &clusters.iter().map(|c| c.point.center).collect(),
         );
 
@@ -452,7 +538,7 @@ impl<'a> Greedy {
             .par_iter_mut()
             .for_each(|cluster| cluster.set_unique(&cluster_tree));
 
-        clusters.retain(|cluster| cluster.unique.len() >= self.min_points);
+        clusters.retain(|cluster| weight_of(&cluster.unique) >= self.min_points as Precision);
 
         log::info!(
             "finished updating unique in {:.2}s",
@@ -480,7 +566,10 @@ impl<'a> Greedy {
                     .par_iter()
                     .filter_map(|p| {
                         let point = Point::new(self.radius, 20, *p);
-                        if seen_points.contains(&&point) {
+                        if seen_points.contains(&&point)
+                            || self.weights.get(p) <= 0.
+                            || self.exclusions.contains(p)
+                        {
                             None
                         } else {
                             Some(point)
@@ -503,3 +592,119 @@ impl<'a> Greedy {
         clusters
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use geo::{Distance, Haversine};
+
+    use super::*;
+
+    fn distance(a: &[Precision; 2], b: &[Precision; 2]) -> Precision {
+        Haversine.distance(geo::Point::new(a[1], a[0]), geo::Point::new(b[1], b[0]))
+    }
+
+    fn group(center: [Precision; 2], count: usize) -> SingleVec {
+        (0..count)
+            .map(|i| [center[0] + i as Precision * 0.0002, center[1]])
+            .collect()
+    }
+
+    #[test]
+    fn covers_every_point() {
+        let points: SingleVec = (0..100)
+            .map(|i| {
+                [
+                    40. + (i / 10) as Precision * 0.001,
+                    -74. + (i % 10) as Precision * 0.001,
+                ]
+            })
+            .collect();
+        let mut greedy = Greedy::default();
+        greedy.set_radius(70.).set_min_points(1);
+        let clusters = greedy.run(&points);
+        assert!(!clusters.is_empty());
+        for point in points.iter() {
+            assert!(clusters.iter().any(|c| distance(c, point) <= 70.));
+        }
+    }
+
+    #[test]
+    fn takes_the_heaviest_cluster_first() {
+        let mut points = group([40., -74.], 3);
+        points.push([40.01, -74.]);
+        // the single heavy point outweighs the group of three
+        let weights = Weights::new(&points, &[1., 1., 1., 1e12]);
+        let mut greedy = Greedy::default();
+        greedy
+            .set_radius(70.)
+            .set_min_points(2)
+            .set_max_clusters(1)
+            .set_weights(weights);
+        let clusters = greedy.run(&points);
+        assert_eq!(clusters.len(), 1);
+        assert!(distance(&clusters[0], &points[3]) <= 70.);
+    }
+
+    #[test]
+    fn compares_fractional_weights() {
+        let mut points = group([40., -74.], 3);
+        points.extend(group([40.01, -74.], 2));
+        let weights = Weights::new(&points, &[0.9, 0.9, 0.9, 1.2, 1.2]);
+        let mut greedy = Greedy::default();
+        greedy
+            .set_radius(70.)
+            .set_min_points(2)
+            .set_weights(weights);
+        let clusters = greedy.run(&points);
+        assert_eq!(clusters.len(), 2);
+
+        greedy.set_max_clusters(1);
+        let clusters = greedy.run(&points);
+        assert_eq!(clusters.len(), 1);
+        assert!(points[..3].iter().all(|p| distance(&clusters[0], p) <= 70.));
+    }
+
+    #[test]
+    fn covers_points_within_their_own_radius() {
+        // about 220m apart, too far for a single 70m cluster to cover two of them
+        let points: SingleVec = (0..6)
+            .map(|i| [40. + i as Precision * 0.002, -74.])
+            .collect();
+        let mut greedy = Greedy::default();
+        greedy.set_radius(70.).set_min_points(1);
+        assert_eq!(greedy.run(&points).len(), 6);
+
+        let radii = Radii::new(&points, &[250.; 6]);
+        greedy.set_radii(radii.clone());
+        let clusters = greedy.run(&points);
+        assert!(clusters.len() < 6);
+        for point in points.iter() {
+            assert!(
+                clusters
+                    .iter()
+                    .any(|c| distance(c, point) <= radii.get(point, 70.))
+            );
+        }
+    }
+
+    #[test]
+    fn split_runs_are_identical() {
+        let points: SingleVec = (0..400)
+            .map(|i| {
+                let i = (i * 151 % 400) as Precision;
+                [40. + (i / 20.).floor() * 0.0011, -74. + (i % 20.) * 0.0013]
+            })
+            .collect();
+        let mut greedy = Greedy::default();
+        greedy
+            .set_radius(70.)
+            .set_min_points(1)
+            .set_cluster_split_level(15);
+        let first = greedy.run(&points);
+        assert_eq!(greedy.run(&points), first);
+
+        let mut reversed = points.clone();
+        reversed.reverse();
+        assert_eq!(greedy.run(&reversed), first);
+    }
+}
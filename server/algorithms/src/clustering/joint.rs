@@ -0,0 +1,295 @@
+use std::{io, ops::AddAssign};
+
+use geo::{BoundingRect, Contains, Distance, Euclidean, Intersects, MultiPolygon, Point, Rect};
+use geojson::FeatureCollection;
+use hashbrown::{HashMap, HashSet};
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use serde::Serialize;
+
+use super::ClusterOptions;
+use crate::{radii::Radii, rtree, s2::point_cell, stats::Stats, utils::polygons, weights::Weights};
+
+/// Clusters and data points that belong to one of the child geofences
+#[derive(Debug, Clone, Default)]
+pub struct Child {
+    /// Index of the geofence in the area that was clustered
+    pub index: usize,
+    pub data_points: SingleVec,
+    pub clusters: SingleVec,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JointStats {
+    pub children: usize,
+    /// Clusters that cover data points of more than one child
+    pub border_clusters: usize,
+    /// Clusters of the joint run, summed over the children
+    pub clusters: usize,
+    /// Clusters when each child is clustered on its own with the same options
+    pub independent_clusters: usize,
+    /// `independent_clusters - clusters`, negative when the joint run placed more clusters
+    pub clusters_saved: isize,
+}
+
+impl<'a> AddAssign<&'a Self> for JointStats {
+    fn add_assign(&mut self, rhs: &'a Self) {
+        self.children += rhs.children;
+        self.border_clusters += rhs.border_clusters;
+        self.clusters += rhs.clusters;
+        self.independent_clusters += rhs.independent_clusters;
+        self.clusters_saved += rhs.clusters_saved;
+    }
+}
+
+struct Fence {
+    rect: Rect<Precision>,
+    polygon: MultiPolygon<Precision>,
+}
+
+impl Fence {
+    fn contains(&self, point: &PointArray) -> bool {
+        let point = Point::new(point[1], point[0]);
+        self.rect.intersects(&point) && self.polygon.contains(&point)
+    }
+}
+
+/// Splits clusters that were made from the data points of every child geofence at once.
+/// Each cluster goes to the child whose data points it covers the most, ties go to the child
+/// that contains its center
+pub fn split(
+    area: &FeatureCollection,
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    radius: Precision,
+    weights: &Weights,
+    radii: &Radii,
+) -> (Vec<Child>, JointStats) {
+    let fences: Vec<Fence> = area
+        .features
+        .iter()
+        .map(|feature| {
+            let polygon =
+                MultiPolygon::new(feature.geometry.as_ref().map(polygons).unwrap_or_default());
+            Fence {
+                rect: polygon
+                    .bounding_rect()
+                    .unwrap_or(Rect::new((0., 0.), (0., 0.))),
+                polygon,
+            }
+        })
+        .collect();
+    let mut children: Vec<Child> = (0..fences.len())
+        .map(|index| Child {
+            index,
+            ..Default::default()
+        })
+        .collect();
+
+    let mut owners = HashMap::new();
+    for point in data_points.iter() {
+        if let Some(owner) = fences.iter().position(|fence| fence.contains(point)) {
+            owners.entry(point_cell(point)).or_insert(owner);
+            children[owner].data_points.push(*point);
+        }
+    }
+
+    let tree = rtree::spawn_with_radii(radius, data_points, weights, radii);
+    let mut stats = JointStats {
+        children: fences.len(),
+        ..Default::default()
+    };
+    stats.clusters = clusters.len();
+    for cluster in clusters {
+        let mut covered = HashMap::<usize, Precision>::new();
+        let mut seen = HashSet::new();
+        for point in tree.locate_all_at_point(&cluster) {
+            if !seen.insert(point.cell_id.0) {
+                continue;
+            }
+            if let Some(owner) = owners.get(&point.cell_id.0) {
+                *covered.entry(*owner).or_default() += point.weight;
+            }
+        }
+        if covered.len() > 1 {
+            stats.border_clusters += 1;
+        }
+        let center_owner = fences.iter().position(|fence| fence.contains(&cluster));
+        let owner = covered
+            .iter()
+            .max_by(|(a_owner, a_weight), (b_owner, b_weight)| {
+                a_weight
+                    .total_cmp(b_weight)
+                    .then((Some(**a_owner) == center_owner).cmp(&(Some(**b_owner) == center_owner)))
+                    .then(b_owner.cmp(a_owner))
+            })
+            .map(|(owner, _)| *owner)
+            .or(center_owner)
+            .unwrap_or_else(|| {
+                let point = Point::new(cluster[1], cluster[0]);
+                (0..fences.len())
+                    .min_by(|a, b| {
+                        Euclidean
+                            .distance(&point, &fences[*a].polygon)
+                            .total_cmp(&Euclidean.distance(&point, &fences[*b].polygon))
+                    })
+                    .unwrap_or_default()
+            });
+        if let Some(child) = children.get_mut(owner) {
+            child.clusters.push(cluster);
+        }
+    }
+    log::info!(
+        "[JOINT] split clusters between {} geofences | border clusters: {}",
+        stats.children,
+        stats.border_clusters,
+    );
+    (children, stats)
+}
+
+/// Clusters each child on its own with the same options to count the clusters that the joint run saved.
+/// Runs the clustering a second time, so it takes about as long as the joint run itself.
+/// Errors of the plugins are added to `plugin_errors`
+pub fn cluster_independently(
+    area: &FeatureCollection,
+    children: &[Child],
+    radius: Precision,
+    options: &ClusterOptions,
+    stats: &mut JointStats,
+    plugin_errors: &mut Vec<String>,
+) -> io::Result<()> {
+    stats.independent_clusters = 0;
+    for child in children {
+        let Some(feature) = area.features.get(child.index) else {
+            continue;
+        };
+        let collection = FeatureCollection {
+            features: vec![feature.clone()],
+            bbox: None,
+            foreign_members: None,
+        };
+        let mut child_stats = Stats::new(String::new(), options.min_points);
+        let clusters = super::main(
+            &child.data_points,
+            radius,
+            collection,
+            &mut child_stats,
+            options,
+        );
+        plugin_errors.extend(child_stats.plugin_errors);
+        let clusters = clusters?;
+        stats.independent_clusters += clusters.len();
+    }
+    stats.clusters_saved = stats.independent_clusters as isize - stats.clusters as isize;
+    log::info!(
+        "[JOINT] independent clusters: {} | joint clusters: {} | saved: {}",
+        stats.independent_clusters,
+        stats.clusters,
+        stats.clusters_saved
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use geojson::{Feature, Geometry, Value};
+
+    use super::*;
+
+    fn square(min: [Precision; 2], max: [Precision; 2]) -> Feature {
+        Feature {
+            geometry: Some(Geometry::new(Value::Polygon(vec![vec![
+                vec![min[1], min[0]],
+                vec![max[1], min[0]],
+                vec![max[1], max[0]],
+                vec![min[1], max[0]],
+                vec![min[1], min[0]],
+            ]]))),
+            ..Default::default()
+        }
+    }
+
+    /// Two geofences sharing the `-74.` meridian
+    fn area() -> FeatureCollection {
+        FeatureCollection {
+            features: vec![
+                square([40., -74.01], [40.01, -74.]),
+                square([40., -74.], [40.01, -73.99]),
+            ],
+            bbox: None,
+            foreign_members: None,
+        }
+    }
+
+    #[test]
+    fn splits_by_covered_points() {
+        // two points west of the border, one east of it
+        let data_points = vec![[40.005, -74.0002], [40.0052, -74.0002], [40.005, -73.9998]];
+        let clusters = vec![[40.005, -73.9999], [40.001, -73.995]];
+        let (children, stats) = split(
+            &area(),
+            &data_points,
+            clusters.clone(),
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+        );
+        assert_eq!(stats.children, 2);
+        assert_eq!(stats.border_clusters, 1);
+        assert_eq!(stats.clusters, 2);
+        // the border cluster goes west even though its center is east of the border
+        assert_eq!(children[0].clusters, vec![clusters[0]]);
+        assert_eq!(children[1].clusters, vec![clusters[1]]);
+        assert_eq!(children[0].data_points.len(), 2);
+        assert_eq!(children[1].data_points.len(), 1);
+    }
+
+    #[test]
+    fn weights_decide_the_owner() {
+        let data_points = vec![[40.005, -74.0002], [40.0052, -74.0002], [40.005, -73.9998]];
+        let weights = Weights::new(&data_points, &[1., 1., 5.]);
+        let clusters = vec![[40.005, -74.0001]];
+        let (children, _) = split(
+            &area(),
+            &data_points,
+            clusters.clone(),
+            70.,
+            &weights,
+            &Radii::default(),
+        );
+        assert!(children[0].clusters.is_empty());
+        assert_eq!(children[1].clusters, clusters);
+    }
+
+    #[test]
+    fn counts_the_clusters_saved() {
+        // one pair of points on each side of the border, a single circle covers all of them
+        let data_points = vec![
+            [40.005, -74.0002],
+            [40.0052, -74.0002],
+            [40.005, -73.9998],
+            [40.0052, -73.9998],
+        ];
+        let options = ClusterOptions::default();
+        let clusters = super::super::main(
+            &data_points,
+            70.,
+            area(),
+            &mut Stats::new(String::new(), 1),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(clusters.len(), 1);
+        let (children, mut stats) = split(
+            &area(),
+            &data_points,
+            clusters,
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+        );
+        cluster_independently(&area(), &children, 70., &options, &mut stats, &mut vec![]).unwrap();
+        assert_eq!(stats.clusters, 1);
+        assert_eq!(stats.independent_clusters, 2);
+        assert_eq!(stats.clusters_saved, 1);
+    }
+}
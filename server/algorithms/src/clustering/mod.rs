@@ -1,104 +1,281 @@
-use std::{time::Instant, vec};
+use std::{io, time::Instant, vec};
 
 use crate::{
-    plugin::{Folder, JoinFunction, Plugin},
+    exclusions::Exclusions,
+    ids::PointIds,
+    plugin::{Folder, JoinFunction, Plugin, PluginOptions},
+    radii::Radii,
+    s2::split_level,
     stats::Stats,
     utils,
+    weights::Weights,
 };
 
-use self::greedy::Greedy;
+use self::{budget::Coverage, greedy::Greedy, refine::RefineOptions};
 
 use super::*;
 
 use geojson::FeatureCollection;
-use model::api::{calc_mode::CalculationMode, cluster_mode::ClusterMode, single_vec::SingleVec};
+use model::api::{
+    Precision, calc_mode::CalculationMode, cluster_mode::ClusterMode, single_vec::SingleVec,
+    split_level::SplitLevel,
+};
 
+pub mod anytime;
+mod budget;
 mod fastest;
 mod greedy;
+pub mod joint;
+pub mod refine;
 mod s2;
+pub mod seams;
+
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    pub cluster_mode: ClusterMode,
+    pub min_points: usize,
+    /// Weight of each data point, the clusters then maximize the covered weight
+    pub weights: Weights,
+    /// Radius of each data point, points without one use the radius of the request.
+    /// Ignored by `ClusterMode::Fastest`, `ClusterMode::Custom` and `CalculationMode::S2`
+    pub radii: Radii,
+    /// Id of each data point, sent to the `ClusterMode::Custom` plugins
+    pub ids: PointIds,
+    pub cluster_split_level: SplitLevel,
+    /// `usize::MAX` is unlimited
+    pub max_clusters: usize,
+    pub calculation_mode: CalculationMode,
+    pub s2_level: u8,
+    pub s2_size: u8,
+    pub clustering_args: String,
+    pub center_clusters: bool,
+    /// Local search pass that runs after the clustering, when set
+    pub refine: Option<RefineOptions>,
+    /// Time budget of the anytime clustering in milliseconds, `0` runs the greedy algorithm once
+    pub time_limit_ms: u64,
+    /// Number of lattices tried by the `Honeycomb` mode
+    pub lattice_trials: usize,
+    pub exclusions: Exclusions,
+    /// Timeout and cancellation of the `ClusterMode::Custom` plugins
+    pub plugin: PluginOptions,
+    /// Makes `cluster_split_level: auto` ignore the number of cores, see [crate::s2::auto_split_level]
+    pub seed: Option<u64>,
+}
 
+impl Default for ClusterOptions {
+    fn default() -> Self {
+        ClusterOptions {
+            cluster_mode: ClusterMode::Balanced,
+            min_points: 1,
+            weights: Weights::default(),
+            radii: Radii::default(),
+            ids: PointIds::default(),
+            cluster_split_level: SplitLevel::default(),
+            max_clusters: usize::MAX,
+            calculation_mode: CalculationMode::Radius,
+            s2_level: 15,
+            s2_size: 9,
+            clustering_args: "".to_string(),
+            center_clusters: false,
+            refine: None,
+            time_limit_ms: 0,
+            lattice_trials: 1,
+            exclusions: Exclusions::default(),
+            plugin: PluginOptions::default(),
+            seed: None,
+        }
+    }
+}
+
+/// The features of `collection` are used by `CalculationMode::S2` and their holes are excluded.
+/// Errors when a `ClusterMode::Custom` plugin fails, the error is added to `stats.plugin_errors` as well
 pub fn main(
     data_points: &SingleVec,
-    cluster_mode: ClusterMode,
     radius: f64,
-    min_points: usize,
-    stats: &mut Stats,
-    cluster_split_level: u64,
-    max_clusters: usize,
-    calculation_mode: CalculationMode,
-    s2_level: u8,
-    s2_size: u8,
     collection: FeatureCollection,
-    clustering_args: &str,
-    center_clusters: bool,
-) -> SingleVec {
+    stats: &mut Stats,
+    options: &ClusterOptions,
+) -> io::Result<SingleVec> {
+    let mut exclusions = options.exclusions.clone();
+    for feature in collection.features.iter() {
+        exclusions.add_interiors(feature);
+    }
+    let data_points = &exclusions.filter_data_points(data_points);
     if data_points.is_empty() {
-        return vec![];
+        return Ok(vec![]);
     }
     let time = Instant::now();
-    let clusters = match calculation_mode {
+    // only resolved by the modes that split the data points, `auto` is kept in the stats
+    let resolve_split_level = |stats: &mut Stats| {
+        let level = split_level(options.cluster_split_level, data_points, options.seed);
+        if options.cluster_split_level == SplitLevel::Auto {
+            stats.cluster_split_level = Some(level);
+        }
+        level
+    };
+    let weights = &options.weights;
+    let min_points = options.min_points;
+    let radii = &options.radii;
+    stats.weights = weights.clone();
+    stats.radii = radii.clone();
+    if !radii.is_empty()
+        && (matches!(options.calculation_mode, CalculationMode::S2)
+            || matches!(
+                options.cluster_mode,
+                ClusterMode::Fastest | ClusterMode::Custom(_)
+            ))
+    {
+        log::warn!(
+            "the radii of the data points are ignored by {:?} | {:?}, every point uses a radius of {}m",
+            options.calculation_mode,
+            options.cluster_mode,
+            radius
+        );
+    }
+    let clusters = match options.calculation_mode {
         CalculationMode::S2 => collection
             .into_iter()
-            .flat_map(|feature| s2::cluster(feature, data_points, s2_level, s2_size, min_points))
+            .flat_map(|feature| {
+                s2::cluster(
+                    feature,
+                    data_points,
+                    options.s2_level,
+                    options.s2_size,
+                    min_points,
+                    weights,
+                    &exclusions,
+                )
+            })
             .collect(),
-        _ => match cluster_mode {
-            ClusterMode::Fastest => {
-                let clusters = fastest::main(&data_points, radius, min_points);
-                clusters
-            }
+        _ => match &options.cluster_mode {
+            ClusterMode::Fastest => fastest::main(data_points, radius, min_points, weights),
             ClusterMode::Honeycomb
             | ClusterMode::Balanced
             | ClusterMode::Fast
             | ClusterMode::Better
             | ClusterMode::Best => {
+                let split_level = resolve_split_level(stats);
                 let mut greedy = Greedy::default();
                 greedy
-                    .set_cluster_mode(cluster_mode)
-                    .set_cluster_split_level(cluster_split_level)
-                    .set_max_clusters(max_clusters)
+                    .set_cluster_mode(options.cluster_mode.clone())
+                    .set_cluster_split_level(split_level)
                     .set_min_points(min_points)
-                    .set_radius(radius);
+                    .set_radius(radius)
+                    .set_weights(weights.clone())
+                    .set_radii(radii.clone())
+                    .set_exclusions(exclusions.clone())
+                    .set_lattice_trials(options.lattice_trials);
 
-                greedy.run(&data_points)
+                let clusters = if options.time_limit_ms > 0 {
+                    let (clusters, score_timeline) = anytime::run(
+                        &mut greedy,
+                        data_points,
+                        radius,
+                        &exclusions,
+                        stats,
+                        options,
+                    );
+                    stats.score_timeline = score_timeline;
+                    clusters
+                } else {
+                    greedy.run(data_points)
+                };
+                if split_level > 0 {
+                    let (clusters, seam_stats) = seams::repair(
+                        data_points,
+                        clusters,
+                        split_level,
+                        radius,
+                        weights,
+                        radii,
+                        &exclusions,
+                    );
+                    stats.seams = Some(seam_stats);
+                    clusters
+                } else {
+                    clusters
+                }
             }
             ClusterMode::Custom(plugin) => {
                 match Plugin::new(
-                    &plugin,
+                    plugin,
                     Folder::Clustering,
-                    cluster_split_level,
-                    clustering_args,
+                    resolve_split_level(stats),
+                    &options.clustering_args,
                 ) {
-                    Ok(plugin_manager) => {
-                        match plugin_manager.run_multi::<JoinFunction>(data_points, None) {
+                    Ok(mut plugin_manager) => {
+                        plugin_manager
+                            .set_radius(radius)
+                            .set_min_points(min_points)
+                            .set_max_clusters(options.max_clusters)
+                            .set_weights(weights.clone())
+                            .set_ids(options.ids.clone())
+                            .set_options(&options.plugin);
+                        match plugin_manager.run_multi::<JoinFunction>(data_points, None, stats) {
                             Ok(sorted_clusters) => sorted_clusters,
                             Err(e) => {
                                 log::error!("Error while running plugin: {}", e);
-                                vec![]
+                                stats.plugin_errors.push(e.to_string());
+                                return Err(e);
                             }
                         }
                     }
                     Err(e) => {
                         log::error!("Plugin not found: {}", e);
-                        vec![]
+                        stats.plugin_errors.push(e.to_string());
+                        return Err(e);
                     }
                 }
             }
         },
     };
-    let clusters = if center_clusters {
+    let clusters = if options.center_clusters {
         sec::with_data(radius, data_points, &clusters)
     } else {
         clusters
     };
+    let clusters = exclusions.snap(clusters);
+    let clusters = if let Some(refine) = &options.refine {
+        let (clusters, refine_stats) = refine::main(
+            data_points,
+            clusters,
+            radius,
+            weights,
+            radii,
+            &exclusions,
+            refine,
+        );
+        stats.refine = Some(refine_stats);
+        clusters
+    } else {
+        clusters
+    };
+    // the only place the limit is applied, so it's shared by every split level cell
+    let clusters = if options.max_clusters < usize::MAX {
+        let coverage = match options.calculation_mode {
+            CalculationMode::S2 => Coverage::s2(data_points, &clusters, options.s2_level, weights),
+            _ => Coverage::radius(data_points, &clusters, radius, weights, radii),
+        };
+        let (clusters, coverage_curve) = budget::select(
+            clusters,
+            &coverage,
+            options.max_clusters,
+            min_points as Precision,
+        );
+        stats.coverage_curve = coverage_curve;
+        clusters
+    } else {
+        clusters
+    };
     stats.set_cluster_time(time);
     stats.cluster_stats(radius, data_points, &clusters);
     stats.set_score();
 
-    clusters
+    Ok(clusters)
 }
 
 pub fn clustering_plugins() -> Vec<String> {
-    utils::get_plugin_list("algorithms/src/clustering/plugins").unwrap_or(vec![])
+    utils::get_plugin_list(&Folder::Clustering.dir()).unwrap_or(vec![])
 }
 
 pub fn all_clustering_options() -> Vec<String> {
@@ -111,3 +288,27 @@ pub fn all_clustering_options() -> Vec<String> {
     options.push("best".to_string());
     options
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_plugin_is_an_error() {
+        let data_points = vec![[40., -74.], [40.01, -74.], [40., -74.01], [40.01, -74.01]];
+        let mut stats = Stats::new(String::new(), 1);
+        let options = ClusterOptions {
+            cluster_mode: ClusterMode::Custom("does_not_exist".to_string()),
+            ..Default::default()
+        };
+        let clusters = main(
+            &data_points,
+            70.,
+            FeatureCollection::default(),
+            &mut stats,
+            &options,
+        );
+        assert!(clusters.is_err());
+        assert_eq!(stats.plugin_errors.len(), 1);
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    ops::AddAssign,
+    time::{Duration, Instant},
+};
+
+use hashbrown::HashMap;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use rstar::{AABB, RTree, primitives::GeomWithData};
+use serde::Serialize;
+
+use crate::{
+    exclusions::Exclusions,
+    radii::Radii,
+    rtree::{self, point::Point},
+    sec,
+    weights::Weights,
+};
+
+type IndexedCenter = GeomWithData<[Precision; 2], usize>;
+
+const METERS_PER_DEGREE: Precision = 111_320.;
+/// Closest uncovered points that are tried when re-centering a cluster
+const MAX_CANDIDATES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct RefineOptions {
+    /// Maximum number of refinement passes, `0` runs until nothing changes
+    pub iterations: usize,
+    /// Time limit in seconds, `0` is unlimited
+    pub time_limit: u64,
+    /// Stops at this instant when it comes before the time limit
+    pub deadline: Option<Instant>,
+}
+
+impl Default for RefineOptions {
+    fn default() -> Self {
+        RefineOptions {
+            iterations: 10,
+            time_limit: 0,
+            deadline: None,
+        }
+    }
+}
+
+/// What the refinement changed, coverage is counted after points are deduplicated
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RefineStats {
+    pub iterations: usize,
+    /// Clusters whose points were all covered by other clusters
+    pub removed: usize,
+    /// Pairs of clusters replaced by a single cluster
+    pub swapped: usize,
+    /// Clusters moved to cover more points
+    pub recentered: usize,
+    pub clusters_before: usize,
+    pub clusters_after: usize,
+    pub covered_before: usize,
+    pub covered_after: usize,
+    pub weight_before: Precision,
+    pub weight_after: Precision,
+    pub time: Precision,
+}
+
+impl<'a> AddAssign<&'a Self> for RefineStats {
+    fn add_assign(&mut self, rhs: &'a Self) {
+        self.iterations = self.iterations.max(rhs.iterations);
+        self.removed += rhs.removed;
+        self.swapped += rhs.swapped;
+        self.recentered += rhs.recentered;
+        self.clusters_before += rhs.clusters_before;
+        self.clusters_after += rhs.clusters_after;
+        self.covered_before += rhs.covered_before;
+        self.covered_after += rhs.covered_after;
+        self.weight_before += rhs.weight_before;
+        self.weight_after += rhs.weight_after;
+        self.time += rhs.time;
+    }
+}
+
+struct Refinement {
+    /// Largest radius of any data point, moves are always checked against the radius of each point
+    radius: Precision,
+    tree: RTree<Point>,
+    wide_tree: RTree<Point>,
+    /// Cell id of a data point to its index in `points`
+    index: HashMap<u64, usize>,
+    points: Vec<PointArray>,
+    weights: Vec<Precision>,
+    /// Number of clusters covering each point
+    counts: Vec<u32>,
+    /// Center and covered points of each cluster, `None` once removed
+    clusters: Vec<Option<(PointArray, Vec<usize>)>>,
+    exclusions: Exclusions,
+    deadline: Option<Instant>,
+}
+
+impl Refinement {
+    fn new(
+        data_points: &SingleVec,
+        clusters: &SingleVec,
+        radius: Precision,
+        weights: &Weights,
+        radii: &Radii,
+        exclusions: &Exclusions,
+        deadline: Option<Instant>,
+    ) -> Self {
+        let tree = rtree::spawn_with_radii(radius, data_points, weights, radii);
+        let wide_tree = rtree::spawn_with_radii(
+            radius * 2.,
+            data_points,
+            &Weights::default(),
+            &radii.scale(2.),
+        );
+        let mut index = HashMap::new();
+        let mut points = vec![];
+        let mut point_weights = vec![];
+        for point in tree.iter() {
+            index.entry(point.cell_id.0).or_insert_with(|| {
+                points.push(point.center);
+                point_weights.push(point.weight);
+                points.len() - 1
+            });
+        }
+        let mut refinement = Refinement {
+            radius: radii.max(radius),
+            tree,
+            wide_tree,
+            index,
+            counts: vec![0; points.len()],
+            points,
+            weights: point_weights,
+            clusters: Vec::with_capacity(clusters.len()),
+            exclusions: exclusions.clone(),
+            deadline,
+        };
+        for center in clusters.iter() {
+            let cover = refinement.cover(center);
+            refinement.add(*center, cover);
+        }
+        refinement
+    }
+
+    fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Sorted indices of the points within the radius of `center`
+    fn cover(&self, center: &PointArray) -> Vec<usize> {
+        let mut cover: Vec<usize> = self
+            .tree
+            .locate_all_at_point(center)
+            .filter_map(|point| self.index.get(&point.cell_id.0).copied())
+            .collect();
+        cover.sort_unstable();
+        cover.dedup();
+        cover
+    }
+
+    fn add(&mut self, center: PointArray, cover: Vec<usize>) {
+        for &point in cover.iter() {
+            self.counts[point] += 1;
+        }
+        self.clusters.push(Some((center, cover)));
+    }
+
+    fn remove(&mut self, cluster: usize) {
+        if let Some((_, cover)) = self.clusters[cluster].take() {
+            for point in cover {
+                self.counts[point] -= 1;
+            }
+        }
+    }
+
+    fn replace(&mut self, cluster: usize, center: PointArray, cover: Vec<usize>) {
+        self.remove(cluster);
+        for &point in cover.iter() {
+            self.counts[point] += 1;
+        }
+        self.clusters[cluster] = Some((center, cover));
+    }
+
+    fn covered(&self) -> (usize, Precision) {
+        self.counts
+            .iter()
+            .zip(self.weights.iter())
+            .filter(|(count, _)| **count > 0)
+            .fold((0, 0.), |(count, weight), (_, w)| (count + 1, weight + w))
+    }
+
+    /// Drops clusters whose points are all covered by other clusters, lightest first
+    fn remove_redundant(&mut self) -> usize {
+        let mut order: Vec<(Precision, usize)> = self
+            .clusters
+            .iter()
+            .enumerate()
+            .filter_map(|(i, cluster)| {
+                cluster.as_ref().map(|(_, cover)| {
+                    (cover.iter().map(|&p| self.weights[p]).sum::<Precision>(), i)
+                })
+            })
+            .collect();
+        order.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let mut removed = 0;
+        for (_, cluster) in order {
+            let redundant = self.clusters[cluster]
+                .as_ref()
+                .is_some_and(|(_, cover)| cover.iter().all(|&p| self.counts[p] > 1));
+            if redundant {
+                self.remove(cluster);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Replaces two neighbouring clusters with a single cluster centered on the points
+    /// that no other cluster covers, when those points fit within one circle
+    fn swap_pairs(&mut self) -> usize {
+        let centers: RTree<IndexedCenter> = RTree::bulk_load(
+            self.clusters
+                .iter()
+                .enumerate()
+                .filter_map(|(i, cluster)| {
+                    cluster
+                        .as_ref()
+                        .map(|(center, _)| IndexedCenter::new(*center, i))
+                })
+                .collect(),
+        );
+        let mut swapped = 0;
+        for a in 0..self.clusters.len() {
+            if self.timed_out() {
+                break;
+            }
+            let Some((center_a, cover_a)) = self.clusters[a].clone() else {
+                continue;
+            };
+            let lat_delta = self.radius * 2. / METERS_PER_DEGREE;
+            let lon_delta = lat_delta / center_a[0].to_radians().cos().max(0.01);
+            let envelope = AABB::from_corners(
+                [center_a[0] - lat_delta, center_a[1] - lon_delta],
+                [center_a[0] + lat_delta, center_a[1] + lon_delta],
+            );
+            let mut neighbours: Vec<usize> = centers
+                .locate_in_envelope(&envelope)
+                .map(|center| center.data)
+                .filter(|&b| b != a)
+                .collect();
+            neighbours.sort_unstable();
+
+            for b in neighbours {
+                let Some((_, cover_b)) = &self.clusters[b] else {
+                    continue;
+                };
+                let mut needed: Vec<usize> =
+                    cover_a.iter().chain(cover_b.iter()).copied().collect();
+                needed.sort_unstable();
+                needed.dedup();
+                needed.retain(|p| {
+                    let own = cover_a.binary_search(p).is_ok() as u32
+                        + cover_b.binary_search(p).is_ok() as u32;
+                    self.counts[*p] == own
+                });
+                if needed.is_empty() {
+                    continue;
+                }
+                let needed_points: Vec<PointArray> =
+                    needed.iter().map(|&p| self.points[p]).collect();
+                let Some(center) = sec::enclose(&needed_points, self.radius)
+                    .filter(|center| !self.exclusions.contains(center))
+                else {
+                    continue;
+                };
+                let cover = self.cover(&center);
+                if needed.iter().all(|p| cover.binary_search(p).is_ok()) {
+                    self.remove(b);
+                    self.replace(a, center, cover);
+                    swapped += 1;
+                    break;
+                }
+            }
+        }
+        swapped
+    }
+
+    /// Moves clusters onto the smallest enclosing circle of the points only they cover
+    /// plus as many nearby uncovered points as still fit
+    fn recenter(&mut self) -> usize {
+        let mut recentered = 0;
+        for cluster in 0..self.clusters.len() {
+            if self.timed_out() {
+                break;
+            }
+            let Some((center, cover)) = &self.clusters[cluster] else {
+                continue;
+            };
+            let needed: Vec<usize> = cover
+                .iter()
+                .copied()
+                .filter(|&p| self.counts[p] == 1)
+                .collect();
+            let origin = Point::new(self.radius, 20, *center);
+            let mut candidates: Vec<(Precision, usize)> = self
+                .wide_tree
+                .locate_all_at_point(center)
+                .filter_map(|point| self.index.get(&point.cell_id.0).copied())
+                .filter(|&p| self.counts[p] == 0)
+                .map(|p| (origin.haversine_distance(&self.points[p]), p))
+                .collect();
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+            candidates.dedup_by_key(|(_, p)| *p);
+            candidates.truncate(MAX_CANDIDATES);
+
+            let mut included: Vec<PointArray> = needed.iter().map(|&p| self.points[p]).collect();
+            let mut best: Option<(PointArray, Vec<usize>)> = None;
+            let mut best_gain = 0.;
+            for (_, candidate) in candidates {
+                included.push(self.points[candidate]);
+                let Some(new_center) = sec::enclose(&included, self.radius)
+                    .filter(|center| !self.exclusions.contains(center))
+                else {
+                    included.pop();
+                    continue;
+                };
+                let new_cover = self.cover(&new_center);
+                if !needed.iter().all(|p| new_cover.binary_search(p).is_ok()) {
+                    included.pop();
+                    continue;
+                }
+                let gain: Precision = new_cover
+                    .iter()
+                    .filter(|&&p| self.counts[p] == 0)
+                    .map(|&p| self.weights[p])
+                    .sum();
+                if gain > best_gain {
+                    best_gain = gain;
+                    best = Some((new_center, new_cover));
+                }
+            }
+            if let Some((new_center, new_cover)) = best {
+                self.replace(cluster, new_center, new_cover);
+                recentered += 1;
+            }
+        }
+        recentered
+    }
+
+    fn result(self) -> SingleVec {
+        self.clusters
+            .into_iter()
+            .filter_map(|cluster| cluster.map(|(center, _)| center))
+            .collect()
+    }
+}
+
+/// Local search run after clustering, removes redundant clusters, replaces pairs of clusters
+/// with a single one and re-centers clusters to pick up uncovered points.
+/// Every change keeps all points covered that were covered before and no center is moved into the exclusions
+pub fn main(
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    radius: Precision,
+    weights: &Weights,
+    radii: &Radii,
+    exclusions: &Exclusions,
+    options: &RefineOptions,
+) -> (SingleVec, RefineStats) {
+    let time = Instant::now();
+    let deadline = if options.time_limit == 0 {
+        options.deadline
+    } else {
+        let deadline = time + Duration::from_secs(options.time_limit);
+        Some(
+            options
+                .deadline
+                .map_or(deadline, |other| other.min(deadline)),
+        )
+    };
+    let mut refinement = Refinement::new(
+        data_points,
+        &clusters,
+        radius,
+        weights,
+        radii,
+        exclusions,
+        deadline,
+    );
+    let (covered_before, weight_before) = refinement.covered();
+    let mut stats = RefineStats {
+        clusters_before: clusters.len(),
+        covered_before,
+        weight_before,
+        ..Default::default()
+    };
+
+    while options.iterations == 0 || stats.iterations < options.iterations {
+        stats.iterations += 1;
+        let removed = refinement.remove_redundant();
+        let swapped = refinement.swap_pairs();
+        let recentered = refinement.recenter();
+        stats.removed += removed;
+        stats.swapped += swapped;
+        stats.recentered += recentered;
+        if removed + swapped + recentered == 0 || refinement.timed_out() {
+            break;
+        }
+    }
+    // clusters that became redundant during the last pass
+    stats.removed += refinement.remove_redundant();
+
+    (stats.covered_after, stats.weight_after) = refinement.covered();
+    let clusters = refinement.result();
+    stats.clusters_after = clusters.len();
+    stats.time = time.elapsed().as_secs_f64();
+
+    log::info!(
+        "[REFINE] {} -> {} clusters in {} passes ({:.2}s) | removed: {} | swapped: {} | recentered: {} | covered: {} -> {}",
+        stats.clusters_before,
+        stats.clusters_after,
+        stats.iterations,
+        stats.time,
+        stats.removed,
+        stats.swapped,
+        stats.recentered,
+        stats.covered_before,
+        stats.covered_after,
+    );
+    (clusters, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(size: usize, step: Precision) -> SingleVec {
+        (0..size * size)
+            .map(|i| {
+                [
+                    40. + (i / size) as Precision * step,
+                    -74. + (i % size) as Precision * step,
+                ]
+            })
+            .collect()
+    }
+
+    fn refine(
+        data_points: &SingleVec,
+        clusters: SingleVec,
+        iterations: usize,
+    ) -> (SingleVec, RefineStats) {
+        main(
+            data_points,
+            clusters,
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+            &Exclusions::default(),
+            &RefineOptions {
+                iterations,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn removes_redundant_clusters() {
+        let data_points = grid(2, 0.0001);
+        let clusters = vec![data_points[0], data_points[0], data_points[3]];
+        let (refined, stats) = refine(&data_points, clusters, 10);
+        assert_eq!(refined.len(), 1);
+        assert_eq!(stats.clusters_before, 3);
+        assert_eq!(stats.clusters_after, 1);
+        assert_eq!(stats.covered_after, data_points.len());
+    }
+
+    #[test]
+    fn never_loses_coverage() {
+        let data_points = grid(8, 0.0008);
+        // every other data point, offset so the clusters can be moved to cover more
+        let clusters: SingleVec = data_points
+            .iter()
+            .step_by(2)
+            .map(|point| [point[0] + 0.0003, point[1] + 0.0003])
+            .collect();
+        let (refined, stats) = refine(&data_points, clusters.clone(), 0);
+        assert!(stats.covered_after >= stats.covered_before);
+        assert!(refined.len() <= clusters.len());
+        assert!(stats.weight_after >= stats.weight_before);
+    }
+
+    #[test]
+    fn stops_after_the_iterations() {
+        let data_points = grid(8, 0.0008);
+        let clusters: SingleVec = data_points
+            .iter()
+            .map(|point| [point[0] + 0.0004, point[1]])
+            .collect();
+        let (_, stats) = refine(&data_points, clusters, 1);
+        assert_eq!(stats.iterations, 1);
+    }
+}
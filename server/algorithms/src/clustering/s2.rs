@@ -1,9 +1,9 @@
 use geojson::Feature;
 use hashbrown::HashMap;
-use model::api::single_vec::SingleVec;
+use model::api::{Precision, single_vec::SingleVec};
 use s2::cellid::CellID;
 
-use crate::bootstrap;
+use crate::{bootstrap, exclusions::Exclusions, weights::Weights};
 
 pub fn cluster(
     feature: Feature,
@@ -11,11 +11,13 @@ pub fn cluster(
     level: u8,
     size: u8,
     min_points: usize,
+    weights: &Weights,
+    exclusions: &Exclusions,
 ) -> SingleVec {
-    let bootstrap_cells = bootstrap::s2::BootstrapS2::new(&feature, level as u64, size);
+    let bootstrap_cells = bootstrap::s2::BootstrapS2::new(&feature, level as u64, size, exclusions);
     let all_cells = bootstrap_cells.result();
 
-    let mut cell_map = HashMap::<u64, usize>::new();
+    let mut cell_map = HashMap::<u64, Precision>::new();
 
     data.iter().for_each(|f| {
         cell_map
@@ -24,8 +26,8 @@ pub fn cluster(
                     .parent(level as u64)
                     .0,
             )
-            .and_modify(|v| *v += 1)
-            .or_insert(1);
+            .and_modify(|v| *v += weights.get(f))
+            .or_insert(weights.get(f));
     });
 
     all_cells
@@ -36,7 +38,7 @@ pub fn cluster(
                     .parent(level as u64)
                     .0,
             ) {
-                if count >= min_points {
+                if count >= min_points as Precision {
                     Some(point)
                 } else {
                     None
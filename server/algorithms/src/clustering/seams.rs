@@ -0,0 +1,218 @@
+use std::ops::AddAssign;
+
+use geo::{Destination, Haversine, Point};
+use hashbrown::HashSet;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use serde::Serialize;
+
+use crate::{
+    exclusions::Exclusions, radii::Radii, rtree, s2::from_array_to_cell_id, weights::Weights,
+};
+
+use super::refine::{self, RefineOptions};
+
+/// Directions that are checked for a split boundary around each cluster
+const BEARINGS: usize = 8;
+
+/// Clusters along the borders of the cells that the data points were split into
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SeamStats {
+    /// Clusters within one radius of a cell border
+    pub seam_clusters: usize,
+    /// Seam clusters that were dropped, or merged with another one, since clusters from the
+    /// other side covered their points
+    pub removed: usize,
+    /// Seam clusters moved to pick up the points of the removed ones
+    pub recentered: usize,
+}
+
+impl<'a> AddAssign<&'a Self> for SeamStats {
+    fn add_assign(&mut self, rhs: &'a Self) {
+        self.seam_clusters += rhs.seam_clusters;
+        self.removed += rhs.removed;
+        self.recentered += rhs.recentered;
+    }
+}
+
+fn is_seam(center: &PointArray, radius: Precision, split_level: u64) -> bool {
+    let cell = from_array_to_cell_id(center, split_level);
+    let point = Point::new(center[1], center[0]);
+    (0..BEARINGS).any(|i| {
+        let edge = Haversine.destination(point, (i * 360 / BEARINGS) as Precision, radius);
+        from_array_to_cell_id(&[edge.y(), edge.x()], split_level) != cell
+    })
+}
+
+/// Removes and re-centers the redundant clusters within one radius of a split boundary,
+/// the cells on each side were clustered on their own so their borders are often covered twice.
+/// Only the points that no other cluster covers are considered, so nothing that was covered is lost
+pub fn repair(
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    split_level: u64,
+    radius: Precision,
+    weights: &Weights,
+    radii: &Radii,
+    exclusions: &Exclusions,
+) -> (SingleVec, SeamStats) {
+    let max_radius = radii.max(radius);
+    let (seams, mut result): (SingleVec, SingleVec) = clusters
+        .into_iter()
+        .partition(|cluster| is_seam(cluster, max_radius, split_level));
+    let mut stats = SeamStats {
+        seam_clusters: seams.len(),
+        ..Default::default()
+    };
+    if seams.is_empty() {
+        return (result, stats);
+    }
+
+    let tree = rtree::spawn_with_radii(radius, data_points, weights, radii);
+    let covered: HashSet<u64> = result
+        .iter()
+        .flat_map(|cluster| tree.locate_all_at_point(cluster))
+        .map(|point| point.cell_id.0)
+        .collect();
+    let mut seen = HashSet::new();
+    let seam_points: SingleVec = seams
+        .iter()
+        .flat_map(|cluster| tree.locate_all_at_point(cluster))
+        .filter(|point| !covered.contains(&point.cell_id.0) && seen.insert(point.cell_id.0))
+        .map(|point| point.center)
+        .collect();
+
+    let seams = if seam_points.is_empty() {
+        vec![]
+    } else {
+        let (seams, refine_stats) = refine::main(
+            &seam_points,
+            seams,
+            radius,
+            weights,
+            radii,
+            exclusions,
+            &RefineOptions::default(),
+        );
+        stats.recentered = refine_stats.recentered;
+        seams
+    };
+    stats.removed = stats.seam_clusters - seams.len();
+    result.extend(seams);
+
+    log::info!(
+        "[SEAMS] {} clusters along split level {} borders | removed: {} | recentered: {}",
+        stats.seam_clusters,
+        split_level,
+        stats.removed,
+        stats.recentered
+    );
+    (result, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Distance;
+
+    use super::*;
+    use crate::s2::from_cell_id_to_array;
+
+    const LEVEL: u64 = 12;
+
+    /// A point on the border between two cells of [LEVEL], found by walking east from `[40, -74]`
+    fn border() -> PointArray {
+        let cell = from_array_to_cell_id(&[40., -74.], LEVEL);
+        let mut lon = -74.;
+        while from_array_to_cell_id(&[40., lon], LEVEL) == cell {
+            lon += 0.00001;
+        }
+        [40., lon]
+    }
+
+    fn covered(data_points: &SingleVec, clusters: &SingleVec) -> usize {
+        data_points
+            .iter()
+            .filter(|point| {
+                clusters.iter().any(|cluster| {
+                    Haversine.distance(
+                        Point::new(point[1], point[0]),
+                        Point::new(cluster[1], cluster[0]),
+                    ) <= 70.
+                })
+            })
+            .count()
+    }
+
+    fn repair(data_points: &SingleVec, clusters: SingleVec) -> (SingleVec, SeamStats) {
+        super::repair(
+            data_points,
+            clusters,
+            LEVEL,
+            70.,
+            &Weights::default(),
+            &Radii::default(),
+            &Exclusions::default(),
+        )
+    }
+
+    #[test]
+    fn removes_one_of_two_clusters_across_a_border() {
+        let border = border();
+        // about 20m on each side of the border, both cover every point along it
+        let west = [border[0], border[1] - 0.000235];
+        let east = [border[0], border[1] + 0.000235];
+        let inside = from_cell_id_to_array(from_array_to_cell_id(&[40., -74.], LEVEL));
+        let data_points = vec![
+            border,
+            [border[0] + 0.0002, border[1]],
+            [border[0] - 0.0002, border[1]],
+            inside,
+        ];
+        let clusters = vec![inside, west, east];
+        assert!(is_seam(&west, 70., LEVEL));
+        assert!(is_seam(&east, 70., LEVEL));
+
+        let (repaired, stats) = repair(&data_points, clusters.clone());
+        assert_eq!(repaired.len(), 2);
+        assert!(repaired.contains(&inside));
+        assert_eq!(
+            covered(&data_points, &repaired),
+            covered(&data_points, &clusters)
+        );
+        assert_eq!(stats.seam_clusters, 2);
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.recentered, 0);
+    }
+
+    #[test]
+    fn merges_two_clusters_that_fit_in_one() {
+        let border = border();
+        // each cluster covers a point of its own, both points fit in a single circle
+        let clusters = vec![
+            [border[0], border[1] - 0.0003],
+            [border[0], border[1] + 0.0003],
+        ];
+        let data_points = vec![
+            [border[0], border[1] - 0.0007],
+            [border[0], border[1] + 0.0007],
+        ];
+        let (repaired, stats) = repair(&data_points, clusters);
+        assert_eq!(repaired.len(), 1);
+        assert_eq!(covered(&data_points, &repaired), 2);
+        assert_eq!(stats.seam_clusters, 2);
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.recentered, 0);
+    }
+
+    #[test]
+    fn leaves_clusters_away_from_borders_alone() {
+        let center = from_cell_id_to_array(from_array_to_cell_id(&[40., -74.], LEVEL));
+        assert!(!is_seam(&center, 70., LEVEL));
+        // both cover the same point, but neither is on a border
+        let clusters = vec![center, [center[0] + 0.0001, center[1]]];
+        let (repaired, stats) = repair(&vec![center], clusters.clone());
+        assert_eq!(repaired, clusters);
+        assert_eq!(stats.seam_clusters, 0);
+        assert_eq!(stats.removed, 0);
+        assert_eq!(stats.recentered, 0);
+    }
+}
@@ -0,0 +1,72 @@
+use hashbrown::HashMap;
+use model::api::point_array::PointArray;
+
+/// Exact coordinates of a point, so that points a few meters apart never share a key
+pub fn key(point: &PointArray) -> [u64; 2] {
+    [point[0].to_bits(), point[1].to_bits()]
+}
+
+/// Values looked up by the exact coordinates of a point
+#[derive(Debug, Clone)]
+pub struct CoordMap<T>(HashMap<[u64; 2], T>);
+
+impl<T> Default for CoordMap<T> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<T> CoordMap<T> {
+    /// Stores `value` for `point`, or passes it to `merge` when the coordinates
+    /// already have a value
+    pub fn merge(&mut self, point: &PointArray, value: T, merge: impl FnOnce(&mut T, T)) {
+        match self.0.get_mut(&key(point)) {
+            Some(existing) => merge(existing, value),
+            None => {
+                self.0.insert(key(point), value);
+            }
+        }
+    }
+
+    pub fn get(&self, point: &PointArray) -> Option<&T> {
+        self.0.get(&key(point))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.0.values()
+    }
+
+    pub fn map<U>(&self, f: impl Fn(&T) -> U) -> CoordMap<U> {
+        CoordMap(self.0.iter().map(|(key, value)| (*key, f(value))).collect())
+    }
+}
+
+impl<T: Clone> CoordMap<T> {
+    pub fn extend(&mut self, other: &CoordMap<T>) {
+        self.0
+            .extend(other.0.iter().map(|(key, value)| (*key, value.clone())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_the_exact_coordinates() {
+        // a gym and a pokestop a few meters apart
+        let mut map = CoordMap::default();
+        map.merge(&[40., -74.], 120., |existing, value| *existing += value);
+        map.merge(&[40.00003, -74.00003], 80., |existing, value| {
+            *existing += value
+        });
+        map.merge(&[40., -74.], 10., |existing, value| *existing += value);
+        assert_eq!(map.get(&[40., -74.]), Some(&130.));
+        assert_eq!(map.get(&[40.00003, -74.00003]), Some(&80.));
+        assert_eq!(map.get(&[40.00001, -74.00001]), None);
+    }
+}
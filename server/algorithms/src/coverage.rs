@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+
+use hashbrown::HashSet;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use rstar::{RTree, primitives::GeomWithData};
+
+use crate::{
+    coord_map::{CoordMap, key},
+    radii::Radii,
+    routing::tsp::Projection,
+    rtree::{point::Point, spawn, spawn_with_radii},
+    s2::from_array_to_cell_id,
+    weights::Weights,
+};
+
+/// Data points covered by a single cluster
+#[derive(Debug, Clone)]
+pub struct ClusterCoverage {
+    pub center: PointArray,
+    /// Data points within the radius of the cluster
+    pub covered: usize,
+    /// Covered data points that no other cluster covers
+    pub unique: usize,
+    /// Indices of the covered data points
+    pub points: Vec<usize>,
+    /// Meters to the closest other cluster, `0` when there is none
+    pub nearest_distance: Precision,
+}
+
+/// Coverage of each cluster, in the order of `clusters`.
+/// Data points with a radius in `radii` are covered within that radius instead of `radius`
+pub fn clusters(
+    radius: Precision,
+    data_points: &SingleVec,
+    clusters: &SingleVec,
+    radii: &Radii,
+) -> Vec<ClusterCoverage> {
+    if clusters.is_empty() {
+        return vec![];
+    }
+    let mut indices = CoordMap::<Vec<usize>>::default();
+    for (i, point) in data_points.iter().enumerate() {
+        indices.merge(point, vec![i], |existing, i| existing.extend(i));
+    }
+    let point_tree = spawn_with_radii(radius, data_points, &Weights::default(), radii);
+    let cluster_tree = spawn(radii.max(radius), clusters);
+
+    let projection = Projection::new(clusters);
+    let center_tree: RTree<GeomWithData<[Precision; 2], usize>> = RTree::bulk_load(
+        clusters
+            .iter()
+            .enumerate()
+            .map(|(i, cluster)| GeomWithData::new(projection.project(cluster), i))
+            .collect(),
+    );
+
+    clusters
+        .par_iter()
+        .enumerate()
+        .map(|(i, cluster)| {
+            let mut located: Vec<&Point> = point_tree.locate_all_at_point(cluster).collect();
+            located.sort_by_key(|point| key(&point.center));
+            located.dedup_by_key(|point| key(&point.center));
+            let points: Vec<usize> = located
+                .iter()
+                .filter_map(|point| indices.get(&point.center))
+                .flatten()
+                .copied()
+                .collect();
+            let unique = located
+                .iter()
+                .filter(|point| {
+                    cluster_tree
+                        .locate_all_at_point(&point.center)
+                        .filter(|other| other.haversine_distance(&point.center) <= point.radius)
+                        .count()
+                        == 1
+                })
+                .filter_map(|point| indices.get(&point.center))
+                .map(|indices| indices.len())
+                .sum();
+            let center = Point::new(radius, 20, *cluster);
+            let nearest_distance = center_tree
+                .nearest_neighbor_iter(&projection.project(cluster))
+                .find(|neighbor| neighbor.data != i)
+                .map(|neighbor| center.haversine_distance(&clusters[neighbor.data]))
+                .unwrap_or(0.);
+            ClusterCoverage {
+                center: *cluster,
+                covered: points.len(),
+                unique,
+                points,
+                nearest_distance,
+            }
+        })
+        .collect()
+}
+
+/// Data points that none of the clusters cover, in the order of `data_points`.
+/// Data points with a radius in `radii` are covered within that radius instead of `radius`
+pub fn uncovered(
+    radius: Precision,
+    data_points: &SingleVec,
+    clusters: &SingleVec,
+    radii: &Radii,
+) -> SingleVec {
+    let point_tree = spawn_with_radii(radius, data_points, &Weights::default(), radii);
+    let covered: HashSet<[u64; 2]> = clusters
+        .iter()
+        .flat_map(|cluster| point_tree.locate_all_at_point(cluster))
+        .map(|point| key(&point.center))
+        .collect();
+    data_points
+        .iter()
+        .filter(|point| !covered.contains(&key(point)))
+        .copied()
+        .collect()
+}
+
+/// Number of points in each S2 cell of `level`, keyed by the cell id
+pub fn count_by_cell(points: &SingleVec, level: u8) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for point in points.iter() {
+        *counts
+            .entry(from_array_to_cell_id(point, level as u64).0.to_string())
+            .or_default() += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_uncovered_points() {
+        let data_points = vec![[40., -74.], [40.0003, -74.], [40.01, -74.], [40.01, -74.]];
+        let clusters = vec![[40.0001, -74.]];
+        assert_eq!(
+            uncovered(70., &data_points, &clusters, &Radii::default()),
+            vec![[40.01, -74.], [40.01, -74.]]
+        );
+        // the far points have a radius that reaches the cluster
+        let radii = Radii::new(&data_points, &[70., 70., 1200., 1200.]);
+        assert!(uncovered(70., &data_points, &clusters, &radii).is_empty());
+    }
+
+    #[test]
+    fn counts_by_cell() {
+        let points = vec![[40., -74.], [40.00001, -74.], [41., -74.]];
+        let counts = count_by_cell(&points, 15);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.values().sum::<usize>(), 3);
+    }
+}
@@ -0,0 +1,224 @@
+use geo::{
+    BoundingRect, Closest, ClosestPoint, Contains, Distance, Haversine, Intersects, Point, Polygon,
+    Rect,
+};
+use geojson::{Feature, FeatureCollection};
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+
+use crate::utils::polygons;
+
+/// How far, in degrees, a snapped center is pushed past the boundary of an exclusion
+const NUDGE: Precision = 1e-7;
+
+/// Polygons that no cluster or bootstrap center may be placed in
+#[derive(Debug, Clone, Default)]
+pub struct Exclusions {
+    polygons: Vec<(Rect<Precision>, Polygon<Precision>)>,
+    /// Whether data points inside the exclusions are left out of clustering
+    pub ignore_data_points: bool,
+}
+
+impl Exclusions {
+    pub fn new(exclude_area: &FeatureCollection, ignore_data_points: bool) -> Self {
+        let mut exclusions = Self {
+            polygons: vec![],
+            ignore_data_points,
+        };
+        for feature in exclude_area.features.iter() {
+            if let Some(geometry) = feature.geometry.as_ref() {
+                for polygon in polygons(geometry) {
+                    exclusions.push(polygon);
+                }
+            }
+        }
+        exclusions
+    }
+
+    fn push(&mut self, polygon: Polygon<Precision>) {
+        if let Some(rect) = polygon.bounding_rect() {
+            self.polygons.push((rect, polygon));
+        }
+    }
+
+    /// Interior rings of the feature's polygons are excluded as well
+    pub fn add_interiors(&mut self, feature: &Feature) {
+        if let Some(geometry) = feature.geometry.as_ref() {
+            for polygon in polygons(geometry) {
+                for interior in polygon.interiors() {
+                    self.push(Polygon::new(interior.clone(), vec![]));
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.polygons.is_empty()
+    }
+
+    pub fn contains_point(&self, point: &Point<Precision>) -> bool {
+        self.polygons
+            .iter()
+            .any(|(rect, polygon)| rect.intersects(point) && polygon.contains(point))
+    }
+
+    pub fn contains(&self, point: &PointArray) -> bool {
+        !self.polygons.is_empty() && self.contains_point(&Point::new(point[1], point[0]))
+    }
+
+    /// Data points outside of the exclusions, or all of them when they are not ignored
+    pub fn filter_data_points(&self, data_points: &SingleVec) -> SingleVec {
+        if self.ignore_data_points && !self.is_empty() {
+            data_points
+                .iter()
+                .filter(|point| !self.contains(point))
+                .copied()
+                .collect()
+        } else {
+            data_points.clone()
+        }
+    }
+
+    /// Moves the centers that are inside an exclusion to the closest point just outside of it,
+    /// centers that can't be moved out are dropped
+    pub fn snap(&self, clusters: SingleVec) -> SingleVec {
+        if self.is_empty() {
+            return clusters;
+        }
+        let mut moved = 0;
+        let mut dropped = 0;
+        let clusters = clusters
+            .into_iter()
+            .filter_map(|cluster| {
+                if !self.contains(&cluster) {
+                    return Some(cluster);
+                }
+                let snapped = self.closest_outside(Point::new(cluster[1], cluster[0]));
+                if snapped.is_some() {
+                    moved += 1;
+                } else {
+                    dropped += 1;
+                }
+                snapped
+            })
+            .collect();
+        if moved + dropped > 0 {
+            log::info!(
+                "[EXCLUSIONS] moved {} clusters out of excluded areas, dropped {}",
+                moved,
+                dropped
+            );
+        }
+        clusters
+    }
+
+    fn closest_outside(&self, point: Point<Precision>) -> Option<PointArray> {
+        let mut candidates: Vec<(Precision, Point<Precision>)> = self
+            .polygons
+            .iter()
+            .filter(|(rect, polygon)| rect.intersects(&point) && polygon.contains(&point))
+            .flat_map(|(_, polygon)| {
+                std::iter::once(polygon.exterior())
+                    .chain(polygon.interiors())
+                    .filter_map(|ring| match ring.closest_point(&point) {
+                        Closest::SinglePoint(closest) | Closest::Intersection(closest) => {
+                            Some(closest)
+                        }
+                        Closest::Indeterminate => None,
+                    })
+            })
+            .map(|closest| {
+                let (dx, dy) = (closest.x() - point.x(), closest.y() - point.y());
+                let length = (dx * dx + dy * dy).sqrt().max(Precision::EPSILON);
+                let nudged = Point::new(
+                    closest.x() + dx / length * NUDGE,
+                    closest.y() + dy / length * NUDGE,
+                );
+                (Haversine.distance(point, nudged), nudged)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate)
+            .find(|candidate| !self.contains_point(candidate))
+            .map(|candidate| [candidate.y(), candidate.x()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geojson::{Geometry, Value};
+
+    use super::*;
+
+    fn square(min: [Precision; 2], max: [Precision; 2]) -> Vec<Vec<Precision>> {
+        vec![
+            vec![min[1], min[0]],
+            vec![max[1], min[0]],
+            vec![max[1], max[0]],
+            vec![min[1], max[0]],
+            vec![min[1], min[0]],
+        ]
+    }
+
+    fn feature(rings: Vec<Vec<Vec<Precision>>>) -> Feature {
+        Feature {
+            geometry: Some(Geometry::new(Value::Polygon(rings))),
+            ..Default::default()
+        }
+    }
+
+    fn exclusions(ignore_data_points: bool) -> Exclusions {
+        Exclusions::new(
+            &FeatureCollection {
+                features: vec![feature(vec![square([40., -74.], [40.01, -73.99])])],
+                bbox: None,
+                foreign_members: None,
+            },
+            ignore_data_points,
+        )
+    }
+
+    #[test]
+    fn contains_points_inside() {
+        let exclusions = exclusions(false);
+        assert!(exclusions.contains(&[40.005, -73.995]));
+        assert!(!exclusions.contains(&[40.02, -73.995]));
+        assert!(!Exclusions::default().contains(&[40.005, -73.995]));
+    }
+
+    #[test]
+    fn filters_data_points_only_when_ignored() {
+        let data_points = vec![[40.005, -73.995], [40.02, -73.995]];
+        assert_eq!(
+            exclusions(false).filter_data_points(&data_points),
+            data_points
+        );
+        assert_eq!(
+            exclusions(true).filter_data_points(&data_points),
+            vec![[40.02, -73.995]]
+        );
+    }
+
+    #[test]
+    fn snaps_centers_out() {
+        let exclusions = exclusions(false);
+        let snapped = exclusions.snap(vec![[40.009, -73.995], [40.02, -73.995]]);
+        assert_eq!(snapped.len(), 2);
+        assert!(snapped.iter().all(|point| !exclusions.contains(point)));
+        // moved to the closest edge, the northern one
+        assert!((snapped[0][0] - 40.01).abs() < 1e-5);
+        assert_eq!(snapped[1], [40.02, -73.995]);
+    }
+
+    #[test]
+    fn excludes_holes() {
+        let mut exclusions = Exclusions::default();
+        exclusions.add_interiors(&feature(vec![
+            square([40., -74.], [40.1, -73.9]),
+            square([40.04, -73.96], [40.06, -73.94]),
+        ]));
+        assert!(exclusions.contains(&[40.05, -73.95]));
+        assert!(!exclusions.contains(&[40.02, -73.95]));
+    }
+}
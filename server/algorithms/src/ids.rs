@@ -0,0 +1,24 @@
+use model::api::{point_array::PointArray, single_vec::SingleVec};
+
+use crate::coord_map::CoordMap;
+
+/// Id of each data point, such as the id of the spawnpoint or fort it was read from,
+/// looked up by its exact coordinates
+#[derive(Debug, Clone, Default)]
+pub struct PointIds(CoordMap<String>);
+
+impl PointIds {
+    /// `ids` are matched to `points` by index, when several points share their coordinates
+    /// the first one is kept
+    pub fn new(points: &SingleVec, ids: &[String]) -> Self {
+        let mut map = CoordMap::default();
+        for (point, id) in points.iter().zip(ids.iter()) {
+            map.merge(point, id.clone(), |_, _| {});
+        }
+        Self(map)
+    }
+
+    pub fn get(&self, point: &PointArray) -> Option<&String> {
+        self.0.get(point)
+    }
+}
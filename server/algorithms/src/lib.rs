@@ -2,11 +2,17 @@ use model;
 
 pub mod bootstrap;
 pub mod clustering;
-mod plugin;
+mod coord_map;
+pub mod coverage;
+pub mod exclusions;
+pub mod ids;
+pub mod plugin;
 mod project;
+pub mod radii;
 pub mod routing;
 mod rtree;
 pub mod s2;
 mod sec;
 pub mod stats;
 pub mod utils;
+pub mod weights;
@@ -0,0 +1,1119 @@
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, Instant};
+
+use crate::ids::PointIds;
+use crate::s2::create_cell_map;
+use crate::stats::Stats;
+use crate::utils;
+use crate::weights::Weights;
+use geojson::Feature;
+use hashbrown::HashMap;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use self::wasm::WasmPlugin;
+
+mod wasm;
+
+/// Name of the JSON protocol in the requests
+const PROTOCOL: &str = "koji-json";
+/// Version of the JSON protocol that is sent in the requests
+const PROTOCOL_VERSION: u32 = 1;
+/// Set to [PROTOCOL_VERSION] when the plugin is run with the JSON protocol
+const PROTOCOL_ENV: &str = "KOJI_PROTOCOL";
+/// How often a running plugin is checked for its timeout and cancellation
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the output of a plugin is waited for after it exited,
+/// in case a process it started still holds on to it
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+/// Characters at the end of stderr that are kept for the error of a failed plugin
+const STDERR_TAIL: usize = 2000;
+/// Default for `plugin_timeout`, in seconds
+pub const DEFAULT_TIMEOUT: u64 = 600;
+/// Default for `wasm_memory_limit`, in MiB
+pub const DEFAULT_WASM_MEMORY: usize = 256;
+/// Default for `wasm_fuel`, roughly the number of instructions a WebAssembly plugin may execute
+pub const DEFAULT_WASM_FUEL: u64 = 10_000_000_000;
+/// Directory that contains the `{folder}/plugins` directories
+const PLUGIN_DIR_ENV: &str = "PLUGIN_DIR";
+/// Used when [PLUGIN_DIR_ENV] isn't set, relative to the working directory
+const DEFAULT_PLUGIN_DIR: &str = "algorithms/src";
+/// Manifest inside of a directory plugin
+const MANIFEST: &str = "manifest.json";
+/// Manifest next to a file plugin, `{plugin}.manifest.json`
+pub const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Shared flag that stops the plugins that were started with it
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancels when the guard is dropped, e.g. along with an aborted request
+    pub fn guard(&self) -> CancelGuard {
+        CancelGuard(self.clone())
+    }
+}
+
+pub struct CancelGuard(Cancel);
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Limits for the plugin processes
+#[derive(Debug, Clone)]
+pub struct PluginOptions {
+    /// Seconds a plugin may run before it is killed, along with the processes it started
+    pub timeout: u64,
+    /// Kills the running plugins when it is cancelled
+    pub cancel: Cancel,
+    /// Bytes of linear memory that a WebAssembly plugin may grow to
+    pub wasm_memory: usize,
+    /// Fuel of a WebAssembly plugin, it is stopped once the fuel runs out
+    pub wasm_fuel: u64,
+}
+
+impl Default for PluginOptions {
+    fn default() -> Self {
+        PluginOptions {
+            timeout: DEFAULT_TIMEOUT,
+            cancel: Cancel::default(),
+            wasm_memory: DEFAULT_WASM_MEMORY << 20,
+            wasm_fuel: DEFAULT_WASM_FUEL,
+        }
+    }
+}
+
+/// How Kōji talks to a plugin over stdin and stdout, picked by the `protocol` of its manifest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// `lat,lng lat,lng` in and out, or a GeoJSON Feature in for bootstrapping
+    #[default]
+    Text,
+    /// A [PluginRequest] in and a [PluginResponse] out
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestPoint {
+    /// Id of the data point, or its index in the request when it has none
+    id: String,
+    lat: Precision,
+    lon: Precision,
+    weight: Precision,
+}
+
+/// Input of the JSON protocol
+#[derive(Debug, Serialize)]
+struct PluginRequest<'a> {
+    protocol: &'static str,
+    version: u32,
+    /// `clustering`, `routing` or `bootstrap`
+    kind: String,
+    plugin: &'a str,
+    points: Vec<RequestPoint>,
+    /// Area to bootstrap
+    area: Option<&'a Feature>,
+    radius: Option<Precision>,
+    min_points: Option<usize>,
+    max_clusters: Option<usize>,
+    /// The args that are passed on the command line as well
+    args: &'a [String],
+}
+
+/// A point of the response, either `[lat, lon]`, `{ "lat", "lon" }` or `{ "id" }` to return
+/// a point of the request as it was sent
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ResponsePoint {
+    Array(PointArray),
+    Object {
+        id: Option<String>,
+        lat: Option<Precision>,
+        lon: Option<Precision>,
+    },
+}
+
+/// Output of the JSON protocol
+#[derive(Debug, Deserialize)]
+struct PluginResponse {
+    points: Vec<ResponsePoint>,
+    #[serde(default)]
+    stats: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    warnings: Vec<String>,
+}
+
+/// Stats and warnings that a plugin returned with the JSON protocol
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginReport {
+    pub plugin: String,
+    /// Any values the plugin wants to report, passed along as they were returned
+    pub stats: serde_json::Map<String, serde_json::Value>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Folder {
+    Routing,
+    Clustering,
+    Bootstrap,
+}
+
+impl Display for Folder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Folder::Routing => write!(f, "routing"),
+            Folder::Clustering => write!(f, "clustering"),
+            Folder::Bootstrap => write!(f, "bootstrap"),
+        }
+    }
+}
+
+impl Folder {
+    /// Directory that the plugins are loaded from, `{PLUGIN_DIR}/{folder}/plugins`
+    pub fn dir(&self) -> PathBuf {
+        let root = std::env::var(PLUGIN_DIR_ENV).unwrap_or(DEFAULT_PLUGIN_DIR.to_string());
+        Path::new(&root).join(self.to_string()).join("plugins")
+    }
+
+    /// Whether the plugin is installed in the folder
+    pub fn contains(&self, plugin: &str) -> bool {
+        self.dir().join(plugin).exists()
+    }
+
+    /// Every plugin of the folder along with its manifest
+    pub fn manifests(&self) -> Vec<PluginInfo> {
+        let dir = self.dir();
+        utils::get_plugin_list(&dir)
+            .unwrap_or(vec![])
+            .into_iter()
+            .map(|plugin| PluginInfo {
+                manifest: Manifest::read(&dir.join(&plugin)),
+                plugin,
+            })
+            .collect()
+    }
+}
+
+/// Optional description of a plugin, read from `manifest.json` inside of a directory plugin
+/// or from `{plugin}.manifest.json` next to a file plugin
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Manifest {
+    /// Display name, the plugin is still selected by its file name
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// Used instead of the interpreter that is picked by the extension
+    pub interpreter: Option<String>,
+    /// Entry point of a directory plugin, relative to the directory
+    pub entry: Option<String>,
+    /// `text` unless the plugin opts into the JSON protocol with `json`
+    pub protocol: Protocol,
+    /// Passed before the args of the request, so that the request can override them
+    pub default_args: Vec<String>,
+    /// JSON schema of the args that the plugin accepts, for clients to render a form
+    pub args_schema: Option<serde_json::Value>,
+}
+
+impl Manifest {
+    /// Plugins without a manifest, or with one that can't be parsed, get the default
+    pub fn read(plugin_path: &Path) -> Self {
+        let path = if plugin_path.is_dir() {
+            plugin_path.join(MANIFEST)
+        } else {
+            PathBuf::from(format!("{}{}", plugin_path.display(), MANIFEST_SUFFIX))
+        };
+        let Ok(manifest) = std::fs::read_to_string(&path) else {
+            return Manifest::default();
+        };
+        serde_json::from_str(&manifest).unwrap_or_else(|err| {
+            log::warn!("[PLUGIN] invalid manifest {}: {}", path.display(), err);
+            Manifest::default()
+        })
+    }
+}
+
+/// A plugin as it is listed by the API
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginInfo {
+    /// File or directory name that selects the plugin
+    pub plugin: String,
+    #[serde(flatten)]
+    pub manifest: Manifest,
+}
+
+/// The plugins of each folder with their manifests
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifests {
+    pub routing: Vec<PluginInfo>,
+    pub clustering: Vec<PluginInfo>,
+    pub bootstrap: Vec<PluginInfo>,
+}
+
+impl Manifests {
+    pub fn load() -> Self {
+        Manifests {
+            routing: Folder::Routing.manifests(),
+            clustering: Folder::Clustering.manifests(),
+            bootstrap: Folder::Bootstrap.manifests(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Plugin {
+    plugin_path: String,
+    interpreter: String,
+    args: Vec<String>,
+    folder: Folder,
+    protocol: Protocol,
+    radius: Option<Precision>,
+    min_points: Option<usize>,
+    max_clusters: Option<usize>,
+    weights: Weights,
+    ids: PointIds,
+    options: PluginOptions,
+    /// Set for `.wasm` plugins, which run in-process instead of as a child process
+    wasm: Option<WasmPlugin>,
+    pub plugin: String,
+    pub split_level: u64,
+}
+
+pub type JoinFunction = fn(&Plugin, Vec<SingleVec>) -> SingleVec;
+
+trait ParseCoord {
+    fn parse_next_coord(&mut self) -> Option<f64>;
+}
+
+impl ParseCoord for std::str::Split<'_, &str> {
+    fn parse_next_coord(&mut self) -> Option<f64> {
+        if let Some(coord) = self.next() {
+            if coord.contains(" ") {
+                return coord.split(",").parse_next_coord();
+            }
+            if let Ok(coord) = coord.parse::<f64>() {
+                return Some(coord);
+            }
+        }
+        None
+    }
+}
+
+impl Plugin {
+    pub fn new(
+        plugin: &str,
+        folder: Folder,
+        route_split_level: u64,
+        input_args: &str,
+    ) -> std::io::Result<Self> {
+        let mut plugin_path = folder.dir().join(plugin).display().to_string();
+        if !Path::new(&plugin_path).exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("plugin {plugin} does not exist"),
+            ));
+        }
+        let manifest = Manifest::read(Path::new(&plugin_path));
+        if let Some(entry) = &manifest.entry {
+            plugin_path = Path::new(&plugin_path).join(entry).display().to_string();
+        }
+        let entry = manifest.entry.as_deref().unwrap_or(plugin);
+        let mut interpreter = match &manifest.interpreter {
+            Some(interpreter) => interpreter.as_str(),
+            None => match entry.split(".").last() {
+                Some("py") => "python3",
+                Some("js") => "node",
+                Some("sh") => "bash",
+                Some("ts") => "ts-node",
+                val => {
+                    if entry == val.unwrap_or("") {
+                        &plugin_path
+                    } else {
+                        ""
+                    }
+                }
+            },
+        }
+        .to_string();
+        let args = manifest
+            .default_args
+            .iter()
+            .map(|arg| arg.to_string())
+            .chain(
+                input_args
+                    .split_whitespace()
+                    .skip_while(|arg| !arg.starts_with("--"))
+                    .map(|arg| arg.to_string()),
+            )
+            .collect::<Vec<String>>();
+
+        for (index, pre_arg) in input_args
+            .split_whitespace()
+            .take_while(|arg| !arg.starts_with("--"))
+            .enumerate()
+        {
+            log::info!("[PLUGIN PARSER] {index} | pre_arg: {}", pre_arg);
+            if index == 0 {
+                interpreter = pre_arg.to_string();
+            } else if index == 1 {
+                plugin_path = folder.dir().join(pre_arg).display().to_string();
+            } else {
+                log::warn!("Unrecognized argument: {pre_arg} for plugin: {plugin}")
+            }
+        }
+
+        // `.wasm` files run in-process, unless an interpreter was picked for them
+        let in_process = interpreter.is_empty() && plugin_path.ends_with(".wasm");
+        if interpreter.is_empty() && !in_process {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Unrecognized plugin, please create a PR to add support for it",
+            ));
+        };
+        let path = Path::new(&plugin_path);
+        if path.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{plugin} is a directory, not a file, something may not be right with the provided args"
+                ),
+            ));
+        }
+        if path.exists() {
+            plugin_path = path.display().to_string();
+            if in_process {
+                log::info!("{plugin_path} (in-process) {}", args.join(" "));
+            } else if interpreter == plugin_path {
+                log::info!("{interpreter} {}", args.join(" "));
+            } else {
+                log::info!("{interpreter} {plugin_path} {}", args.join(" "));
+            }
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "{plugin} does not exist{}",
+                    if plugin == "tsp" {
+                        ", rerun the OR Tools Script"
+                    } else {
+                        ""
+                    }
+                ),
+            ));
+        }
+
+        let mut plugin = Plugin {
+            plugin: plugin.to_string(),
+            plugin_path,
+            interpreter,
+            split_level: route_split_level,
+            args,
+            folder,
+            protocol: manifest.protocol,
+            radius: None,
+            min_points: None,
+            max_clusters: None,
+            weights: Weights::default(),
+            ids: PointIds::default(),
+            options: PluginOptions::default(),
+            wasm: None,
+        };
+        if in_process {
+            plugin.wasm = Some(WasmPlugin::new(Path::new(&plugin.plugin_path))?);
+        }
+        log::info!(
+            "[PLUGIN] {} uses the {:?} protocol",
+            plugin.plugin,
+            plugin.protocol
+        );
+        Ok(plugin)
+    }
+
+    /// Sent to plugins that use the JSON protocol
+    pub fn set_radius(&mut self, radius: Precision) -> &mut Self {
+        self.radius = Some(radius);
+        self
+    }
+    /// Sent to plugins that use the JSON protocol
+    pub fn set_min_points(&mut self, min_points: usize) -> &mut Self {
+        self.min_points = Some(min_points);
+        self
+    }
+    /// Sent to plugins that use the JSON protocol, unless it is `usize::MAX` (no limit)
+    pub fn set_max_clusters(&mut self, max_clusters: usize) -> &mut Self {
+        self.max_clusters = (max_clusters < usize::MAX).then_some(max_clusters);
+        self
+    }
+    /// Weight of each point that is sent to plugins that use the JSON protocol
+    pub fn set_weights(&mut self, weights: Weights) -> &mut Self {
+        self.weights = weights;
+        self
+    }
+    /// Id of each point that is sent to plugins that use the JSON protocol
+    pub fn set_ids(&mut self, ids: PointIds) -> &mut Self {
+        self.ids = ids;
+        self
+    }
+
+    pub fn set_options(&mut self, options: &PluginOptions) -> &mut Self {
+        self.options = options.clone();
+        self
+    }
+
+    /// The plugin runs in a process group of its own so that [kill_tree] reaches
+    /// the processes it starts as well
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.interpreter);
+        if self.plugin_path != self.interpreter {
+            command.arg(&self.plugin_path);
+        };
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+        command
+    }
+
+    /// Waits for the output of the child, it is killed when it runs out of time or is cancelled
+    fn wait(
+        &self,
+        child: &mut Child,
+        stdout: &mpsc::Receiver<String>,
+        stderr: &mpsc::Receiver<String>,
+    ) -> io::Result<(ExitStatus, String, String)> {
+        let deadline = Instant::now() + Duration::from_secs(self.options.timeout);
+        let mut output = None;
+        let status = loop {
+            let waiting = output.is_none();
+            if waiting {
+                match stdout.recv_timeout(POLL_INTERVAL) {
+                    Ok(stdout) => output = Some(stdout),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => output = Some(String::new()),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                }
+            }
+            // a process that the plugin started may keep stdout open after the plugin exited
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if !waiting {
+                std::thread::sleep(POLL_INTERVAL);
+            }
+            let stopped = if self.options.cancel.is_cancelled() {
+                Some((io::ErrorKind::Interrupted, "was cancelled".to_string()))
+            } else if Instant::now() >= deadline {
+                Some((
+                    io::ErrorKind::TimedOut,
+                    format!("timed out after {}s", self.options.timeout),
+                ))
+            } else {
+                None
+            };
+            if let Some((kind, reason)) = stopped {
+                kill_tree(child);
+                let stderr = stderr.recv_timeout(OUTPUT_GRACE).unwrap_or_default();
+                return Err(io::Error::new(
+                    kind,
+                    format!("{} {}{}", self.plugin, reason, stderr_tail(&stderr)),
+                ));
+            }
+        };
+        // processes that the plugin started may still hold on to its stdout and stderr
+        #[cfg(unix)]
+        kill_group(child);
+        let output = match output {
+            Some(output) => output,
+            None => stdout.recv_timeout(OUTPUT_GRACE).unwrap_or_default(),
+        };
+        let stderr = stderr.recv_timeout(OUTPUT_GRACE).unwrap_or_default();
+        Ok((status, output, stderr))
+    }
+
+    pub fn run_multi<T>(
+        &self,
+        points: &SingleVec,
+        joiner: Option<T>,
+        stats: &mut Stats,
+    ) -> Result<SingleVec, std::io::Error>
+    where
+        T: Fn(&Self, Vec<SingleVec>) -> SingleVec,
+    {
+        let handlers = if self.split_level == 0 {
+            vec![self.run(points, None)?]
+        } else {
+            let (handlers, errors): (Vec<_>, Vec<_>) = create_cell_map(points, self.split_level)
+                .into_values()
+                .collect::<Vec<SingleVec>>()
+                .into_par_iter()
+                .map(|x| self.run(&x, None))
+                .partition(|result| result.is_ok());
+            // a failed cell would leave a hole in the result, so the whole run fails,
+            // the first error is returned and the others are added to the stats
+            let mut errors = errors.into_iter().filter_map(|result| result.err());
+            if let Some(err) = errors.next() {
+                stats
+                    .plugin_errors
+                    .extend(errors.map(|err| err.to_string()));
+                return Err(err);
+            }
+            handlers
+                .into_iter()
+                .filter_map(|result| result.ok())
+                .collect()
+        };
+        let (handlers, reports): (Vec<SingleVec>, Vec<Option<PluginReport>>) =
+            handlers.into_iter().unzip();
+        stats.plugins.extend(reports.into_iter().flatten());
+        if let Some(joiner) = joiner {
+            Ok(joiner(self, handlers))
+        } else {
+            Ok(handlers.into_iter().flatten().collect())
+        }
+    }
+
+    /// Runs the plugin with the area to bootstrap, as a GeoJSON Feature with the text protocol
+    pub fn run_area(
+        &self,
+        feature: &Feature,
+        stats: &mut Stats,
+    ) -> Result<SingleVec, std::io::Error> {
+        let (results, report) = self.run(&vec![], Some(feature))?;
+        stats.plugins.extend(report);
+        Ok(results)
+    }
+
+    /// Id of each point in the request, points without one get their index
+    fn point_ids(&self, points: &SingleVec) -> Vec<String> {
+        points
+            .iter()
+            .enumerate()
+            .map(|(index, point)| match self.ids.get(point) {
+                Some(id) => id.clone(),
+                None => index.to_string(),
+            })
+            .collect()
+    }
+
+    fn request(&self, points: &SingleVec, ids: &[String], area: Option<&Feature>) -> String {
+        let request = PluginRequest {
+            protocol: PROTOCOL,
+            version: PROTOCOL_VERSION,
+            kind: self.folder.to_string(),
+            plugin: &self.plugin,
+            points: points
+                .iter()
+                .zip(ids.iter())
+                .map(|(point, id)| RequestPoint {
+                    id: id.clone(),
+                    lat: point[0],
+                    lon: point[1],
+                    weight: self.weights.get(point),
+                })
+                .collect(),
+            area,
+            radius: self.radius,
+            min_points: self.min_points,
+            max_clusters: self.max_clusters,
+            args: &self.args,
+        };
+        serde_json::to_string(&request).unwrap_or_default()
+    }
+
+    /// What the plugin receives on stdin, or as the request of a `.wasm` plugin
+    fn input(&self, points: &SingleVec, ids: &[String], area: Option<&Feature>) -> String {
+        match self.protocol {
+            Protocol::Text => match area {
+                Some(area) => area.to_string(),
+                None => utils::stringify_points(points),
+            },
+            Protocol::Json => self.request(points, ids, area),
+        }
+    }
+
+    fn run(
+        &self,
+        points: &SingleVec,
+        area: Option<&Feature>,
+    ) -> Result<(SingleVec, Option<PluginReport>), std::io::Error> {
+        let time = Instant::now();
+        let ids = match self.protocol {
+            Protocol::Text => vec![],
+            Protocol::Json => self.point_ids(points),
+        };
+
+        let (stdout, stderr) = match &self.wasm {
+            Some(wasm) => {
+                log::info!("running {} in-process", self.plugin);
+                if self.options.cancel.is_cancelled() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        format!("{} was cancelled", self.plugin),
+                    ));
+                }
+                wasm.run(&self.input(points, &ids, area), &self.options)
+                    .map_err(|err| io::Error::new(err.kind(), format!("{} {}", self.plugin, err)))?
+            }
+            None => self.spawn(points, &ids, area)?,
+        };
+        if !stderr.trim().is_empty() {
+            log::debug!("[PLUGIN] {} stderr:\n{}", self.plugin, stderr.trim());
+        }
+
+        let (mut results, invalid, report) = match self.protocol {
+            Protocol::Text => {
+                let (results, invalid) = parse_text(stdout.as_bytes());
+                (results, invalid, None)
+            }
+            Protocol::Json => self.parse_json(stdout.as_bytes(), points, &ids),
+        };
+
+        if let Some(first) = results.first()
+            && let Some(last) = results.last()
+            && first == last
+        {
+            results.pop();
+        }
+
+        if !invalid.is_empty() {
+            log::warn!(
+                "Some invalid results were returned from the plugin: `{}`",
+                invalid.join(", ")
+            );
+        }
+        if results.is_empty() {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!(
+                    "no valid output from {} \n{}\n{}{}",
+                    self.plugin,
+                    invalid.join(", "),
+                    match self.protocol {
+                        Protocol::Text =>
+                            "output should return points in the following format: `lat,lng lat,lng`",
+                        Protocol::Json => "output should be a JSON object with a `points` list",
+                    },
+                    stderr_tail(&stderr)
+                ),
+            ))
+        } else {
+            log::info!(
+                "{} child process finished in {}s with {} points",
+                self.plugin,
+                time.elapsed().as_secs_f32(),
+                results.len()
+            );
+            // Ok(output_indexes.into_iter().map(|i| points[i]).collect())
+            Ok((results, report))
+        }
+    }
+
+    /// Runs the plugin as a child process, returns its stdout and stderr
+    fn spawn(
+        &self,
+        points: &SingleVec,
+        ids: &[String],
+        area: Option<&Feature>,
+    ) -> io::Result<(String, String)> {
+        log::info!("spawning {} child process", self.plugin);
+
+        let mut child = self.command();
+        if self.protocol == Protocol::Json {
+            child.env(PROTOCOL_ENV, PROTOCOL_VERSION.to_string());
+        }
+        let input = self.input(points, ids, area);
+        let mut child = match child
+            .args(self.args.iter())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => return Err(err),
+        };
+
+        let mut stdin = match child.stdin.take() {
+            Some(stdin) => stdin,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "Failed to open stdin",
+                ));
+            }
+        };
+
+        std::thread::spawn(move || match stdin.write_all(input.as_bytes()) {
+            Ok(_) => match stdin.flush() {
+                Ok(_) => {}
+                Err(err) => {
+                    log::error!("failed to flush stdin: {}", err);
+                }
+            },
+            Err(err) => {
+                log::error!("failed to write to stdin: {}", err)
+            }
+        });
+
+        let stdout = read_pipe(child.stdout.take())?;
+        let stderr = read_pipe(child.stderr.take())?;
+        let (status, stdout, stderr) = self.wait(&mut child, &stdout, &stderr)?;
+
+        if status.success() {
+            Ok((stdout, stderr))
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "{} exited with status: {}{}",
+                    self.plugin,
+                    status,
+                    stderr_tail(&stderr)
+                ),
+            ))
+        }
+    }
+
+    /// Reads a [PluginResponse] from stdout, lines that aren't one are returned as invalid.
+    /// `ids` are the ids of `points` that were sent in the request
+    fn parse_json(
+        &self,
+        stdout: impl Read,
+        points: &SingleVec,
+        ids: &[String],
+    ) -> (SingleVec, Vec<String>, Option<PluginReport>) {
+        let mut output = String::new();
+        if let Err(e) = BufReader::new(stdout).read_to_string(&mut output) {
+            log::error!("Error reading output: {}", e);
+        }
+        // the response is usually the whole output, otherwise the last line that is one
+        let response = serde_json::from_str::<PluginResponse>(&output)
+            .ok()
+            .or_else(|| {
+                output
+                    .lines()
+                    .rev()
+                    .find_map(|line| serde_json::from_str::<PluginResponse>(line.trim()).ok())
+            });
+        let Some(response) = response else {
+            return (
+                vec![],
+                output
+                    .lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(|line| line.to_string())
+                    .collect(),
+                None,
+            );
+        };
+
+        let by_id: HashMap<&String, &PointArray> = ids.iter().zip(points.iter()).collect();
+        let mut results = vec![];
+        let mut invalid = vec![];
+        for (index, point) in response.points.into_iter().enumerate() {
+            match point {
+                ResponsePoint::Array(point) => results.push(point),
+                ResponsePoint::Object { id: Some(id), .. } if by_id.contains_key(&id) => {
+                    results.push(*by_id[&id])
+                }
+                ResponsePoint::Object {
+                    id: None,
+                    lat: Some(lat),
+                    lon: Some(lon),
+                } => results.push([lat, lon]),
+                _ => invalid.push(format!("point {index}")),
+            }
+        }
+        for warning in response.warnings.iter() {
+            log::warn!("[PLUGIN] {}: {}", self.plugin, warning);
+        }
+        let report = if response.stats.is_empty() && response.warnings.is_empty() {
+            None
+        } else {
+            Some(PluginReport {
+                plugin: self.plugin.clone(),
+                stats: response.stats,
+                warnings: response.warnings,
+            })
+        };
+        (results, invalid, report)
+    }
+}
+
+/// Reads `lat,lng lat,lng` points from stdout, tokens that aren't a point are returned as invalid
+fn parse_text(stdout: impl Read) -> (SingleVec, Vec<String>) {
+    let mut results = vec![];
+    let mut invalid = vec![];
+    let reader = BufReader::new(stdout);
+    for line in reader.lines() {
+        match line {
+            Ok(line) => {
+                if line.contains(" ") {
+                    for line in line.trim().split(" ") {
+                        let mut coord: std::str::Split<'_, &str> = line.trim().split(",");
+                        match (coord.parse_next_coord(), coord.parse_next_coord()) {
+                            (Some(lat), Some(lng)) => results.push([lat, lng]),
+                            _ => invalid.push(line.to_string()),
+                        }
+                    }
+                } else {
+                    let mut iter: std::str::Split<'_, &str> = line.trim().split(",");
+                    match (iter.parse_next_coord(), iter.parse_next_coord()) {
+                        (Some(lat), Some(lng)) => results.push([lat, lng]),
+                        _ => invalid.push(line),
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Error reading line: {}", e);
+            }
+        }
+    }
+    (results, invalid)
+}
+
+/// Reads a pipe of the child on its own thread so that a full pipe can't block it
+fn read_pipe<R>(pipe: Option<R>) -> io::Result<mpsc::Receiver<String>>
+where
+    R: Read + Send + 'static,
+{
+    let mut pipe = pipe.ok_or_else(|| io::Error::other("Could not capture the output"))?;
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = vec![];
+        if pipe.read_to_end(&mut output).is_ok() {
+            sender
+                .send(String::from_utf8_lossy(&output).into_owned())
+                .ok();
+        }
+    });
+    Ok(receiver)
+}
+
+/// The end of stderr, to be appended to an error message
+fn stderr_tail(stderr: &str) -> String {
+    let stderr = stderr.trim();
+    if stderr.is_empty() {
+        return String::new();
+    }
+    let start = stderr
+        .char_indices()
+        .rev()
+        .nth(STDERR_TAIL - 1)
+        .map_or(0, |(index, _)| index);
+    format!("\nstderr: {}", &stderr[start..])
+}
+
+/// Kills the process group of the child, which includes the processes it started.
+/// Other platforms have no process groups, only the child itself is killed there
+fn kill_tree(child: &mut Child) {
+    #[cfg(unix)]
+    kill_group(child);
+    child.kill().ok();
+    child.wait().ok();
+}
+
+#[cfg(unix)]
+fn kill_group(child: &Child) {
+    // SAFETY: only sends a signal, the child is the leader of its own process group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(ids: PointIds) -> Plugin {
+        Plugin {
+            plugin_path: String::new(),
+            interpreter: String::new(),
+            args: vec![],
+            folder: Folder::Clustering,
+            protocol: Protocol::Json,
+            radius: None,
+            min_points: None,
+            max_clusters: None,
+            weights: Weights::default(),
+            ids,
+            options: PluginOptions::default(),
+            wasm: None,
+            plugin: "test".to_string(),
+            split_level: 0,
+        }
+    }
+
+    /// Runs `script` with bash and the text protocol
+    #[cfg(unix)]
+    fn script(name: &str, script: &str, options: PluginOptions) -> Plugin {
+        let path = std::env::temp_dir().join(format!("koji-{}-{}.sh", std::process::id(), name));
+        std::fs::write(&path, script).unwrap();
+        let mut plugin = plugin(PointIds::default());
+        plugin.plugin_path = path.display().to_string();
+        plugin.interpreter = "bash".to_string();
+        plugin.protocol = Protocol::Text;
+        plugin.options = options;
+        plugin
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn runs_a_text_plugin() {
+        let plugin = script(
+            "text",
+            "cat > /dev/null\necho 40.1,-74.1 40.2,-74.2\n",
+            PluginOptions::default(),
+        );
+        let (results, report) = plugin.run(&vec![[40., -74.]], None).unwrap();
+        assert_eq!(results, vec![[40.1, -74.1], [40.2, -74.2]]);
+        assert!(report.is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kills_a_plugin_that_times_out() {
+        let plugin = script(
+            "timeout",
+            "sleep 30\necho 40.1,-74.1\n",
+            PluginOptions {
+                timeout: 1,
+                ..Default::default()
+            },
+        );
+        let time = Instant::now();
+        let err = plugin.run(&vec![[40., -74.]], None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reads_the_output_when_a_started_process_keeps_running() {
+        let plugin = script(
+            "background",
+            "cat > /dev/null\nsleep 30 &\necho 40.1,-74.1\n",
+            PluginOptions {
+                timeout: 20,
+                ..Default::default()
+            },
+        );
+        let time = Instant::now();
+        let (results, _) = plugin.run(&vec![[40., -74.]], None).unwrap();
+        assert_eq!(results, vec![[40.1, -74.1]]);
+        assert!(time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn kills_a_cancelled_plugin() {
+        let options = PluginOptions::default();
+        let plugin = script("cancel", "sleep 30\necho 40.1,-74.1\n", options.clone());
+        let cancel = options.cancel.guard();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(cancel);
+        });
+        let time = Instant::now();
+        let err = plugin.run(&vec![[40., -74.]], None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fails_when_one_cell_fails() {
+        // adds a point to each cell, except for the cells south of the equator
+        let mut plugin = script(
+            "cells",
+            "input=$(cat)\nif [[ $input == -* ]]; then echo south >&2; exit 1; fi\necho $input 1,1\n",
+            PluginOptions::default(),
+        );
+        plugin.split_level = 10;
+        let points = vec![[40., -74.], [-33., 151.], [-34., 18.]];
+        let mut stats = Stats::new(String::new(), 0);
+        assert!(
+            plugin
+                .run_multi::<JoinFunction>(&points, None, &mut stats)
+                .is_err()
+        );
+        // the first error is returned, the other failed cell is in the stats
+        assert_eq!(stats.plugin_errors.len(), 1);
+
+        let points = vec![[40., -74.], [41., -73.]];
+        let results = plugin
+            .run_multi::<JoinFunction>(&points, None, &mut stats)
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(points.iter().all(|point| results.contains(point)));
+    }
+
+    #[test]
+    fn parses_text_points() {
+        let (results, invalid) =
+            parse_text("40.1,-74.1 40.2,-74.2 oops\n40.3,-74.3\nhello\n".as_bytes());
+        assert_eq!(results, vec![[40.1, -74.1], [40.2, -74.2], [40.3, -74.3]]);
+        assert_eq!(invalid, vec!["oops", "hello"]);
+    }
+
+    #[test]
+    fn reads_the_protocol_from_the_manifest() {
+        let manifest: Manifest = serde_json::from_str(r#"{ "protocol": "json" }"#).unwrap();
+        assert_eq!(manifest.protocol, Protocol::Json);
+        let manifest: Manifest = serde_json::from_str(r#"{ "name": "test" }"#).unwrap();
+        assert_eq!(manifest.protocol, Protocol::Text);
+    }
+
+    #[test]
+    fn sends_the_ids_of_the_data_points() {
+        let points = vec![[40.1, -74.1], [40.2, -74.2]];
+        let plugin = plugin(PointIds::new(&vec![[40.2, -74.2]], &["fort".to_string()]));
+        let ids = plugin.point_ids(&points);
+        assert_eq!(ids, vec!["0", "fort"]);
+
+        let request: serde_json::Value =
+            serde_json::from_str(&plugin.request(&points, &ids, None)).unwrap();
+        assert_eq!(request["version"], PROTOCOL_VERSION);
+        assert_eq!(request["points"][1]["id"], "fort");
+        assert_eq!(request["points"][1]["lat"], 40.2);
+    }
+
+    #[test]
+    fn parses_json_responses() {
+        let points = vec![[40.1, -74.1], [40.2, -74.2]];
+        let ids = vec!["a".to_string(), "b".to_string()];
+        let plugin = plugin(PointIds::default());
+        let output = concat!(
+            "loading...\n",
+            r#"{ "points": [{ "id": "b" }, [40.3, -74.3], { "lat": 40.4, "lon": -74.4 }, { "id": "c" }], "#,
+            r#""stats": { "gap": 0.02 }, "warnings": ["slow"] }"#,
+            "\n"
+        );
+        let (results, invalid, report) = plugin.parse_json(output.as_bytes(), &points, &ids);
+        assert_eq!(results, vec![[40.2, -74.2], [40.3, -74.3], [40.4, -74.4]]);
+        assert_eq!(invalid, vec!["point 3"]);
+        let report = report.unwrap();
+        assert_eq!(report.stats["gap"], 0.02);
+        assert_eq!(report.warnings, vec!["slow"]);
+
+        let (results, invalid, report) = plugin.parse_json("40.1,-74.1".as_bytes(), &points, &ids);
+        assert!(results.is_empty());
+        assert_eq!(invalid, vec!["40.1,-74.1"]);
+        assert!(report.is_none());
+    }
+}
@@ -0,0 +1,480 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use wasm_encoder::{
+    CodeSection, EntityType, ImportSection, Instruction, SectionId, TypeSection,
+    reencode::{Error, Reencode, utils},
+};
+use wasmi::{
+    Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+    core::{HostError, TrapCode},
+};
+use wasmparser::{Parser, Payload, TypeRef};
+
+use super::{Cancel, PluginOptions, stderr_tail};
+
+/// Module of the functions that a WebAssembly plugin may import
+const HOST_MODULE: &str = "koji";
+/// Bytes that are kept of what a plugin logs with `koji.log`
+const LOG_LIMIT: usize = 1 << 20;
+/// Imported by every plugin through [instrument], it stops the plugin once it is cancelled
+/// or out of time
+const INTERRUPT: &str = "__interrupt";
+/// Calls of [INTERRUPT] between two checks of the clock, the cancellation is checked on every call
+const INTERRUPT_TICKS: u32 = 1024;
+
+/// A plugin compiled to WebAssembly that runs in-process with the protocol of its manifest.
+/// It has no access to the file system, the network or the environment,
+/// the only function that it may import is `koji.log(ptr: i32, len: i32)`.
+/// It is stopped by `plugin_timeout` and the cancellation of the request like a child process,
+/// since wasmi can't interrupt a running module, [instrument] adds the checks to the module itself.
+///
+/// The module exports:
+/// - `memory`
+/// - `alloc(len: i32) -> i32`, a buffer of `len` bytes for the request
+/// - `run(ptr: i32, len: i32) -> i64`, takes the request and returns where the response is,
+///   as `ptr << 32 | len`
+#[derive(Debug)]
+pub struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+}
+
+struct State {
+    limits: StoreLimits,
+    /// Written by `koji.log`, it takes the place of the stderr of a child process
+    log: String,
+    cancel: Cancel,
+    deadline: Instant,
+    /// Calls of [INTERRUPT] since the clock was checked
+    ticks: u32,
+}
+
+/// Why [INTERRUPT] stopped the plugin
+#[derive(Debug)]
+enum Interrupt {
+    Cancelled,
+    TimedOut,
+}
+
+impl Display for Interrupt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interrupt::Cancelled => write!(f, "cancelled"),
+            Interrupt::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+impl HostError for Interrupt {}
+
+impl WasmPlugin {
+    pub fn new(path: &Path) -> io::Result<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let invalid = |err: &dyn Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is not a valid WebAssembly module: {}",
+                    path.display(),
+                    err
+                ),
+            )
+        };
+        let wasm = instrument(&std::fs::read(path)?).map_err(|err| invalid(&err))?;
+        let module = Module::new(&engine, &wasm[..]).map_err(|err| invalid(&err))?;
+        Ok(WasmPlugin { engine, module })
+    }
+
+    /// Runs the plugin with a request in a fresh instance that is limited to
+    /// `options.wasm_memory` bytes, `options.wasm_fuel` and `options.timeout`,
+    /// returns the response and the log
+    pub fn run(&self, request: &str, options: &PluginOptions) -> io::Result<(String, String)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(options.wasm_memory)
+            .trap_on_grow_failure(true)
+            .build();
+        let mut store = Store::new(
+            &self.engine,
+            State {
+                limits,
+                log: String::new(),
+                cancel: options.cancel.clone(),
+                deadline: Instant::now() + Duration::from_secs(options.timeout),
+                ticks: 0,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+
+        let result = self.call(&mut store, request, options.wasm_fuel);
+        let log = std::mem::take(&mut store.data_mut().log);
+        match result {
+            Ok(output) => Ok((output, log)),
+            Err(err) => {
+                let (kind, reason) = match err.downcast_ref::<Interrupt>() {
+                    Some(Interrupt::Cancelled) => {
+                        (io::ErrorKind::Interrupted, "was cancelled".to_string())
+                    }
+                    Some(Interrupt::TimedOut) => (
+                        io::ErrorKind::TimedOut,
+                        format!("timed out after {}s", options.timeout),
+                    ),
+                    None => (
+                        match err.as_trap_code() {
+                            Some(TrapCode::OutOfFuel) => io::ErrorKind::TimedOut,
+                            Some(TrapCode::GrowthOperationLimited) => io::ErrorKind::OutOfMemory,
+                            _ => io::ErrorKind::Other,
+                        },
+                        format!("failed: {}", err),
+                    ),
+                };
+                Err(io::Error::new(
+                    kind,
+                    format!("{}{}", reason, stderr_tail(&log)),
+                ))
+            }
+        }
+    }
+
+    fn call(
+        &self,
+        store: &mut Store<State>,
+        request: &str,
+        fuel: u64,
+    ) -> Result<String, wasmi::Error> {
+        store.set_fuel(fuel)?;
+        let mut linker = Linker::<State>::new(&self.engine);
+        linker.func_wrap(
+            HOST_MODULE,
+            "log",
+            |mut caller: Caller<'_, State>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+                let memory = caller
+                    .get_export("memory")
+                    .and_then(|export| export.into_memory())
+                    .ok_or_else(|| wasmi::Error::new("the plugin does not export its memory"))?;
+                let message =
+                    String::from_utf8_lossy(read(memory.data(&caller), ptr as u32, len as u32)?)
+                        .into_owned();
+                let log = &mut caller.data_mut().log;
+                if log.len() < LOG_LIMIT {
+                    log.push_str(&message);
+                    log.push('\n');
+                }
+                Ok(())
+            },
+        )?;
+        linker.func_wrap(
+            HOST_MODULE,
+            INTERRUPT,
+            |mut caller: Caller<'_, State>| -> Result<(), wasmi::Error> {
+                let state = caller.data_mut();
+                if state.cancel.is_cancelled() {
+                    return Err(wasmi::Error::host(Interrupt::Cancelled));
+                }
+                state.ticks += 1;
+                if state.ticks >= INTERRUPT_TICKS {
+                    state.ticks = 0;
+                    if Instant::now() >= state.deadline {
+                        return Err(wasmi::Error::host(Interrupt::TimedOut));
+                    }
+                }
+                Ok(())
+            },
+        )?;
+        let instance = linker
+            .instantiate(&mut *store, &self.module)?
+            .start(&mut *store)?;
+        let memory = instance
+            .get_memory(&*store, "memory")
+            .ok_or_else(|| wasmi::Error::new("the plugin does not export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&*store, "alloc")?;
+        let run = instance.get_typed_func::<(i32, i32), i64>(&*store, "run")?;
+
+        let len = i32::try_from(request.len())
+            .map_err(|_| wasmi::Error::new("the request is too large for the plugin"))?;
+        let ptr = alloc.call(&mut *store, len)?;
+        memory.write(&mut *store, ptr as u32 as usize, request.as_bytes())?;
+        let location = run.call(&mut *store, (ptr, len))? as u64;
+
+        let output = read(
+            memory.data(&*store),
+            (location >> 32) as u32,
+            location as u32,
+        )?;
+        Ok(String::from_utf8_lossy(output).into_owned())
+    }
+}
+
+/// Bytes of the memory of the plugin, an error when they are out of its bounds
+fn read(memory: &[u8], ptr: u32, len: u32) -> Result<&[u8], wasmi::Error> {
+    memory
+        .get(ptr as usize..ptr as usize + len as usize)
+        .ok_or_else(|| wasmi::Error::new("the plugin returned a buffer out of its memory"))
+}
+
+/// Adds the import of [INTERRUPT] to `wasm` and calls it at the start of every function and
+/// every loop, so that no plugin can run for long without checking whether it should stop
+fn instrument(wasm: &[u8]) -> Result<Vec<u8>, Error<Infallible>> {
+    let mut types = None;
+    let mut imported = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(section) => {
+                let mut count = 0;
+                for group in section {
+                    count += group?.types().len() as u32;
+                }
+                types = Some(count);
+            }
+            Payload::ImportSection(section) => {
+                for import in section.into_imports() {
+                    if matches!(import?.ty, TypeRef::Func(_) | TypeRef::FuncExact(_)) {
+                        imported += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // a module without types has no functions to interrupt
+    let Some(ty) = types else {
+        return Ok(wasm.to_vec());
+    };
+    let mut module = wasm_encoder::Module::new();
+    Interrupter {
+        imported,
+        ty,
+        imports_added: false,
+    }
+    .parse_core_module(&mut module, Parser::new(0), wasm)?;
+    Ok(module.finish())
+}
+
+struct Interrupter {
+    /// Functions that the module imports, [INTERRUPT] is imported after them
+    imported: u32,
+    /// Type of [INTERRUPT], added after the types of the module
+    ty: u32,
+    imports_added: bool,
+}
+
+impl Interrupter {
+    fn add_import(&mut self, imports: &mut ImportSection) {
+        imports.import(HOST_MODULE, INTERRUPT, EntityType::Function(self.ty));
+        self.imports_added = true;
+    }
+}
+
+impl Reencode for Interrupter {
+    type Error = Infallible;
+
+    /// Functions that the module defines come after the imported ones, one more now
+    fn function_index(&mut self, func: u32) -> Result<u32, Error<Infallible>> {
+        Ok(if func < self.imported { func } else { func + 1 })
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), Error<Infallible>> {
+        utils::parse_type_section(self, types, section)?;
+        types.ty().function([], []);
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), Error<Infallible>> {
+        utils::parse_import_section(self, imports, section)?;
+        self.add_import(imports);
+        Ok(())
+    }
+
+    /// Adds an import section when the module has none, in front of the first section that
+    /// has to follow the imports or at the end. Custom sections may come before the types,
+    /// so they are skipped
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), Error<Infallible>> {
+        let follows_imports = !matches!(
+            before,
+            Some(SectionId::Custom | SectionId::Type | SectionId::Import)
+        );
+        if !self.imports_added && follows_imports {
+            let mut imports = ImportSection::new();
+            self.add_import(&mut imports);
+            module.section(&imports);
+        }
+        Ok(())
+    }
+
+    fn parse_function_body(
+        &mut self,
+        code: &mut CodeSection,
+        func: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), Error<Infallible>> {
+        let interrupt = Instruction::Call(self.imported);
+        let mut function = self.new_function_with_parsed_locals(&func)?;
+        function.instruction(&interrupt);
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            let instruction = self.parse_instruction(&mut reader)?;
+            function.instruction(&instruction);
+            if matches!(instruction, Instruction::Loop(_)) {
+                function.instruction(&interrupt);
+            }
+        }
+        code.function(&function);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(name: &str, wat: &str) -> WasmPlugin {
+        plugin_from_bytes(name, &wat::parse_str(wat).unwrap())
+    }
+
+    fn plugin_from_bytes(name: &str, wasm: &[u8]) -> WasmPlugin {
+        let path = std::env::temp_dir().join(format!("koji-{}-{}.wasm", std::process::id(), name));
+        std::fs::write(&path, wasm).unwrap();
+        WasmPlugin::new(&path).unwrap()
+    }
+
+    /// Runs `$body` from `run` and returns the 10 bytes at `0`
+    fn module(body: &str) -> String {
+        format!(
+            r#"(module
+                (import "koji" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "40.1,-74.1")
+                (func $spin (loop $forever (br $forever)))
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "run") (param i32 i32) (result i64)
+                    (call $log (i32.const 0) (i32.const 4))
+                    {body}
+                    (i64.const 10)))"#
+        )
+    }
+
+    #[test]
+    fn runs_a_module() {
+        let (output, log) = plugin("runs-a-module", &module(""))
+            .run("40,-74", &PluginOptions::default())
+            .unwrap();
+        assert_eq!(output, "40.1,-74.1");
+        assert_eq!(log, "40.1\n");
+    }
+
+    #[test]
+    fn stops_at_the_memory_limit() {
+        let options = PluginOptions {
+            wasm_memory: 1 << 20,
+            ..Default::default()
+        };
+        let err = plugin(
+            "stops-at-the-memory-limit",
+            &module("(drop (memory.grow (i32.const 100)))"),
+        )
+        .run("", &options)
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn stops_when_out_of_fuel() {
+        let options = PluginOptions {
+            wasm_fuel: 100_000,
+            ..Default::default()
+        };
+        let err = plugin("stops-when-out-of-fuel", &module("(call $spin)"))
+            .run("", &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("failed"));
+    }
+
+    #[test]
+    fn stops_at_the_timeout() {
+        let options = PluginOptions {
+            timeout: 1,
+            wasm_fuel: u64::MAX,
+            ..Default::default()
+        };
+        let time = Instant::now();
+        let err = plugin("stops-at-the-timeout", &module("(call $spin)"))
+            .run("", &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("timed out after 1s"));
+        assert!(time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let options = PluginOptions {
+            wasm_fuel: u64::MAX,
+            ..Default::default()
+        };
+        let cancel = options.cancel.guard();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            drop(cancel);
+        });
+        let time = Instant::now();
+        let err = plugin("stops-when-cancelled", &module("(call $spin)"))
+            .run("", &options)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn instruments_modules_without_imports() {
+        let plugin = plugin(
+            "without-imports",
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "40.1,-74.1")
+                (func $len (result i64) (i64.const 10))
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "run") (param i32 i32) (result i64) (call $len)))"#,
+        );
+        let (output, _) = plugin.run("", &PluginOptions::default()).unwrap();
+        assert_eq!(output, "40.1,-74.1");
+    }
+
+    #[test]
+    fn instruments_modules_that_start_with_a_custom_section() {
+        for wat in [
+            module(""),
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "40.1,-74.1")
+                (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+                (func (export "run") (param i32 i32) (result i64) (i64.const 10)))"#
+                .to_string(),
+        ] {
+            let mut wasm = wat::parse_str(&wat).unwrap();
+            // a custom section named `first` right after the magic number and the version
+            wasm.splice(8..8, [0, 6, 5, b'f', b'i', b'r', b's', b't']);
+            let (output, _) = plugin_from_bytes("custom-section", &wasm)
+                .run("", &PluginOptions::default())
+                .unwrap();
+            assert_eq!(output, "40.1,-74.1");
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+
+use crate::coord_map::CoordMap;
+
+/// Radius of each data point, looked up by its exact coordinates.
+/// Points without a radius of their own, or every point when there are no radii at all,
+/// use the radius of the request
+#[derive(Debug, Clone, Default)]
+pub struct Radii(CoordMap<Precision>);
+
+impl Radii {
+    /// `radii` are matched to `points` by index, radii that aren't positive are ignored.
+    /// When several points share their coordinates, the largest radius is kept
+    pub fn new(points: &SingleVec, radii: &[Precision]) -> Self {
+        let mut map = CoordMap::default();
+        for (point, radius) in points.iter().zip(radii.iter()) {
+            if *radius <= 0. {
+                continue;
+            }
+            map.merge(point, *radius, |existing, radius| {
+                *existing = existing.max(radius)
+            });
+        }
+        Self(map)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, point: &PointArray, radius: Precision) -> Precision {
+        if self.0.is_empty() {
+            radius
+        } else {
+            self.0.get(point).copied().unwrap_or(radius)
+        }
+    }
+
+    /// Largest radius of any point, never less than `radius`
+    pub fn max(&self, radius: Precision) -> Precision {
+        self.0.values().copied().fold(radius, Precision::max)
+    }
+
+    /// Smallest radius of any point, never more than `radius`
+    pub fn min(&self, radius: Precision) -> Precision {
+        self.0.values().copied().fold(radius, Precision::min)
+    }
+
+    /// Every radius multiplied by `factor`
+    pub fn scale(&self, factor: Precision) -> Self {
+        Self(self.0.map(|radius| radius * factor))
+    }
+
+    pub fn extend(&mut self, other: &Radii) {
+        self.0.extend(&other.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_the_request_radius() {
+        let points = vec![[40., -74.], [40.01, -74.], [40.02, -74.]];
+        let radii = Radii::new(&points, &[120., 0.]);
+        assert_eq!(radii.get(&points[0], 70.), 120.);
+        assert_eq!(radii.get(&points[1], 70.), 70.);
+        assert_eq!(radii.get(&points[2], 70.), 70.);
+        assert_eq!(Radii::default().get(&points[0], 70.), 70.);
+    }
+
+    #[test]
+    fn keeps_the_largest_radius_of_the_same_coordinates() {
+        let points = vec![[40., -74.], [40., -74.], [40.01, -74.]];
+        let radii = Radii::new(&points, &[40., 120., 80.]);
+        assert_eq!(radii.get(&points[0], 70.), 120.);
+        assert_eq!(radii.max(70.), 120.);
+        assert_eq!(radii.min(70.), 70.);
+        assert_eq!(radii.scale(2.).get(&points[2], 70.), 160.);
+    }
+}
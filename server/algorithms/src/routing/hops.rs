@@ -0,0 +1,298 @@
+use std::time::{Duration, Instant};
+
+use geo::{Distance, Haversine, InterpolatePoint, Point};
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+
+const EPSILON: Precision = 1e-7;
+
+fn distance(a: &PointArray, b: &PointArray) -> Precision {
+    Haversine.distance(Point::new(a[1], a[0]), Point::new(b[1], b[0]))
+}
+
+fn passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// How far a leg goes over the limit, legs within the limit cost nothing
+fn excess(a: &PointArray, b: &PointArray, max_hop_distance: Precision) -> Precision {
+    (distance(a, b) - max_hop_distance).max(0.)
+}
+
+enum Move {
+    /// Reverses `route[0..=index]`
+    Reverse(usize),
+    /// Moves `route[index]` to the end of the route
+    Relocate(usize),
+}
+
+/// Reorders the route to get rid of legs longer than `max_hop_distance`.
+/// Loops repeatedly rotate a long leg to the end of the route, `last -> first`,
+/// then look for the 2-opt or relocation move that removes the most distance over the limit.
+/// Open paths have no closing leg, their ends are kept and only 2-opt moves are used.
+/// Without a time limit it runs until no move is left
+pub fn reorder(
+    route: SingleVec,
+    max_hop_distance: Precision,
+    open_path: bool,
+    time_limit: Option<Duration>,
+) -> SingleVec {
+    let len = route.len();
+    if len < 4 || max_hop_distance <= 0. {
+        return route;
+    }
+    let time = Instant::now();
+    let deadline = time_limit.map(|time_limit| time + time_limit);
+    let route = if open_path {
+        reorder_path(route, max_hop_distance, deadline)
+    } else {
+        reorder_loop(route, max_hop_distance, deadline)
+    };
+    log::info!(
+        "[HOPS] reordered {} points for a {}m limit in {:.2}s",
+        len,
+        max_hop_distance,
+        time.elapsed().as_secs_f32()
+    );
+    route
+}
+
+fn reorder_loop(
+    mut route: SingleVec,
+    max_hop_distance: Precision,
+    deadline: Option<Instant>,
+) -> SingleVec {
+    let len = route.len();
+    let original_start = route[0];
+    let mut improved = true;
+    while improved && !passed(deadline) {
+        improved = false;
+        for i in 0..len {
+            if distance(&route[i], &route[(i + 1) % len]) <= max_hop_distance {
+                continue;
+            }
+            route.rotate_left((i + 1) % len);
+            if let Some(best_move) = best_move(&route, max_hop_distance) {
+                match best_move {
+                    Move::Reverse(index) => route[..=index].reverse(),
+                    Move::Relocate(index) => {
+                        let point = route.remove(index);
+                        route.push(point);
+                    }
+                }
+                improved = true;
+            }
+            if passed(deadline) {
+                break;
+            }
+        }
+    }
+    if let Some(start) = route.iter().position(|p| *p == original_start) {
+        route.rotate_left(start);
+    }
+    route
+}
+
+fn reorder_path(
+    mut route: SingleVec,
+    max_hop_distance: Precision,
+    deadline: Option<Instant>,
+) -> SingleVec {
+    let len = route.len();
+    let mut improved = true;
+    while improved && !passed(deadline) {
+        improved = false;
+        for i in 0..len - 1 {
+            if distance(&route[i], &route[i + 1]) <= max_hop_distance {
+                continue;
+            }
+            if let Some((start, end)) = best_path_move(&route, i, max_hop_distance) {
+                route[start..=end].reverse();
+                improved = true;
+            }
+            if passed(deadline) {
+                break;
+            }
+        }
+    }
+    route
+}
+
+/// Finds the 2-opt move removing the long leg `route[leg] -> route[leg + 1]` of an open path,
+/// returns the inclusive range to reverse, the first and last points never move
+fn best_path_move(
+    route: &SingleVec,
+    leg: usize,
+    max_hop_distance: Precision,
+) -> Option<(usize, usize)> {
+    let len = route.len();
+    let mut best: Option<(Precision, Precision, (usize, usize))> = None;
+    // reversing `route[a + 1..=b]` replaces the legs `a -> a + 1` and `b -> b + 1`
+    let candidates = (leg + 1..len - 1)
+        .map(|b| (leg, b))
+        .chain((0..leg).map(|a| (a, leg)));
+    for (a, b) in candidates {
+        let (p, q, r, s) = (&route[a], &route[a + 1], &route[b], &route[b + 1]);
+        let excess_delta = excess(p, r, max_hop_distance) + excess(q, s, max_hop_distance)
+            - excess(p, q, max_hop_distance)
+            - excess(r, s, max_hop_distance);
+        let distance_delta = distance(p, r) + distance(q, s) - distance(p, q) - distance(r, s);
+        if excess_delta < -EPSILON
+            && best.as_ref().is_none_or(|(best_excess, best_distance, _)| {
+                excess_delta < best_excess - EPSILON
+                    || (excess_delta < best_excess + EPSILON && distance_delta < *best_distance)
+            })
+        {
+            best = Some((excess_delta, distance_delta, (a + 1, b)));
+        }
+    }
+    best.map(|(_, _, range)| range)
+}
+
+fn best_move(route: &SingleVec, max_hop_distance: Precision) -> Option<Move> {
+    let len = route.len();
+    let first = &route[0];
+    let last = &route[len - 1];
+    let current = excess(last, first, max_hop_distance);
+    let current_distance = distance(last, first);
+
+    let mut best: Option<(Precision, Precision, Move)> = None;
+    let mut consider = |excess_delta: Precision, distance_delta: Precision, candidate: Move| {
+        if excess_delta < -EPSILON
+            && best.as_ref().is_none_or(|(best_excess, best_distance, _)| {
+                excess_delta < best_excess - EPSILON
+                    || (excess_delta < best_excess + EPSILON && distance_delta < *best_distance)
+            })
+        {
+            best = Some((excess_delta, distance_delta, candidate));
+        }
+    };
+
+    for j in 1..len - 2 {
+        let (a, b) = (&route[j], &route[j + 1]);
+        let excess_delta = excess(last, a, max_hop_distance) + excess(first, b, max_hop_distance)
+            - current
+            - excess(a, b, max_hop_distance);
+        let distance_delta =
+            distance(last, a) + distance(first, b) - current_distance - distance(a, b);
+        consider(excess_delta, distance_delta, Move::Reverse(j));
+    }
+    for k in 1..len - 1 {
+        let (prev, point, next) = (&route[k - 1], &route[k], &route[k + 1]);
+        let excess_delta = excess(prev, next, max_hop_distance)
+            + excess(last, point, max_hop_distance)
+            + excess(point, first, max_hop_distance)
+            - current
+            - excess(prev, point, max_hop_distance)
+            - excess(point, next, max_hop_distance);
+        let distance_delta = distance(prev, next) + distance(last, point) + distance(point, first)
+            - current_distance
+            - distance(prev, point)
+            - distance(point, next);
+        consider(excess_delta, distance_delta, Move::Relocate(k));
+    }
+    best.map(|(_, _, best_move)| best_move)
+}
+
+/// Inserts evenly spaced points along every leg that is still longer than `max_hop_distance`,
+/// the leg closing the loop is skipped for open paths.
+/// Returns the route and the filler points that were inserted into it
+pub fn fill(
+    route: SingleVec,
+    max_hop_distance: Precision,
+    open_path: bool,
+) -> (SingleVec, SingleVec) {
+    if route.len() < 2 || max_hop_distance <= 0. {
+        return (route, vec![]);
+    }
+    let mut filled = SingleVec::with_capacity(route.len());
+    let mut fillers = SingleVec::new();
+    for (i, point) in route.iter().enumerate() {
+        filled.push(*point);
+        if open_path && i == route.len() - 1 {
+            break;
+        }
+        let next = &route[(i + 1) % route.len()];
+        let count = (distance(point, next) / max_hop_distance).ceil() as usize;
+        for step in 1..count {
+            let filler = Haversine.point_at_ratio_between(
+                Point::new(point[1], point[0]),
+                Point::new(next[1], next[0]),
+                step as Precision / count as Precision,
+            );
+            filled.push([filler.y(), filler.x()]);
+            fillers.push([filler.y(), filler.x()]);
+        }
+    }
+    if !fillers.is_empty() {
+        log::info!("[HOPS] inserted {} filler points", fillers.len());
+    }
+    (filled, fillers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Destination;
+
+    const LIMIT: Precision = 310.;
+
+    /// Points `step` meters apart along a parallel, in the given order
+    fn points(order: &[usize], step: Precision) -> SingleVec {
+        let start = Point::new(-74., 40.);
+        order
+            .iter()
+            .map(|&i| {
+                let point = Haversine.destination(start, 90., i as Precision * step);
+                [point.y(), point.x()]
+            })
+            .collect()
+    }
+
+    fn long_legs(route: &SingleVec, open_path: bool) -> usize {
+        let len = route.len();
+        let legs = if open_path { len - 1 } else { len };
+        (0..legs)
+            .filter(|&i| distance(&route[i], &route[(i + 1) % len]) > LIMIT)
+            .count()
+    }
+
+    #[test]
+    fn reorders_loops() {
+        let route = points(&[0, 2, 4, 6, 1, 3, 5, 7], 150.);
+        assert_eq!(long_legs(&route, false), 2);
+        let reordered = reorder(route.clone(), LIMIT, false, Some(Duration::from_secs(1)));
+        assert_eq!(reordered.len(), route.len());
+        assert_eq!(reordered[0], route[0]);
+        assert_eq!(long_legs(&reordered, false), 0);
+    }
+
+    #[test]
+    fn reorders_open_paths_without_the_closing_leg() {
+        let route = points(&[0, 2, 1, 3, 4, 6, 5, 7], 200.);
+        assert!(long_legs(&route, true) > 0);
+        let reordered = reorder(route.clone(), LIMIT, true, Some(Duration::from_secs(1)));
+        assert_eq!(reordered.first(), route.first());
+        assert_eq!(reordered.last(), route.last());
+        assert_eq!(long_legs(&reordered, true), 0);
+
+        // the ends are far apart but there is no leg between them
+        let route = points(&[0, 1, 2, 3, 4, 5], 200.);
+        assert_eq!(
+            reorder(route.clone(), LIMIT, true, Some(Duration::from_secs(1))),
+            route
+        );
+    }
+
+    #[test]
+    fn fills_long_legs() {
+        let route = points(&[0, 3], 150.);
+        let (filled, fillers) = fill(route.clone(), LIMIT, false);
+        assert_eq!(fillers.len(), 2);
+        assert_eq!(filled.len(), 4);
+        assert_eq!(long_legs(&filled, false), 0);
+
+        let (filled, fillers) = fill(route, LIMIT, true);
+        assert_eq!(fillers.len(), 1);
+        assert_eq!(long_legs(&filled, true), 0);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{io, time::Instant};
+
+use geo::{Distance, Haversine, Point};
+use hashbrown::HashSet;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec, sort_by::SortBy};
+use serde::Serialize;
+
+use super::{RouteOptions, hops, path};
+use crate::{radii::Radii, rtree, stats::Stats};
+
+/// Summary of the changes [update] made to a route
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RouteDiff {
+    pub kept: usize,
+    pub added: SingleVec,
+    pub removed: SingleVec,
+    /// Positions of the added clusters in the updated route
+    pub added_positions: Vec<usize>,
+    pub uncovered_before: usize,
+    pub uncovered_after: usize,
+    pub distance_before: Precision,
+    pub distance_after: Precision,
+}
+
+fn distance(a: &PointArray, b: &PointArray) -> Precision {
+    Haversine.distance(Point::new(a[1], a[0]), Point::new(b[1], b[0]))
+}
+
+fn route_distance(route: &[PointArray], open_path: bool) -> Precision {
+    let len = route.len();
+    let legs = if open_path {
+        len.saturating_sub(1)
+    } else {
+        len
+    };
+    (0..legs)
+        .map(|i| distance(&route[i], &route[(i + 1) % len]))
+        .sum()
+}
+
+/// Updates an existing route without reshuffling it.
+/// Clusters that no longer cover any data points are dropped, the uncovered data points are
+/// clustered on their own with `cluster` and each new cluster is inserted where it adds the least
+/// distance, so `options.sort_by` isn't run. The route then goes through the same hop limit and
+/// start/end point handling as [super::main].
+/// Data points with a radius in `radii` are covered within their own radius.
+/// Errors when `cluster` fails
+pub fn update<F>(
+    route: SingleVec,
+    data_points: &SingleVec,
+    radius: Precision,
+    radii: &Radii,
+    stats: &mut Stats,
+    options: &RouteOptions,
+    cluster: F,
+) -> io::Result<(SingleVec, RouteDiff)>
+where
+    F: FnOnce(&SingleVec) -> io::Result<SingleVec>,
+{
+    let time = Instant::now();
+    let open_path = options.open_path;
+    let tree = rtree::spawn_with_radii(radius, data_points, &stats.weights, radii);
+    let mut covered = HashSet::new();
+    let mut diff = RouteDiff::default();
+
+    let mut updated = SingleVec::with_capacity(route.len());
+    for cluster in route.iter() {
+        let mut covers_any = false;
+        for point in tree.locate_all_at_point(cluster) {
+            covered.insert(point);
+            covers_any = true;
+        }
+        if covers_any {
+            updated.push(*cluster);
+        } else {
+            diff.removed.push(*cluster);
+        }
+    }
+    diff.kept = updated.len();
+    diff.distance_before = route_distance(&route, open_path);
+
+    let uncovered: SingleVec = tree
+        .iter()
+        .filter(|point| !covered.contains(point))
+        .map(|point| point.center)
+        .collect();
+    diff.uncovered_before = uncovered.len();
+
+    let new_clusters = if uncovered.is_empty() {
+        vec![]
+    } else {
+        cluster(&uncovered)?
+    };
+
+    for cluster in new_clusters.iter() {
+        if updated.len() < 2 {
+            updated.push(*cluster);
+            continue;
+        }
+        let len = updated.len();
+        let legs = if open_path { len - 1 } else { len };
+        let (mut index, mut cost) = (len, Precision::MAX);
+        if open_path {
+            // appending or prepending only adds a single leg to an open path
+            cost = distance(&updated[len - 1], cluster);
+            let prepend = distance(cluster, &updated[0]);
+            if prepend < cost {
+                index = 0;
+                cost = prepend;
+            }
+        }
+        for i in 0..legs {
+            let (a, b) = (&updated[i], &updated[(i + 1) % len]);
+            let insert_cost = distance(a, cluster) + distance(cluster, b) - distance(a, b);
+            if insert_cost < cost {
+                index = i + 1;
+                cost = insert_cost;
+            }
+        }
+        updated.insert(index, *cluster);
+    }
+
+    // the spawn time order is timed, only the filler points are added to it
+    let time_limit = options.reorder_limit(options.time_limit(updated.len()));
+    let reorder = |route: SingleVec| {
+        if options.sort_by == SortBy::SpawnTime {
+            route
+        } else {
+            hops::reorder(route, options.max_hop_distance, open_path, time_limit)
+        }
+    };
+    let updated = if open_path {
+        if options.start_point.is_some() || options.end_point.is_some() {
+            reorder(path::open(
+                updated,
+                options.start_point.as_ref(),
+                options.end_point.as_ref(),
+                false,
+                time_limit.map(|limit| Instant::now() + limit),
+            ))
+        } else {
+            reorder(updated)
+        }
+    } else if let Some(start_point) = &options.start_point {
+        path::rotate_to_start(reorder(updated), start_point)
+    } else {
+        reorder(updated)
+    };
+
+    diff.added = new_clusters;
+
+    stats.set_route_time(time);
+    stats.cluster_stats(radius, data_points, &updated);
+    stats.set_score();
+    diff.uncovered_after = stats.total_points - stats.points_covered.min(stats.total_points);
+
+    let (updated, filler_points) = hops::fill(updated, options.max_hop_distance, open_path);
+    diff.distance_after = route_distance(&updated, open_path);
+    diff.added_positions = updated
+        .iter()
+        .enumerate()
+        .filter_map(|(i, cluster)| diff.added.contains(cluster).then_some(i))
+        .collect();
+    stats.distance_stats(&updated, open_path);
+    stats.filler_points = filler_points;
+
+    log::info!(
+        "[ROUTE_UPDATE] kept {} | added {} | removed {} | uncovered {} -> {}",
+        diff.kept,
+        diff.added.len(),
+        diff.removed.len(),
+        diff.uncovered_before,
+        diff.uncovered_after
+    );
+    Ok((updated, diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Points about a kilometer apart, so that each one needs a cluster of its own
+    fn line(count: usize, lon: Precision) -> SingleVec {
+        (0..count)
+            .map(|i| [40. + i as Precision * 0.01, lon])
+            .collect()
+    }
+
+    fn run(route: SingleVec, data_points: &SingleVec) -> (SingleVec, RouteDiff, Option<SingleVec>) {
+        let mut clustered = None;
+        let (updated, diff) = update(
+            route,
+            data_points,
+            70.,
+            &Radii::default(),
+            &mut Stats::new(String::new(), 1),
+            &RouteOptions::default(),
+            |uncovered| {
+                clustered = Some(uncovered.clone());
+                Ok(uncovered.clone())
+            },
+        )
+        .unwrap();
+        (updated, diff, clustered)
+    }
+
+    #[test]
+    fn keeps_the_order_of_kept_clusters() {
+        let route = line(6, -74.);
+        let mut data_points = route.clone();
+        data_points.remove(2);
+        data_points.extend(line(3, -73.99));
+        let (updated, diff, _) = run(route, &data_points);
+        let kept: SingleVec = updated
+            .into_iter()
+            .filter(|cluster| !diff.added.contains(cluster))
+            .collect();
+        assert_eq!(kept, data_points[..5].to_vec());
+        assert_eq!(diff.kept, 5);
+    }
+
+    #[test]
+    fn removes_clusters_that_cover_nothing() {
+        let route = line(5, -74.);
+        let data_points = vec![route[0], route[1], route[3]];
+        let (updated, diff, clustered) = run(route.clone(), &data_points);
+        assert_eq!(diff.removed, vec![route[2], route[4]]);
+        assert_eq!(updated, data_points);
+        assert!(diff.added.is_empty());
+        assert!(clustered.is_none());
+    }
+
+    #[test]
+    fn covers_the_uncovered_points() {
+        let route = line(4, -74.);
+        let new_points = line(3, -73.99);
+        let mut data_points = route.clone();
+        data_points.extend(new_points.iter());
+        let (updated, diff, clustered) = run(route, &data_points);
+
+        let mut clustered = clustered.unwrap();
+        clustered.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        assert_eq!(clustered, new_points);
+        assert_eq!(diff.uncovered_before, 3);
+        assert_eq!(diff.uncovered_after, 0);
+        assert_eq!(updated.len(), 7);
+        assert!(new_points.iter().all(|point| updated.contains(point)));
+    }
+
+    #[test]
+    fn added_positions_match_the_added_clusters() {
+        let route = line(4, -74.);
+        let mut data_points = route.clone();
+        data_points.extend(line(3, -73.99));
+        let (updated, diff, _) = run(route, &data_points);
+
+        assert_eq!(diff.added_positions.len(), diff.added.len());
+        for position in diff.added_positions.iter() {
+            assert!(diff.added.contains(&updated[*position]));
+        }
+    }
+
+    #[test]
+    fn covers_points_within_their_own_radius() {
+        let route = vec![[40., -74.]];
+        // about 100m north of the cluster
+        let data_points = vec![[40.0009, -74.]];
+        let (updated, diff, _) = run(route.clone(), &data_points);
+        assert_eq!(diff.removed, route);
+        assert!(!updated.contains(&route[0]));
+
+        let (updated, diff) = update(
+            route.clone(),
+            &data_points,
+            70.,
+            &Radii::new(&data_points, &[120.]),
+            &mut Stats::new(String::new(), 1),
+            &RouteOptions::default(),
+            |_| panic!("every point is covered"),
+        )
+        .unwrap();
+        assert_eq!(updated, route);
+        assert_eq!(diff.kept, 1);
+        assert_eq!(diff.uncovered_before, 0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::plugin::{JoinFunction, Plugin};
+use crate::stats::Stats;
 use crate::utils;
 use geo::{Distance, Haversine, Point};
 use model::api::{point_array::PointArray, single_vec::SingleVec};
@@ -26,8 +27,9 @@ pub fn join(plugin: &Plugin, input: Vec<SingleVec>) -> SingleVec {
         centroids.push(center);
         point_map.insert(get_cell_id(center), points.clone());
     }
+    // only orders the cells, the reports of the routes themselves are kept
     let clusters: Vec<SingleVec> = plugin
-        .run_multi::<JoinFunction>(&centroids, None)
+        .run_multi::<JoinFunction>(&centroids, None, &mut Stats::new(String::new(), 0))
         .unwrap_or(vec![])
         .into_iter()
         .filter_map(|c| {
@@ -1,66 +1,305 @@
-use std::time::Instant;
+use std::io;
+use std::time::{Duration, Instant};
 
-use model::api::{single_vec::SingleVec, sort_by::SortBy};
+use model::api::{
+    Precision,
+    args::{DeviceBalance, Travel},
+    point_array::PointArray,
+    single_vec::SingleVec,
+    sort_by::SortBy,
+    split_level::SplitLevel,
+};
 
-use self::sorting::{SortGeohash, SortLatLng, SortPointCount, SortRandom, SortS2};
+use self::{
+    sorting::{SortGeohash, SortLatLng, SortPointCount, SortRandom, SortS2},
+    spawn_time::TimedSpawn,
+};
 use crate::{
-    plugin::{Folder, Plugin},
+    plugin::{Folder, Plugin, PluginOptions},
+    s2::split_level,
     stats::Stats,
     utils,
 };
 
+pub mod hops;
+pub mod incremental;
 mod join;
+mod partition;
+pub mod path;
 pub mod sorting;
+pub mod spawn_time;
+pub mod tsp;
 // pub mod vrp;
 
+#[derive(Debug, Clone)]
+pub struct RouteOptions {
+    pub sort_by: SortBy,
+    pub route_split_level: SplitLevel,
+    pub routing_args: String,
+    pub routing_time: u64,
+    pub devices: usize,
+    pub device_balance: DeviceBalance,
+    /// Used for the travel time stats when set, `SortBy::SpawnTime` defaults to `10` m/s
+    pub travel: Option<Travel>,
+    /// Maximum distance between two consecutive points, `0` is unlimited
+    pub max_hop_distance: Precision,
+    pub start_point: Option<PointArray>,
+    pub end_point: Option<PointArray>,
+    pub open_path: bool,
+    /// Spawnpoints with a known despawn time, used by `SortBy::SpawnTime` and the spawn stats
+    pub timed_spawns: Vec<TimedSpawn>,
+    /// Seeds the randomized steps, the searches then ignore the time limit and stop on work
+    /// limits that don't depend on the speed of the machine
+    pub seed: Option<u64>,
+    /// Timeout and cancellation of the `SortBy::Custom` plugins
+    pub plugin: PluginOptions,
+}
+
+impl Default for RouteOptions {
+    fn default() -> Self {
+        RouteOptions {
+            sort_by: SortBy::Unset,
+            route_split_level: SplitLevel::default(),
+            routing_args: "".to_string(),
+            routing_time: 0,
+            devices: 1,
+            device_balance: DeviceBalance::Distance,
+            travel: None,
+            max_hop_distance: 0.,
+            start_point: None,
+            end_point: None,
+            open_path: false,
+            timed_spawns: vec![],
+            seed: None,
+            plugin: PluginOptions::default(),
+        }
+    }
+}
+
+impl RouteOptions {
+    fn time_limit(&self, count: usize) -> Duration {
+        if self.routing_time == 0 {
+            tsp::auto_time_limit(count)
+        } else {
+            Duration::from_secs(self.routing_time)
+        }
+    }
+
+    /// Time limit of the steps after the sort, seeded runs only stop once no move is left
+    /// so that they give the same route on any machine
+    fn reorder_limit(&self, time_limit: Duration) -> Option<Duration> {
+        self.seed.is_none().then_some(time_limit)
+    }
+}
+
+/// Splits the clusters between `options.devices` and routes each of them separately.
+/// There are fewer routes than devices when there are fewer clusters than devices.
+/// The split and the routes share the time limit, a quarter goes to the tour that is split
+/// and the rest is divided between the routes. Stats for each route are added to `stats.routes`
+pub fn multi(
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    radius: f64,
+    stats: &mut Stats,
+    options: &RouteOptions,
+) -> io::Result<Vec<SingleVec>> {
+    if options.devices <= 1 {
+        return Ok(vec![main(data_points, clusters, radius, stats, options)?]);
+    }
+    let route_time = Instant::now();
+    let time_limit = options.time_limit(clusters.len());
+    let split_limit = time_limit / 4;
+    let groups = partition::split(
+        clusters,
+        options.devices,
+        &options.device_balance,
+        split_limit,
+        options.seed,
+    );
+    if groups.len() < options.devices {
+        log::warn!(
+            "only {} clusters to split between {} devices, returning {} routes",
+            groups.len(),
+            options.devices,
+            groups.len()
+        );
+    }
+    let route_limit = (time_limit - split_limit) / groups.len().max(1) as u32;
+
+    stats.reset_route_stats();
+    let routes = groups
+        .into_iter()
+        .enumerate()
+        .map(|(i, group)| {
+            let mut route_stats = Stats::new(format!("Device {}", i + 1), stats.min_points);
+            route_stats.weights = stats.weights.clone();
+            route_stats.radii = stats.radii.clone();
+            route_stats.cluster_stats(radius, data_points, &group);
+            route_stats.set_score();
+            let route = route(
+                data_points,
+                group,
+                radius,
+                &mut route_stats,
+                options,
+                route_limit,
+            );
+            stats.add_route(route_stats);
+            route
+        })
+        .collect::<io::Result<Vec<SingleVec>>>()?;
+    stats.set_route_time(route_time);
+
+    Ok(routes)
+}
+
+/// Errors when a `SortBy::Custom` plugin fails, the error is added to `stats.plugin_errors` as well
 pub fn main(
     data_points: &SingleVec,
     clusters: SingleVec,
-    sort_by: &SortBy,
-    route_split_level: u64,
     radius: f64,
     stats: &mut Stats,
-    routing_args: &str,
-) -> SingleVec {
+    options: &RouteOptions,
+) -> io::Result<SingleVec> {
+    let time_limit = options.time_limit(clusters.len());
+    route(data_points, clusters, radius, stats, options, time_limit)
+}
+
+/// Same as [main] with the time limit of the route
+fn route(
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    radius: f64,
+    stats: &mut Stats,
+    options: &RouteOptions,
+    time_limit: Duration,
+) -> io::Result<SingleVec> {
     let route_time = Instant::now();
-    let clusters = match sort_by {
-        SortBy::PointCount => clusters.sort_point_count(&data_points, radius),
+    let reorder_limit = options.reorder_limit(time_limit);
+    let clusters = sort(data_points, clusters, radius, stats, options, time_limit)?;
+    // the spawn time order is timed, only the filler points are added to it
+    let reorder = |clusters: SingleVec| {
+        if options.sort_by == SortBy::SpawnTime {
+            clusters
+        } else {
+            hops::reorder(
+                clusters,
+                options.max_hop_distance,
+                options.open_path,
+                reorder_limit,
+            )
+        }
+    };
+    let clusters = if options.open_path {
+        let improve = matches!(options.sort_by, SortBy::NativeTsp | SortBy::Custom(_));
+        reorder(path::open(
+            clusters,
+            options.start_point.as_ref(),
+            options.end_point.as_ref(),
+            improve,
+            reorder_limit.map(|limit| Instant::now() + limit),
+        ))
+    } else {
+        let clusters = reorder(clusters);
+        if let Some(start_point) = &options.start_point {
+            path::rotate_to_start(clusters, start_point)
+        } else if options.sort_by == SortBy::SpawnTime {
+            // the spawn time order starts where the device should be at the start of the loop
+            clusters
+        } else {
+            utils::rotate_to_best(clusters, stats)
+        }
+    };
+    let (clusters, filler_points) =
+        hops::fill(clusters, options.max_hop_distance, options.open_path);
+    stats.filler_points = filler_points;
+
+    stats.set_route_time(route_time);
+    stats.distance_stats(&clusters, options.open_path);
+    if let Some(travel) = &options.travel {
+        stats.time_stats(&clusters, travel, options.open_path);
+    }
+    if !options.timed_spawns.is_empty() {
+        let coverage = spawn_time::coverage(
+            &clusters,
+            &options.timed_spawns,
+            radius,
+            options.travel.clone().unwrap_or_default(),
+        );
+        stats.spawns_live = coverage.live;
+        stats.spawns_missed = coverage.missed;
+    }
+
+    Ok(clusters)
+}
+
+/// Orders the clusters with `options.sort_by`
+fn sort(
+    data_points: &SingleVec,
+    clusters: SingleVec,
+    radius: f64,
+    stats: &mut Stats,
+    options: &RouteOptions,
+    time_limit: Duration,
+) -> io::Result<SingleVec> {
+    Ok(match &options.sort_by {
+        SortBy::PointCount => clusters.sort_point_count(data_points, radius),
         SortBy::LatLon => clusters.sort_lat_lng(),
         SortBy::GeoHash => clusters.sort_geohash(),
         SortBy::S2Cell => clusters.sort_s2(),
-        SortBy::Random => clusters.sort_random(),
+        SortBy::Random => clusters.sort_random(options.seed),
         SortBy::Unset => clusters,
-        SortBy::Custom(plugin) => {
-            let clusters = clusters.sort_s2();
-            match Plugin::new(plugin, Folder::Routing, route_split_level, routing_args) {
-                Ok(plugin_manager) => match plugin_manager.run_multi(&clusters, Some(join::join)) {
-                    Ok(sorted_clusters) => sorted_clusters,
-                    Err(e) => {
-                        log::error!("Error while running plugin: {}", e);
-                        clusters
-                    }
-                },
-                Err(e) => {
-                    log::error!("Plugin not found: {}", e);
-                    clusters
-                }
+        SortBy::NativeTsp => tsp::solve(&clusters, time_limit, options.seed),
+        SortBy::SpawnTime => spawn_time::solve(
+            &clusters,
+            &options.timed_spawns,
+            radius,
+            options.travel.clone().unwrap_or_default(),
+            time_limit,
+            options.seed,
+        ),
+        SortBy::Custom(plugin) => match custom(plugin, clusters, radius, stats, options) {
+            Ok(sorted_clusters) => sorted_clusters,
+            Err(e) => {
+                log::error!("Error while running plugin: {}", e);
+                stats.plugin_errors.push(e.to_string());
+                return Err(e);
             }
-        }
-    };
-    let clusters = utils::rotate_to_best(clusters, stats);
-
-    stats.set_route_time(route_time);
-    stats.distance_stats(&clusters);
+        },
+    })
+}
 
-    clusters
+fn custom(
+    plugin: &str,
+    clusters: SingleVec,
+    radius: f64,
+    stats: &mut Stats,
+    options: &RouteOptions,
+) -> io::Result<SingleVec> {
+    let clusters = clusters.sort_s2();
+    let route_split_level = split_level(options.route_split_level, &clusters, options.seed);
+    if options.route_split_level == SplitLevel::Auto {
+        stats.route_split_level = Some(route_split_level);
+    }
+    Plugin::new(
+        plugin,
+        Folder::Routing,
+        route_split_level,
+        &options.routing_args,
+    )?
+    .set_radius(radius)
+    .set_options(&options.plugin)
+    .run_multi(&clusters, Some(join::join), stats)
 }
 
 pub fn routing_plugins() -> Vec<String> {
-    utils::get_plugin_list("algorithms/src/routing/plugins").unwrap_or(vec![])
+    utils::get_plugin_list(&Folder::Routing.dir()).unwrap_or(vec![])
 }
 
 pub fn all_routing_options() -> Vec<String> {
     let mut options = routing_plugins();
+    options.push("native_tsp".to_string());
+    options.push("spawn_time".to_string());
     options.push("point_count".to_string());
     options.push("latlon".to_string());
     options.push("geohash".to_string());
@@ -68,3 +307,49 @@ pub fn all_routing_options() -> Vec<String> {
     options.push("random".to_string());
     options
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_plugin_is_an_error() {
+        let clusters = vec![[40., -74.], [40.01, -74.], [40., -74.01], [40.01, -74.01]];
+        let mut stats = Stats::new(String::new(), 1);
+        let options = RouteOptions {
+            sort_by: SortBy::Custom("does_not_exist".to_string()),
+            ..Default::default()
+        };
+        assert!(main(&vec![], clusters, 70., &mut stats, &options).is_err());
+        assert_eq!(stats.plugin_errors.len(), 1);
+    }
+
+    #[test]
+    fn seeded_routes_are_identical() {
+        let clusters: SingleVec = (0..120)
+            .map(|i| {
+                let i = (i * 37 % 120) as Precision;
+                [40. + (i / 12.).floor() * 0.002, -74. + (i % 12.) * 0.002]
+            })
+            .collect();
+        let options = RouteOptions {
+            sort_by: SortBy::NativeTsp,
+            devices: 2,
+            seed: Some(11),
+            ..Default::default()
+        };
+        let route = |options: &RouteOptions| {
+            let mut stats = Stats::new(String::new(), 1);
+            multi(&vec![], clusters.clone(), 70., &mut stats, options).unwrap()
+        };
+        let first = route(&options);
+        assert_eq!(first.len(), 2);
+        assert_eq!(route(&options), first);
+
+        let options = RouteOptions {
+            sort_by: SortBy::Random,
+            ..options
+        };
+        assert_eq!(route(&options), route(&options));
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use geo::{Distance, Haversine, Point};
+use model::api::{Precision, args::DeviceBalance, point_array::PointArray, single_vec::SingleVec};
+
+use super::tsp;
+
+const MAX_OFFSETS: usize = 32;
+const SEARCH_ITERATIONS: usize = 30;
+
+fn distance(a: &PointArray, b: &PointArray) -> Precision {
+    Haversine.distance(Point::new(a[1], a[0]), Point::new(b[1], b[0]))
+}
+
+/// Splits the clusters into `devices` groups, each group is a contiguous section of a single
+/// TSP tour, so the groups stay geographically compact before they are routed on their own.
+/// With no more clusters than devices, each cluster is a group of its own
+/// and fewer than `devices` groups are returned
+pub fn split(
+    clusters: SingleVec,
+    devices: usize,
+    balance: &DeviceBalance,
+    time_limit: Duration,
+    seed: Option<u64>,
+) -> Vec<SingleVec> {
+    if devices <= 1 || clusters.is_empty() {
+        return vec![clusters];
+    }
+    if clusters.len() <= devices {
+        return clusters.into_iter().map(|c| vec![c]).collect();
+    }
+    let time = Instant::now();
+    let tour = tsp::solve(&clusters, time_limit, seed);
+    let legs: Vec<Precision> = tour
+        .iter()
+        .enumerate()
+        .map(|(i, point)| distance(point, &tour[(i + 1) % tour.len()]))
+        .collect();
+
+    let step = (tour.len() / MAX_OFFSETS).max(1);
+    let mut best: Option<(Precision, Vec<SingleVec>)> = None;
+    for offset in (0..tour.len()).step_by(step) {
+        let rotated: SingleVec = tour[offset..]
+            .iter()
+            .chain(&tour[..offset])
+            .copied()
+            .collect();
+        let mut prefix = vec![0.];
+        for i in 0..rotated.len() - 1 {
+            prefix.push(prefix[i] + legs[(offset + i) % legs.len()]);
+        }
+        let cuts = match balance {
+            DeviceBalance::HopCount => hop_cuts(rotated.len(), devices),
+            DeviceBalance::Distance => distance_cuts(&rotated, &prefix, devices),
+        };
+        let cost = cuts
+            .windows(2)
+            .map(|w| loop_cost(&rotated, &prefix, w[0], w[1]))
+            .fold(0., Precision::max);
+        if best.as_ref().is_none_or(|(best_cost, _)| cost < *best_cost) {
+            best = Some((
+                cost,
+                cuts.windows(2)
+                    .map(|w| rotated[w[0]..w[1]].to_vec())
+                    .collect(),
+            ));
+        }
+    }
+    log::info!(
+        "split {} clusters between {} devices in {:.2}s",
+        tour.len(),
+        devices,
+        time.elapsed().as_secs_f32()
+    );
+    best.map(|(_, routes)| routes).unwrap_or_default()
+}
+
+/// Estimated length of the loop made from `start..end` of the tour
+fn loop_cost(tour: &SingleVec, prefix: &[Precision], start: usize, end: usize) -> Precision {
+    prefix[end - 1] - prefix[start] + distance(&tour[end - 1], &tour[start])
+}
+
+fn hop_cuts(len: usize, devices: usize) -> Vec<usize> {
+    let size = len / devices;
+    let remainder = len % devices;
+    let mut cuts = vec![0];
+    for i in 0..devices {
+        cuts.push(cuts[i] + size + if i < remainder { 1 } else { 0 });
+    }
+    cuts
+}
+
+/// Greedily cuts the tour into as few sections as possible without any loop exceeding `limit`
+fn greedy_cuts(tour: &SingleVec, prefix: &[Precision], limit: Precision) -> Vec<usize> {
+    let mut cuts = vec![0];
+    let mut start = 0;
+    for end in 1..tour.len() {
+        if loop_cost(tour, prefix, start, end + 1) > limit {
+            cuts.push(end);
+            start = end;
+        }
+    }
+    cuts.push(tour.len());
+    cuts
+}
+
+/// Binary searches the smallest loop length that still fits within the number of devices
+fn distance_cuts(tour: &SingleVec, prefix: &[Precision], devices: usize) -> Vec<usize> {
+    let mut low = 0.;
+    let mut high = loop_cost(tour, prefix, 0, tour.len());
+    for _ in 0..SEARCH_ITERATIONS {
+        let mid = (low + high) / 2.;
+        if greedy_cuts(tour, prefix, mid).len() - 1 > devices {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let mut cuts = greedy_cuts(tour, prefix, high);
+
+    while cuts.len() - 1 < devices {
+        let (index, _) = cuts
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| w[1] - w[0] > 1)
+            .map(|(i, w)| (i, loop_cost(tour, prefix, w[0], w[1])))
+            .fold((0, Precision::MIN), |best, current| {
+                if current.1 > best.1 { current } else { best }
+            });
+        let mid = (cuts[index] + cuts[index + 1]) / 2;
+        cuts.insert(index + 1, mid);
+    }
+    cuts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(size: usize) -> SingleVec {
+        (0..size * size)
+            .map(|i| {
+                [
+                    40. + (i / size) as Precision * 0.001,
+                    -74. + (i % size) as Precision * 0.001,
+                ]
+            })
+            .collect()
+    }
+
+    fn sorted(mut points: SingleVec) -> SingleVec {
+        points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        points
+    }
+
+    #[test]
+    fn keeps_every_cluster() {
+        let clusters = grid(6);
+        for balance in [DeviceBalance::HopCount, DeviceBalance::Distance] {
+            let routes = split(
+                clusters.clone(),
+                4,
+                &balance,
+                Duration::from_millis(50),
+                Some(1),
+            );
+            assert_eq!(routes.len(), 4);
+            assert!(routes.iter().all(|route| !route.is_empty()));
+            assert_eq!(
+                sorted(routes.into_iter().flatten().collect()),
+                sorted(clusters.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn hop_count_balances_sizes() {
+        let routes = split(
+            grid(5),
+            3,
+            &DeviceBalance::HopCount,
+            Duration::from_millis(50),
+            Some(1),
+        );
+        let sizes: Vec<usize> = routes.iter().map(|route| route.len()).collect();
+        assert_eq!(sizes.iter().sum::<usize>(), 25);
+        assert!(sizes.iter().max().unwrap() - sizes.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn small_inputs() {
+        let clusters = grid(1);
+        let routes = split(
+            clusters.clone(),
+            1,
+            &DeviceBalance::HopCount,
+            Duration::from_millis(10),
+            None,
+        );
+        assert_eq!(routes, vec![clusters]);
+
+        let clusters = grid(2);
+        let routes = split(
+            clusters.clone(),
+            6,
+            &DeviceBalance::Distance,
+            Duration::from_millis(10),
+            None,
+        );
+        assert_eq!(
+            routes,
+            clusters.into_iter().map(|c| vec![c]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn hop_cuts_spread_the_remainder() {
+        assert_eq!(hop_cuts(10, 3), vec![0, 4, 7, 10]);
+        assert_eq!(hop_cuts(9, 3), vec![0, 3, 6, 9]);
+    }
+}
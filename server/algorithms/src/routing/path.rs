@@ -0,0 +1,154 @@
+use std::time::Instant;
+
+use geo::{Distance, Haversine, Point};
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+
+use super::tsp::Projection;
+
+const EPSILON: Precision = 1e-7;
+
+fn distance(a: &PointArray, b: &PointArray) -> Precision {
+    Haversine.distance(Point::new(a[1], a[0]), Point::new(b[1], b[0]))
+}
+
+fn path_distance(path: &[PointArray]) -> Precision {
+    path.windows(2).map(|w| distance(&w[0], &w[1])).sum()
+}
+
+/// Index of the route point closest to `target`
+fn nearest(route: &SingleVec, target: &PointArray) -> Option<usize> {
+    route
+        .iter()
+        .map(|point| distance(point, target))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+}
+
+/// Rotates the loop so it begins with the point closest to `start_point`
+pub fn rotate_to_start(mut route: SingleVec, start_point: &PointArray) -> SingleVec {
+    if let Some(start) = nearest(&route, start_point) {
+        route.rotate_left(start);
+    }
+    route
+}
+
+/// Cuts the loop into an open path, beginning and ending with the points closest to
+/// `start_point` and `end_point` when provided, otherwise the longest leg is dropped.
+/// When `improve` is set, the path is then shortened with 2-opt moves that keep the fixed ends
+pub fn open(
+    mut route: SingleVec,
+    start_point: Option<&PointArray>,
+    end_point: Option<&PointArray>,
+    improve: bool,
+    deadline: Option<Instant>,
+) -> SingleVec {
+    let len = route.len();
+    if len < 3 {
+        if let Some(start_point) = start_point {
+            return rotate_to_start(route, start_point);
+        }
+        return route;
+    }
+    let start = start_point.and_then(|point| nearest(&route, point));
+    let end = end_point
+        .and_then(|point| nearest(&route, point))
+        .filter(|end| Some(*end) != start);
+
+    let mut path = match (start, end) {
+        (Some(start), Some(end)) => {
+            route.rotate_left(start);
+            let end = (end + len - start) % len;
+            let mut forward = route[..end].to_vec();
+            forward.extend(route[end + 1..].iter().rev());
+            forward.push(route[end]);
+
+            let mut backward = vec![route[0]];
+            backward.extend(route[end + 1..].iter().rev());
+            backward.extend(&route[1..=end]);
+            if path_distance(&backward) < path_distance(&forward) {
+                backward
+            } else {
+                forward
+            }
+        }
+        (Some(index), None) | (None, Some(index)) => {
+            route.rotate_left(index);
+            // drops the longer of the two legs next to the fixed end
+            if distance(&route[0], &route[1]) > distance(&route[len - 1], &route[0]) {
+                route[1..].reverse();
+            }
+            if end.is_some() {
+                route.reverse();
+            }
+            route
+        }
+        (None, None) => {
+            let longest = (0..len)
+                .map(|i| (i, distance(&route[i], &route[(i + 1) % len])))
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(i, _)| i)
+                .unwrap_or(len - 1);
+            route.rotate_left((longest + 1) % len);
+            route
+        }
+    };
+    if improve {
+        two_opt(&mut path, start.is_some(), end.is_some(), deadline);
+    }
+    path
+}
+
+/// 2-opt for open paths, a free end can also be moved by reversing the segment up to it.
+/// Runs until no move is left when there is no deadline
+fn two_opt(path: &mut SingleVec, fixed_start: bool, fixed_end: bool, deadline: Option<Instant>) {
+    let len = path.len();
+    let projection = Projection::new(path);
+    let mut coords: Vec<[Precision; 2]> = path.iter().map(|p| projection.project(p)).collect();
+    let mut order: Vec<usize> = (0..len).collect();
+    let distance = |coords: &[[Precision; 2]], a: usize, b: usize| {
+        let dx = coords[a][0] - coords[b][0];
+        let dy = coords[a][1] - coords[b][1];
+        (dx * dx + dy * dy).sqrt()
+    };
+
+    let mut improved = true;
+    while improved && deadline.is_none_or(|deadline| Instant::now() < deadline) {
+        improved = false;
+        for from in 0..len - 1 {
+            if from == 0 && fixed_start {
+                continue;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+            for to in from + 1..len {
+                if to == len - 1 && (fixed_end || from == 0) {
+                    continue;
+                }
+                let (before, new_before) = if from > 0 {
+                    (
+                        distance(&coords, from - 1, from),
+                        distance(&coords, from - 1, to),
+                    )
+                } else {
+                    (0., 0.)
+                };
+                let (after, new_after) = if to < len - 1 {
+                    (
+                        distance(&coords, to, to + 1),
+                        distance(&coords, from, to + 1),
+                    )
+                } else {
+                    (0., 0.)
+                };
+                if new_before + new_after < before + after - EPSILON {
+                    coords[from..=to].reverse();
+                    order[from..=to].reverse();
+                    improved = true;
+                }
+            }
+        }
+    }
+    *path = order.into_iter().map(|i| path[i]).collect();
+}
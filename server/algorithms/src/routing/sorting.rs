@@ -1,7 +1,11 @@
 use geo::Coord;
 use geohash::encode;
 use model::api::single_vec::SingleVec;
-use rand::{rngs::mock::StepRng, seq::SliceRandom};
+use rand::{
+    SeedableRng,
+    rngs::{SmallRng, mock::StepRng},
+    seq::SliceRandom,
+};
 use rayon::{
     iter::{IntoParallelRefIterator, ParallelIterator},
     slice::ParallelSliceMut,
@@ -11,20 +15,23 @@ use s2::{cellid::CellID, latlng::LatLng};
 use crate::rtree::{self, cluster, point};
 
 pub trait SortRandom {
-    fn sort_random(self) -> Self;
-    fn sort_random_mut(&mut self);
+    /// Shuffles with `seed`, or the same fixed order every time when it isn't set
+    fn sort_random(self, seed: Option<u64>) -> Self;
+    fn sort_random_mut(&mut self, seed: Option<u64>);
 }
 
 impl SortRandom for SingleVec {
-    fn sort_random(self) -> Self {
+    fn sort_random(self, seed: Option<u64>) -> Self {
         let mut clusters = self;
-        clusters.sort_random_mut();
+        clusters.sort_random_mut(seed);
         clusters
     }
 
-    fn sort_random_mut(&mut self) {
-        let mut rng = StepRng::new(2, 13);
-        self.shuffle(&mut rng);
+    fn sort_random_mut(&mut self, seed: Option<u64>) {
+        match seed {
+            Some(seed) => self.shuffle(&mut SmallRng::seed_from_u64(seed)),
+            None => self.shuffle(&mut StepRng::new(2, 13)),
+        }
     }
 }
 
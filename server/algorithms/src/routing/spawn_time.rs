@@ -0,0 +1,481 @@
+use std::time::{Duration, Instant};
+
+use geo::{Distance, Haversine, Point};
+use model::api::{Precision, args::Travel, point_array::PointArray, single_vec::SingleVec};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use rstar::RTree;
+
+use super::tsp::{self, IndexedPoint, Projection};
+
+/// Minimum amount of time, in seconds, that a spawn is active before it despawns
+const SPAWN_DURATION: Precision = 1800.;
+const HOUR: Precision = 3600.;
+/// Interval, in seconds, between the loop start times that are evaluated
+const START_STEP: usize = 60;
+/// Number of start times that the greedy construction is run from
+const GREEDY_STARTS: usize = 6;
+/// Added to every leg so the greedy construction does not favor tiny hops too much
+const LEG_OFFSET: Precision = 60.;
+
+/// A spawnpoint with a known despawn time
+#[derive(Debug, Clone)]
+pub struct TimedSpawn {
+    pub point: PointArray,
+    pub despawn_sec: u16,
+}
+
+/// Covered spawns seen live or missed for a route
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpawnCoverage {
+    pub live: usize,
+    pub missed: usize,
+}
+
+/// Timing model for a route, the device starts the loop at `start` seconds past the hour
+/// and travels according to [Travel], spawns repeat every hour. Evaluated over one loop,
+/// or over the first hour when the loop is shorter than that. A spawn is seen live when one of
+/// the clusters covering it is visited within [SPAWN_DURATION] seconds before its despawn time.
+struct Schedule {
+    coords: Vec<[Precision; 2]>,
+    covered: Vec<Vec<usize>>,
+    despawns: Vec<Precision>,
+    total_covered: usize,
+    travel: Travel,
+}
+
+struct Evaluation {
+    start: Precision,
+    live: usize,
+    duration: Precision,
+}
+
+impl Evaluation {
+    fn is_better(&self, other: &Self) -> bool {
+        self.live > other.live || (self.live == other.live && self.duration < other.duration)
+    }
+}
+
+impl Schedule {
+    fn new(clusters: &SingleVec, spawns: &[TimedSpawn], radius: Precision, travel: Travel) -> Self {
+        let projection = Projection::new(clusters);
+        let coords: Vec<[Precision; 2]> = clusters.iter().map(|c| projection.project(c)).collect();
+        let tree: RTree<IndexedPoint> = RTree::bulk_load(
+            spawns
+                .iter()
+                .enumerate()
+                .map(|(i, spawn)| IndexedPoint::new(projection.project(&spawn.point), i))
+                .collect(),
+        );
+        // the projection is only approximate, so the search is padded and then checked properly
+        let search_radius = radius * 1.05 + 1.;
+        let mut is_covered = vec![false; spawns.len()];
+        let covered: Vec<Vec<usize>> = clusters
+            .iter()
+            .zip(coords.iter())
+            .map(|(cluster, coord)| {
+                let center = Point::new(cluster[1], cluster[0]);
+                tree.locate_within_distance(*coord, search_radius * search_radius)
+                    .map(|spawn| spawn.data)
+                    .filter(|&i| {
+                        let point = spawns[i].point;
+                        Haversine.distance(center, Point::new(point[1], point[0])) <= radius
+                    })
+                    .inspect(|&i| is_covered[i] = true)
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            coords,
+            covered,
+            despawns: spawns.iter().map(|s| s.despawn_sec as Precision).collect(),
+            total_covered: is_covered.into_iter().filter(|c| *c).count(),
+            travel,
+        }
+    }
+
+    fn travel(&self, a: usize, b: usize) -> Precision {
+        let dx = self.coords[a][0] - self.coords[b][0];
+        let dy = self.coords[a][1] - self.coords[b][1];
+        self.travel.leg_time((dx * dx + dy * dy).sqrt())
+    }
+
+    /// Seconds left until the spawn despawns, when it is currently active
+    fn remaining(&self, spawn: usize, time: Precision) -> Option<Precision> {
+        let remaining = (self.despawns[spawn] - time).rem_euclid(HOUR);
+        if remaining < SPAWN_DURATION {
+            Some(remaining)
+        } else {
+            None
+        }
+    }
+
+    /// Arrival time at each cluster of the order, relative to the loop start, and the loop duration
+    fn arrivals(&self, order: &[usize]) -> (Vec<Precision>, Precision) {
+        let mut time = 0.;
+        let mut arrivals = Vec::with_capacity(order.len());
+        for (i, &cluster) in order.iter().enumerate() {
+            if i > 0 {
+                time += self.travel(order[i - 1], cluster);
+            }
+            arrivals.push(time);
+        }
+        let duration = match (order.first(), order.last()) {
+            (Some(&first), Some(&last)) => time + self.travel(last, first),
+            _ => 0.,
+        };
+        (arrivals, duration)
+    }
+
+    fn live_count(
+        &self,
+        order: &[usize],
+        arrivals: &[Precision],
+        duration: Precision,
+        start: Precision,
+        seen: &mut [bool],
+    ) -> usize {
+        seen.fill(false);
+        let horizon = HOUR.max(duration);
+        // a loop shorter than a minute is checked once a minute, which caps the passes
+        // since a spawn is active for much longer than that
+        let period = duration.max(START_STEP as Precision);
+        let mut count = 0;
+        for (&cluster, &arrival) in order.iter().zip(arrivals) {
+            let mut time = arrival;
+            while time < horizon {
+                for &spawn in self.covered[cluster].iter() {
+                    if !seen[spawn] && self.remaining(spawn, start + time).is_some() {
+                        seen[spawn] = true;
+                        count += 1;
+                    }
+                }
+                if duration <= 0. {
+                    break;
+                }
+                time += period;
+            }
+        }
+        count
+    }
+
+    fn evaluate(&self, order: &[usize], start: Option<Precision>) -> Evaluation {
+        let (arrivals, duration) = self.arrivals(order);
+        let mut seen = vec![false; self.despawns.len()];
+        let mut evaluate_start = |start: Precision| Evaluation {
+            start,
+            live: self.live_count(order, &arrivals, duration, start, &mut seen),
+            duration,
+        };
+        if let Some(start) = start {
+            return evaluate_start(start);
+        }
+        let mut best = evaluate_start(0.);
+        for start in (START_STEP..HOUR as usize).step_by(START_STEP) {
+            let current = evaluate_start(start as Precision);
+            if current.live > best.live {
+                best = current;
+            }
+        }
+        best
+    }
+
+    /// Builds the order by repeatedly travelling to the cluster with the most urgent
+    /// active spawns per second of travel, falling back to the nearest cluster
+    /// once the deadline has passed
+    fn greedy(&self, start: Precision, deadline: Option<Instant>) -> Vec<usize> {
+        let len = self.coords.len();
+        let mut visited = vec![false; len];
+        let mut seen = vec![false; self.despawns.len()];
+        let mut order = Vec::with_capacity(len);
+
+        let mut current = (0..len)
+            .max_by_key(|&c| {
+                self.covered[c]
+                    .iter()
+                    .filter(|&&s| self.remaining(s, start).is_some())
+                    .count()
+            })
+            .unwrap_or(0);
+        let mut time = 0.;
+        loop {
+            visited[current] = true;
+            order.push(current);
+            for &spawn in self.covered[current].iter() {
+                if self.remaining(spawn, start + time).is_some() {
+                    seen[spawn] = true;
+                }
+            }
+            if order.len() == len {
+                break;
+            }
+            let timed_out = passed(deadline);
+            let mut best: Option<(usize, Precision)> = None;
+            let mut nearest: Option<(usize, Precision)> = None;
+            for next in (0..len).filter(|&n| !visited[n]) {
+                let travel = self.travel(current, next);
+                if nearest.is_none_or(|(_, t)| travel < t) {
+                    nearest = Some((next, travel));
+                }
+                if timed_out {
+                    continue;
+                }
+                let arrival = start + time + travel;
+                let score: Precision = self.covered[next]
+                    .iter()
+                    .filter(|&&s| !seen[s])
+                    .filter_map(|&s| self.remaining(s, arrival))
+                    .map(|remaining| 2. - remaining / SPAWN_DURATION)
+                    .sum();
+                if score > 0. {
+                    let score = score / (travel + LEG_OFFSET);
+                    if best.is_none_or(|(_, b)| score > b) {
+                        best = Some((next, score));
+                    }
+                }
+            }
+            if let Some((next, _)) = best.or(nearest) {
+                time += self.travel(current, next);
+                current = next;
+            }
+        }
+        order
+    }
+
+    /// Random relocation moves, kept when more spawns are seen live or the loop gets shorter
+    fn improve(
+        &self,
+        order: &mut Vec<usize>,
+        best: Evaluation,
+        deadline: Option<Instant>,
+        seed: u64,
+    ) -> Evaluation {
+        let len = order.len();
+        let mut best = best;
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let max_moves = len * 200;
+        let mut moves = 0;
+        while moves < max_moves && !passed(deadline) {
+            moves += 1;
+            let from = rng.random_range(0..len);
+            let to = rng.random_range(0..len - 1);
+            let cluster = order.remove(from);
+            order.insert(to, cluster);
+
+            let current = self.evaluate(order, Some(best.start));
+            if current.is_better(&best) {
+                best = current;
+            } else {
+                order.remove(to);
+                order.insert(from, cluster);
+            }
+        }
+        log::debug!("[SPAWN_TIME] finished after {} moves", moves);
+        best
+    }
+}
+
+/// Whether the deadline has passed, runs without one only stop on their work caps
+fn passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Orders the clusters so they are visited while the spawns they cover are active,
+/// the first cluster of the result is where the device should be at the logged start time.
+/// A seeded run ignores `time_limit` and stops after a fixed amount of work instead,
+/// so it gives the same route on any machine
+pub fn solve(
+    clusters: &SingleVec,
+    spawns: &[TimedSpawn],
+    radius: Precision,
+    travel: Travel,
+    time_limit: Duration,
+    seed: Option<u64>,
+) -> SingleVec {
+    if spawns.is_empty() {
+        log::warn!(
+            "No spawnpoints with a known despawn time, falling back to the native TSP solver"
+        );
+        return tsp::solve(clusters, time_limit, seed);
+    }
+    if clusters.len() < 4 {
+        return clusters.clone();
+    }
+    let time = Instant::now();
+    let deadline = |limit: Duration| seed.is_none().then(|| time + limit);
+    let schedule = Schedule::new(clusters, spawns, radius, travel);
+
+    let mut best_order = tsp::solve_indices(clusters, time_limit / 4, seed);
+    let mut best = schedule.evaluate(&best_order, None);
+    let tsp_live = best.live;
+
+    let greedy_deadline = deadline(time_limit / 2);
+    for i in 0..GREEDY_STARTS {
+        if passed(greedy_deadline) {
+            break;
+        }
+        let order = schedule.greedy(
+            i as Precision * HOUR / GREEDY_STARTS as Precision,
+            greedy_deadline,
+        );
+        let current = schedule.evaluate(&order, None);
+        if current.is_better(&best) {
+            best = current;
+            best_order = order;
+        }
+    }
+    let best = schedule.improve(
+        &mut best_order,
+        best,
+        deadline(time_limit),
+        seed.unwrap_or(clusters.len() as u64),
+    );
+
+    log::info!(
+        "[SPAWN_TIME] routed {} clusters in {:.2}s | live spawns: {} (TSP: {}) / {} | start at {:02}:{:02} | loop: {:.0}s",
+        clusters.len(),
+        time.elapsed().as_secs_f32(),
+        best.live,
+        tsp_live,
+        schedule.total_covered,
+        best.start as u32 / 60,
+        best.start as u32 % 60,
+        best.duration,
+    );
+    best_order.into_iter().map(|i| clusters[i]).collect()
+}
+
+/// Counts the covered spawns that would be seen live or missed when the route is
+/// started at the best time of the hour
+pub fn coverage(
+    route: &SingleVec,
+    spawns: &[TimedSpawn],
+    radius: Precision,
+    travel: Travel,
+) -> SpawnCoverage {
+    if route.is_empty() || spawns.is_empty() {
+        return SpawnCoverage::default();
+    }
+    let schedule = Schedule::new(route, spawns, radius, travel);
+    let order: Vec<usize> = (0..route.len()).collect();
+    let live = schedule.evaluate(&order, None).live;
+    SpawnCoverage {
+        live,
+        missed: schedule.total_covered - live,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(len: usize) -> SingleVec {
+        (0..len)
+            .map(|i| [40., -74. + i as Precision * 0.01])
+            .collect()
+    }
+
+    fn sorted(mut points: SingleVec) -> SingleVec {
+        points.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        points
+    }
+
+    #[test]
+    fn remaining_wraps_around_the_hour() {
+        let spawns = vec![TimedSpawn {
+            point: [40., -74.],
+            despawn_sec: 600,
+        }];
+        let schedule = Schedule::new(&line(1), &spawns, 70., Travel::default());
+        assert_eq!(schedule.remaining(0, 0.), Some(600.));
+        assert_eq!(schedule.remaining(0, 3000.), Some(1200.));
+        assert_eq!(schedule.remaining(0, 1200.), None);
+    }
+
+    #[test]
+    fn keeps_every_cluster() {
+        let clusters = line(12);
+        let spawns: Vec<TimedSpawn> = clusters
+            .iter()
+            .enumerate()
+            .map(|(i, point)| TimedSpawn {
+                point: *point,
+                despawn_sec: (i * 300 % 3600) as u16,
+            })
+            .collect();
+        let route = solve(
+            &clusters,
+            &spawns,
+            70.,
+            Travel::default(),
+            Duration::from_millis(100),
+            Some(3),
+        );
+        assert_eq!(sorted(route), sorted(clusters));
+    }
+
+    #[test]
+    fn coverage_splits_the_covered_spawns() {
+        let route = line(6);
+        let mut spawns: Vec<TimedSpawn> = route
+            .iter()
+            .map(|point| TimedSpawn {
+                point: *point,
+                despawn_sec: 0,
+            })
+            .collect();
+        // out of reach of every cluster
+        spawns.push(TimedSpawn {
+            point: [41., -74.],
+            despawn_sec: 0,
+        });
+        let coverage = coverage(&route, &spawns, 70., Travel::default());
+        assert_eq!(coverage.live + coverage.missed, route.len());
+        assert!(coverage.live > 0);
+    }
+
+    #[test]
+    fn without_spawns() {
+        let clusters = line(8);
+        assert_eq!(
+            coverage(&clusters, &[], 70., Travel::default()).live,
+            SpawnCoverage::default().live
+        );
+        let route = solve(
+            &clusters,
+            &[],
+            70.,
+            Travel::default(),
+            Duration::from_millis(50),
+            Some(1),
+        );
+        assert_eq!(sorted(route), sorted(clusters));
+    }
+
+    #[test]
+    fn near_zero_leg_times() {
+        let clusters = line(40);
+        let spawns: Vec<TimedSpawn> = clusters
+            .iter()
+            .enumerate()
+            .map(|(i, point)| TimedSpawn {
+                point: *point,
+                despawn_sec: (i * 90 % 3600) as u16,
+            })
+            .collect();
+        let travel = Travel::Cooldown(vec![[1e6, 0.01]]);
+        let time = Instant::now();
+        let route = solve(
+            &clusters,
+            &spawns,
+            70.,
+            travel.clone(),
+            Duration::from_millis(100),
+            Some(1),
+        );
+        assert!(time.elapsed() < Duration::from_secs(10));
+        assert_eq!(sorted(route), sorted(clusters.clone()));
+        // every spawn is active at some point of the hour and the loop is repeated all the time
+        assert_eq!(coverage(&clusters, &spawns, 70., travel).missed, 0);
+    }
+}
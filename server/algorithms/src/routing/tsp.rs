@@ -0,0 +1,470 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+use rand::{Rng, SeedableRng, rngs::SmallRng};
+use rstar::{RTree, primitives::GeomWithData};
+
+const EARTH_RADIUS: Precision = 6_371_008.8;
+const NEIGHBORS: usize = 10;
+const MAX_SEGMENT: usize = 3;
+const KICK_RANGE: usize = 50;
+const EPSILON: Precision = 1e-7;
+/// Kicks per point of a seeded search, it stops after them instead of at the deadline
+const SEEDED_KICKS: usize = 20;
+
+pub(super) type IndexedPoint = GeomWithData<[Precision; 2], usize>;
+
+/// Native TSP solver, nearest neighbor construction followed by 2-opt & Or-opt local search.
+/// Any remaining time is spent on an iterated local search with segment double bridge kicks.
+pub struct Tsp {
+    coords: Vec<[Precision; 2]>,
+    neighbors: Vec<Vec<usize>>,
+    tour: Vec<usize>,
+    position: Vec<usize>,
+    queue: VecDeque<usize>,
+    queued: Vec<bool>,
+    deadline: Instant,
+    /// Seed of the kicks, when it is set the deadline is ignored and the number of kicks is capped
+    seed: Option<u64>,
+}
+
+/// Returns the auto time budget when the user did not provide one
+pub fn auto_time_limit(count: usize) -> Duration {
+    Duration::from_millis((count as u64 * 2).clamp(500, 30_000))
+}
+
+/// The kicks are seeded with `seed`, or the number of points when it isn't set.
+/// A seeded search ignores `time_limit` and stops after a fixed number of kicks,
+/// so it gives the same tour on any machine
+pub fn solve(points: &SingleVec, time_limit: Duration, seed: Option<u64>) -> SingleVec {
+    if points.len() < 4 {
+        return points.clone();
+    }
+    solve_indices(points, time_limit, seed)
+        .into_iter()
+        .map(|i| points[i])
+        .collect()
+}
+
+/// Same as [solve] but returns the order as indices of `points`
+pub(super) fn solve_indices(
+    points: &SingleVec,
+    time_limit: Duration,
+    seed: Option<u64>,
+) -> Vec<usize> {
+    if points.len() < 4 {
+        return (0..points.len()).collect();
+    }
+    let time = Instant::now();
+    let mut tsp = Tsp::new(points, time_limit, seed);
+    let start_distance = tsp.tour_distance();
+    tsp.optimize();
+    log::info!(
+        "[TSP] solved {} points in {:.2}s | {:.0}m -> {:.0}m",
+        points.len(),
+        time.elapsed().as_secs_f32(),
+        start_distance,
+        tsp.tour_distance()
+    );
+    tsp.tour
+}
+
+/// Equirectangular projection to meters, accurate enough at city scale
+pub(crate) struct Projection {
+    lon_scale: Precision,
+}
+
+impl Projection {
+    pub fn new(points: &SingleVec) -> Self {
+        let mean_lat = points.iter().map(|p| p[0]).sum::<Precision>() / points.len() as Precision;
+        Self {
+            lon_scale: mean_lat.to_radians().cos(),
+        }
+    }
+
+    pub fn project(&self, point: &PointArray) -> [Precision; 2] {
+        [
+            point[1].to_radians() * self.lon_scale * EARTH_RADIUS,
+            point[0].to_radians() * EARTH_RADIUS,
+        ]
+    }
+}
+
+impl Tsp {
+    fn new(points: &SingleVec, time_limit: Duration, seed: Option<u64>) -> Self {
+        let deadline = Instant::now() + time_limit;
+        let projection = Projection::new(points);
+        let coords: Vec<[Precision; 2]> = points.iter().map(|p| projection.project(p)).collect();
+
+        let tree: RTree<IndexedPoint> = RTree::bulk_load(
+            coords
+                .iter()
+                .enumerate()
+                .map(|(i, c)| IndexedPoint::new(*c, i))
+                .collect(),
+        );
+        let neighbors = coords
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                tree.nearest_neighbor_iter(c)
+                    .filter(|n| n.data != i)
+                    .take(NEIGHBORS)
+                    .map(|n| n.data)
+                    .collect()
+            })
+            .collect();
+
+        let mut tsp = Self {
+            tour: Vec::with_capacity(coords.len()),
+            position: vec![0; coords.len()],
+            queue: VecDeque::with_capacity(coords.len()),
+            queued: vec![false; coords.len()],
+            coords,
+            neighbors,
+            deadline,
+            seed,
+        };
+        tsp.nearest_neighbor(tree);
+        tsp
+    }
+
+    fn nearest_neighbor(&mut self, mut tree: RTree<IndexedPoint>) {
+        let mut current = 0;
+        tree.remove(&IndexedPoint::new(self.coords[current], current));
+        self.tour.push(current);
+        while let Some(next) = tree.nearest_neighbor(&self.coords[current]).cloned() {
+            tree.remove(&next);
+            current = next.data;
+            self.tour.push(current);
+        }
+        self.set_positions(0, self.tour.len());
+    }
+
+    fn distance(&self, a: usize, b: usize) -> Precision {
+        let dx = self.coords[a][0] - self.coords[b][0];
+        let dy = self.coords[a][1] - self.coords[b][1];
+        (dx * dx + dy * dy).sqrt()
+    }
+
+    fn tour_distance(&self) -> Precision {
+        self.tour
+            .iter()
+            .enumerate()
+            .map(|(i, &a)| self.distance(a, self.tour[(i + 1) % self.tour.len()]))
+            .sum()
+    }
+
+    fn next(&self, city: usize) -> usize {
+        self.tour[(self.position[city] + 1) % self.tour.len()]
+    }
+
+    fn prev(&self, city: usize) -> usize {
+        let len = self.tour.len();
+        self.tour[(self.position[city] + len - 1) % len]
+    }
+
+    fn set_positions(&mut self, start: usize, end: usize) {
+        for i in start..end {
+            self.position[self.tour[i]] = i;
+        }
+    }
+
+    fn push(&mut self, city: usize) {
+        if !self.queued[city] {
+            self.queued[city] = true;
+            self.queue.push_back(city);
+        }
+    }
+
+    fn timed_out(&self) -> bool {
+        self.seed.is_none() && Instant::now() >= self.deadline
+    }
+
+    /// Reverses the tour between the two positions (inclusive, wrapping),
+    /// the shorter side is reversed since both produce the same cycle
+    fn reverse(&mut self, from: usize, to: usize) {
+        let len = self.tour.len();
+        let mut inner = (to + len - from) % len + 1;
+        let (mut i, mut j) = if inner * 2 > len {
+            inner = len - inner;
+            ((to + 1) % len, (from + len - 1) % len)
+        } else {
+            (from, to)
+        };
+        for _ in 0..inner / 2 {
+            self.tour.swap(i, j);
+            self.position[self.tour[i]] = i;
+            self.position[self.tour[j]] = j;
+            i = (i + 1) % len;
+            j = (j + len - 1) % len;
+        }
+    }
+
+    fn two_opt(&mut self, a: usize) -> bool {
+        let a_next = self.next(a);
+        let a_prev = self.prev(a);
+        for index in 0..self.neighbors[a].len() {
+            let c = self.neighbors[a][index];
+            let new_edge = self.distance(a, c);
+
+            let gain = self.distance(a, a_next) - new_edge;
+            if gain > EPSILON {
+                let c_next = self.next(c);
+                if c != a_next && c_next != a {
+                    let delta = self.distance(a_next, c_next) - self.distance(c, c_next) - gain;
+                    if delta < -EPSILON {
+                        self.reverse(self.position[a_next], self.position[c]);
+                        for city in [a, a_next, c, c_next] {
+                            self.push(city);
+                        }
+                        return true;
+                    }
+                }
+            }
+
+            let gain = self.distance(a_prev, a) - new_edge;
+            if gain > EPSILON {
+                let c_prev = self.prev(c);
+                if c != a_prev && c_prev != a {
+                    let delta = self.distance(a_prev, c_prev) - self.distance(c_prev, c) - gain;
+                    if delta < -EPSILON {
+                        self.reverse(self.position[a], self.position[c_prev]);
+                        for city in [a, a_prev, c, c_prev] {
+                            self.push(city);
+                        }
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Tries to move a segment of up to [MAX_SEGMENT] cities starting at `a`
+    /// next to one of its neighbors, in either orientation
+    fn or_opt(&mut self, a: usize) -> bool {
+        let len = self.tour.len();
+        let start = self.position[a];
+        for segment_len in 1..=MAX_SEGMENT {
+            let end = start + segment_len - 1;
+            if end >= len || segment_len + 2 > len {
+                break;
+            }
+            let first = self.tour[start];
+            let last = self.tour[end];
+            let before = self.prev(first);
+            let after = self.next(last);
+            let removal_gain = self.distance(before, first) + self.distance(last, after)
+                - self.distance(before, after);
+            if removal_gain <= EPSILON {
+                continue;
+            }
+            for index in 0..self.neighbors[a].len() {
+                let c = self.neighbors[a][index];
+                let c_pos = self.position[c];
+                if (start..=end).contains(&c_pos) {
+                    continue;
+                }
+                for (left, right) in [(c, self.next(c)), (self.prev(c), c)] {
+                    let left_pos = self.position[left];
+                    if left == before || (start..=end).contains(&left_pos) {
+                        continue;
+                    }
+                    if (start..=end).contains(&self.position[right]) {
+                        continue;
+                    }
+                    let base = self.distance(left, right);
+                    let forward = self.distance(left, first) + self.distance(last, right) - base;
+                    let reversed = self.distance(left, last) + self.distance(first, right) - base;
+                    let (insert_cost, reverse) = if reversed < forward {
+                        (reversed, true)
+                    } else {
+                        (forward, false)
+                    };
+                    if insert_cost - removal_gain < -EPSILON {
+                        self.move_segment(start, end, left_pos, reverse);
+                        for city in [before, after, left, right, first, last] {
+                            self.push(city);
+                        }
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Moves the segment `start..=end` to directly after `target`
+    fn move_segment(&mut self, start: usize, end: usize, target: usize, reverse: bool) {
+        let segment_len = end - start + 1;
+        let (range_start, range_end, segment_start) = if target > end {
+            self.tour[start..=target].rotate_left(segment_len);
+            (start, target + 1, target + 1 - segment_len)
+        } else {
+            self.tour[target + 1..=end].rotate_right(segment_len);
+            (target + 1, end + 1, target + 1)
+        };
+        if reverse {
+            self.tour[segment_start..segment_start + segment_len].reverse();
+        }
+        self.set_positions(range_start, range_end);
+    }
+
+    fn local_search(&mut self) {
+        while let Some(city) = self.queue.pop_front() {
+            self.queued[city] = false;
+            if self.timed_out() {
+                self.queue.clear();
+                self.queued.iter_mut().for_each(|q| *q = false);
+                break;
+            }
+            if self.two_opt(city) || self.or_opt(city) {
+                self.push(city);
+            }
+        }
+    }
+
+    /// Local double bridge, swaps two adjacent segments within a small window of the tour
+    fn kick(&mut self, rng: &mut SmallRng) {
+        let len = self.tour.len();
+        let window = KICK_RANGE.min(len - 1);
+        let start = rng.random_range(0..len - window);
+        let end = start + window;
+        let first = rng.random_range(start + 2..end);
+        let second = rng.random_range(first + 1..=end);
+
+        self.tour[start + 1..second].rotate_left(first - start - 1);
+        self.set_positions(start + 1, second);
+
+        for i in start..=second.min(len - 1) {
+            self.push(self.tour[i]);
+        }
+    }
+
+    fn optimize(&mut self) {
+        for i in 0..self.tour.len() {
+            self.push(self.tour[i]);
+        }
+        self.local_search();
+
+        let mut best_distance = self.tour_distance();
+        let mut best_tour = self.tour.clone();
+        let len = self.tour.len();
+        let mut rng = SmallRng::seed_from_u64(self.seed.unwrap_or(len as u64));
+        let max_stall = (len * 10).max(1_000);
+        let max_kicks = if self.seed.is_some() {
+            (len * SEEDED_KICKS).max(1_000)
+        } else {
+            usize::MAX
+        };
+        let mut stall = 0;
+        let mut kicks = 0;
+
+        while stall < max_stall && kicks < max_kicks && !self.timed_out() && len > 8 {
+            kicks += 1;
+            self.kick(&mut rng);
+            self.local_search();
+
+            let distance = self.tour_distance();
+            if distance < best_distance - EPSILON {
+                best_distance = distance;
+                best_tour.clone_from(&self.tour);
+                stall = 0;
+            } else {
+                self.tour.clone_from(&best_tour);
+                self.set_positions(0, len);
+                stall += 1;
+            }
+        }
+        log::debug!("[TSP] finished after {} kicks", kicks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Distance, Haversine, Point};
+
+    use super::*;
+
+    fn loop_distance(route: &SingleVec) -> Precision {
+        (0..route.len())
+            .map(|i| {
+                let (a, b) = (route[i], route[(i + 1) % route.len()]);
+                Haversine.distance(Point::new(a[1], a[0]), Point::new(b[1], b[0]))
+            })
+            .sum()
+    }
+
+    /// Points on a circle, shuffled so that the input order is far from the best loop
+    fn shuffled_circle(count: usize) -> SingleVec {
+        let mut points: SingleVec = (0..count)
+            .map(|i| {
+                let angle = i as Precision / count as Precision * std::f64::consts::TAU;
+                [40. + angle.sin() * 0.01, -74. + angle.cos() * 0.01]
+            })
+            .collect();
+        let mut rng = SmallRng::seed_from_u64(1);
+        for i in (1..points.len()).rev() {
+            points.swap(i, rng.random_range(0..=i));
+        }
+        points
+    }
+
+    #[test]
+    fn keeps_every_point() {
+        let points = shuffled_circle(60);
+        let mut route = solve(&points, Duration::from_millis(200), Some(1));
+        assert_eq!(route.len(), points.len());
+
+        let mut points = points;
+        let key =
+            |a: &PointArray, b: &PointArray| a[0].total_cmp(&b[0]).then(a[1].total_cmp(&b[1]));
+        points.sort_by(key);
+        route.sort_by(key);
+        assert_eq!(route, points);
+    }
+
+    #[test]
+    fn finds_the_circle() {
+        let points = shuffled_circle(80);
+        let mut circle = points.clone();
+        circle.sort_by(|a, b| {
+            (a[0] - 40.)
+                .atan2(a[1] + 74.)
+                .total_cmp(&(b[0] - 40.).atan2(b[1] + 74.))
+        });
+        let route = solve(&points, Duration::from_millis(500), Some(1));
+        assert!(loop_distance(&route) < loop_distance(&points) / 2.);
+        assert!(loop_distance(&route) <= loop_distance(&circle) * 1.01);
+    }
+
+    #[test]
+    fn small_inputs_are_returned_as_they_are() {
+        let points = vec![[40., -74.], [40.01, -74.], [40., -74.01]];
+        assert_eq!(solve(&points, Duration::from_millis(10), None), points);
+        assert_eq!(
+            solve_indices(&points, Duration::from_millis(10), None),
+            [0, 1, 2]
+        );
+    }
+
+    #[test]
+    fn seeded_runs_are_identical() {
+        let mut rng = SmallRng::seed_from_u64(2);
+        let points: SingleVec = (0..200)
+            .map(|_| {
+                [
+                    40. + rng.random_range(0. ..0.05),
+                    -74. + rng.random_range(0. ..0.05),
+                ]
+            })
+            .collect();
+        let time = Instant::now();
+        let first = solve(&points, Duration::from_secs(60), Some(7));
+        // the kick cap ends the search long before the time limit
+        assert!(time.elapsed() < Duration::from_secs(30));
+        assert_eq!(solve(&points, Duration::from_secs(60), Some(7)), first);
+    }
+}
@@ -44,12 +44,17 @@ impl<'a> Cluster<'a> {
         self.all = points;
     }
 
+    /// Keeps the points that no other cluster of `tree` covers, within the radius of each point.
+    /// The radius of the clusters in `tree` must be at least the largest radius of the points
     pub fn set_unique(&mut self, tree: &RTree<Point>) {
         let mut points: Vec<_> = self
             .all
             .par_iter()
             .filter_map(|p| {
-                let points = tree.locate_all_at_point(&p.center).count();
+                let points = tree
+                    .locate_all_at_point(&p.center)
+                    .filter(|cluster| cluster.haversine_distance(&p.center) <= p.radius)
+                    .count();
                 if points == 1 {
                     Some(*p)
                 } else {
@@ -4,6 +4,8 @@ pub mod point;
 use model::api::{single_vec::SingleVec, Precision};
 use point::Point;
 
+use crate::{radii::Radii, weights::Weights};
+
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use rstar::RTree;
 
@@ -19,6 +21,32 @@ pub fn spawn(radius: Precision, points: &SingleVec) -> RTree<Point> {
     RTree::bulk_load(points)
 }
 
+/// Points use their weight from `weights` and their own radius from `radii` when they have one,
+/// `radius` otherwise
+pub fn spawn_with_radii(
+    radius: Precision,
+    points: &SingleVec,
+    weights: &Weights,
+    radii: &Radii,
+) -> RTree<Point> {
+    if !weights.is_weighted() && radii.is_empty() {
+        return spawn(radius, points);
+    }
+    let points = points
+        .iter()
+        .map(|p| Point {
+            weight: weights.get(p),
+            ..Point::new(radii.get(p, radius), 20, *p)
+        })
+        .collect::<Vec<_>>();
+    RTree::bulk_load(points)
+}
+
+/// Sum of the weights of the points
+pub fn weight_of(points: &[&Point]) -> Precision {
+    points.iter().map(|p| p.weight).sum()
+}
+
 pub fn cluster_info<'a>(
     point_tree: &'a RTree<Point>,
     clusters: &'a Vec<Point>,
@@ -17,6 +17,8 @@ pub struct Point {
     pub radius: Precision,
     pub center: [Precision; 2],
     pub cell_id: CellID,
+    /// How much covering the point is worth, `1` unless weights were provided
+    pub weight: Precision,
 }
 
 const EARTH_RADIUS: f64 = HaversineMeasure::GRS80_MEAN_RADIUS.radius();
@@ -27,6 +29,7 @@ impl Point {
             radius,
             center,
             cell_id: CellID::from(LatLng::from_degrees(center[0], center[1])).parent(cell_level),
+            weight: 1.,
         }
     }
 
@@ -5,13 +5,22 @@ use std::{
 };
 
 use geo::{Destination, Haversine, Intersects};
-use model::api::{point_array::PointArray, single_vec::SingleVec};
+use model::api::{point_array::PointArray, single_vec::SingleVec, split_level::SplitLevel};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use s2::{
     cell::Cell, cellid::CellID, cellunion::CellUnion, latlng::LatLng, rect::Rect,
     region::RegionCoverer,
 };
 use serde::Serialize;
+use sysinfo::{CpuRefreshKind, RefreshKind, System};
+
+/// Fewest points that are worth a thread of their own when the split level is picked automatically
+const MIN_SPLIT_POINTS: usize = 2_000;
+/// Most groups an automatically picked split level aims for, further capped by the number of cores
+const MAX_SPLIT_GROUPS: usize = 32;
+/// Most cells per group that an automatically picked split level may create
+const MAX_CELLS_PER_GROUP: usize = 4;
+const MAX_AUTO_SPLIT_LEVEL: u64 = 16;
 
 type Covered = Arc<Mutex<HashSet<u64>>>;
 
@@ -421,6 +430,14 @@ pub fn cell_coverage(lat: f64, lon: f64, size: u8, level: u8) -> Covered {
     covered
 }
 
+/// Level of the cells that the rtree points are in, data points are looked up by them
+pub const POINT_LEVEL: u64 = 20;
+
+/// Id of the [POINT_LEVEL] cell of the point
+pub fn point_cell(point: &PointArray) -> u64 {
+    from_array_to_cell_id(point, POINT_LEVEL).0
+}
+
 pub fn from_array_to_cell_id(point: &PointArray, parent_level: u64) -> CellID {
     CellID::from(LatLng::from_degrees(point[0], point[1])).parent(parent_level)
 }
@@ -430,6 +447,82 @@ pub fn from_cell_id_to_array(cell_id: CellID) -> PointArray {
     [center.latitude().deg(), center.longitude().deg()]
 }
 
+/// Picks the lowest level whose cells split `points` into groups of about [MIN_SPLIT_POINTS] each,
+/// at most one group per core, `0` when there are too few points for splitting to pay off.
+/// With a `seed` the cores are ignored, so that seeded runs pick the same level on every machine.
+/// Lower levels are preferred since every cell border is a seam between separately processed groups
+pub fn auto_split_level(points: &SingleVec, seed: Option<u64>) -> u64 {
+    let max_groups = match seed {
+        Some(_) => MAX_SPLIT_GROUPS,
+        None => cores().min(MAX_SPLIT_GROUPS),
+    };
+    let groups = (points.len() / MIN_SPLIT_POINTS).min(max_groups);
+    if groups <= 1 {
+        log::info!("[SPLIT_LEVEL] not splitting {} points", points.len());
+        return 0;
+    }
+    let target = points.len().div_ceil(groups);
+    let mut cells: Vec<u64> = points
+        .iter()
+        .map(|point| from_array_to_cell_id(point, 20).0)
+        .collect();
+    cells.sort_unstable();
+
+    let mut split_level = 0;
+    let mut cell_count = 1;
+    let mut largest = points.len();
+    for level in 1..=MAX_AUTO_SPLIT_LEVEL {
+        // children of a cell have consecutive ids, so each parent is a run of the sorted cells
+        let mut count = 0;
+        let mut max_run = 0;
+        let mut run = 0;
+        let mut previous = None;
+        for cell in cells.iter() {
+            let parent = CellID(*cell).parent(level).0;
+            if previous != Some(parent) {
+                count += 1;
+                run = 0;
+                previous = Some(parent);
+            }
+            run += 1;
+            max_run = max_run.max(run);
+        }
+        if count > groups * MAX_CELLS_PER_GROUP {
+            break;
+        }
+        split_level = level;
+        cell_count = count;
+        largest = max_run;
+        if largest <= target {
+            break;
+        }
+    }
+    log::info!(
+        "[SPLIT_LEVEL] picked level {} for {} points in {} groups | cells: {} | largest: {}",
+        split_level,
+        points.len(),
+        groups,
+        cell_count,
+        largest
+    );
+    split_level
+}
+
+fn cores() -> usize {
+    System::new_with_specifics(RefreshKind::nothing().with_cpu(CpuRefreshKind::nothing()))
+        .cpus()
+        .len()
+        .max(1)
+}
+
+/// Resolves `auto` with [auto_split_level]
+pub fn split_level(split_level: SplitLevel, points: &SingleVec, seed: Option<u64>) -> u64 {
+    match split_level {
+        SplitLevel::Auto => auto_split_level(points, seed),
+        SplitLevel::Level(level) => level,
+    }
+}
+
 pub fn create_cell_map(points: &SingleVec, split_level: u64) -> HashMap<u64, SingleVec> {
     let s20cells: Vec<CellID> = points
         .iter()
@@ -444,3 +537,55 @@ pub fn create_cell_map(points: &SingleVec, split_level: u64) -> HashMap<u64, Sin
     }
     cell_maps
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use model::api::Precision;
+
+    fn grid(size: usize, step: Precision) -> SingleVec {
+        (0..size * size)
+            .map(|i| {
+                [
+                    40. + (i / size) as Precision * step,
+                    -74. + (i % size) as Precision * step,
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn does_not_split_few_points() {
+        assert_eq!(auto_split_level(&grid(40, 0.001), None), 0);
+        assert_eq!(auto_split_level(&grid(40, 0.001), Some(1)), 0);
+    }
+
+    #[test]
+    fn splits_many_points() {
+        let points = grid(200, 0.001);
+        let level = auto_split_level(&points, Some(1));
+        assert!(level > 0 && level <= MAX_AUTO_SPLIT_LEVEL);
+        let cells = create_cell_map(&points, level).len();
+        assert!(
+            cells <= (points.len() / MIN_SPLIT_POINTS).min(MAX_SPLIT_GROUPS) * MAX_CELLS_PER_GROUP
+        );
+    }
+
+    #[test]
+    fn denser_points_get_a_higher_level() {
+        let sparse = auto_split_level(&grid(200, 0.01), Some(1));
+        let dense = auto_split_level(&grid(200, 0.0001), Some(1));
+        assert!(dense > sparse);
+    }
+
+    #[test]
+    fn fewer_cores_never_raise_the_level() {
+        let points = grid(200, 0.001);
+        assert!(auto_split_level(&points, None) <= auto_split_level(&points, Some(1)));
+        // a seed ignores the cores, so it always picks the same level
+        assert_eq!(
+            auto_split_level(&points, Some(1)),
+            auto_split_level(&points, Some(2))
+        );
+    }
+}
@@ -5,7 +5,7 @@ mod utils;
 
 use std::time::Instant;
 
-use model::api::{single_vec::SingleVec, Precision};
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
 use rayon::{
     iter::{IntoParallelRefIterator, ParallelIterator},
     slice::ParallelSliceMut,
@@ -86,3 +86,15 @@ pub fn with_data(radius: Precision, points: &SingleVec, clusters: &SingleVec) ->
     log::info!("centered clusters in {:.2}s", time.elapsed().as_secs_f32());
     final_clusters
 }
+
+/// Center of the smallest circle enclosing all of the points, if it fits within the radius
+pub fn enclose(points: &[PointArray], radius: Precision) -> Option<PointArray> {
+    match sec::multi_attempt(
+        points.iter().map(|p| geo::Point::new(p[1], p[0])),
+        radius,
+        20,
+    ) {
+        sec::SmallestEnclosingCircle::Centered(center) => Some([center.y(), center.x()]),
+        _ => None,
+    }
+}
@@ -1,7 +1,7 @@
 use super::{circle::Circle, state::State, *};
 
 use geo::Point;
-use rand::seq::SliceRandom;
+use rand::{SeedableRng, rngs::SmallRng, seq::SliceRandom};
 
 #[derive(Debug)]
 pub enum SmallestEnclosingCircle {
@@ -19,7 +19,8 @@ pub fn multi_attempt<I: Iterator<Item = Point>>(
     let points: Vec<_> = points.collect();
     let mut circle = Circle::None;
     let mut attempt = 0;
-    let mut rng = rand::rng();
+    // seeded so the same points always end up with the same circle
+    let mut rng = SmallRng::seed_from_u64(points.len() as u64);
 
     for i in 0..max_attempts {
         attempt = i;
@@ -1,11 +1,19 @@
 use std::{ops::AddAssign, time::Instant};
 
 use geo::{Distance, Haversine, Point};
+use geojson::Feature;
 use hashbrown::HashSet;
-use model::api::{Precision, single_vec::SingleVec};
+use model::api::{Precision, args::Travel, single_vec::SingleVec};
 use serde::{Serialize, ser::SerializeStruct};
 
-use crate::rtree::{self, cluster::Cluster, cluster_info, point};
+use crate::{
+    clustering::{anytime::ScorePoint, joint::JointStats, refine::RefineStats, seams::SeamStats},
+    plugin::PluginReport,
+    radii::Radii,
+    routing::incremental::RouteDiff,
+    rtree::{self, cluster::Cluster, cluster_info, point},
+    weights::Weights,
+};
 
 const WIDTH: &str = "=======================================================================";
 
@@ -13,7 +21,11 @@ const WIDTH: &str = "===========================================================
 pub struct Stats {
     stats_start_time: Option<Instant>,
     label: String,
-    min_points: usize,
+    pub min_points: usize,
+    /// Weight of each data point, used by the clustering algorithms and the weighted stats
+    pub weights: Weights,
+    /// Radius of the data points that don't use the radius of the request
+    pub radii: Radii,
 
     pub best_clusters: SingleVec,
     pub best_cluster_point_count: usize,
@@ -28,6 +40,45 @@ pub struct Stats {
     pub total_distance: Precision,
     pub longest_distance: Precision,
     pub mygod_score: usize,
+    pub total_weight: Precision,
+    pub weight_covered: Precision,
+    /// [Stats::get_score] with `min_points` as a weight threshold and uncovered points counted by weight
+    pub mygod_score_weighted: Precision,
+    pub spawns_live: usize,
+    pub spawns_missed: usize,
+    pub filler_points: SingleVec,
+    /// Seconds needed for each leg of the route
+    pub leg_times: Vec<Precision>,
+    /// Seconds needed to run the whole route once
+    pub total_duration: Precision,
+    /// Longest single leg, in seconds
+    pub longest_wait: Precision,
+    /// Estimated seconds between two visits of each point of the route
+    pub revisit_intervals: Vec<Precision>,
+    /// Changes made by the refinement pass, when it ran
+    pub refine: Option<RefineStats>,
+    /// Changes made to an existing route by the route update
+    pub route_diff: Option<RouteDiff>,
+    /// MultiPoint of the data points the route doesn't cover, only set by the route stats
+    pub uncovered: Option<Feature>,
+    /// Fraction of the weight, or of the points when unweighted, covered after each cluster of a `max_clusters` budget
+    pub coverage_curve: Vec<Precision>,
+    /// Best score after each round of the time budgeted clustering
+    pub score_timeline: Vec<ScorePoint>,
+    /// Border clusters shared by the child geofences, when they were clustered jointly
+    pub joint: Option<JointStats>,
+    /// Clusters along the borders of the `cluster_split_level` cells, when the data points were split
+    pub seams: Option<SeamStats>,
+    /// Level picked for `cluster_split_level: auto`
+    pub cluster_split_level: Option<u64>,
+    /// Level picked for `route_split_level: auto`
+    pub route_split_level: Option<u64>,
+    /// Stats and warnings returned by plugins that use the JSON protocol
+    pub plugins: Vec<PluginReport>,
+    /// Error of each plugin run that failed, including every split level cell that failed in
+    /// `Plugin::run_multi`, the request itself then fails
+    pub plugin_errors: Vec<String>,
+    pub routes: Vec<Stats>,
 }
 
 impl Stats {
@@ -46,9 +97,33 @@ impl Stats {
             total_distance: 0.,
             longest_distance: 0.,
             mygod_score: 0,
+            total_weight: 0.,
+            weight_covered: 0.,
+            mygod_score_weighted: 0.,
+            spawns_live: 0,
+            spawns_missed: 0,
+            filler_points: vec![],
+            leg_times: vec![],
+            total_duration: 0.,
+            longest_wait: 0.,
+            revisit_intervals: vec![],
+            refine: None,
+            route_diff: None,
+            uncovered: None,
+            coverage_curve: vec![],
+            score_timeline: vec![],
+            joint: None,
+            seams: None,
+            cluster_split_level: None,
+            route_split_level: None,
+            plugins: vec![],
+            plugin_errors: vec![],
+            routes: vec![],
             stats_start_time: None,
             label,
             min_points,
+            weights: Weights::default(),
+            radii: Radii::default(),
         }
     }
 
@@ -56,9 +131,17 @@ impl Stats {
         self.total_clusters * self.min_points + (self.total_points - self.points_covered)
     }
 
+    pub fn get_weighted_score(&self) -> Precision {
+        self.total_clusters as Precision * self.min_points as Precision
+            + (self.total_weight - self.weight_covered).max(0.)
+    }
+
     pub fn set_score(&mut self) {
         self.start_timer();
         self.mygod_score = self.get_score();
+        if self.weights.is_weighted() {
+            self.mygod_score_weighted = self.get_weighted_score();
+        }
         self.stop_timer();
     }
 
@@ -77,8 +160,9 @@ impl Stats {
                 if replace { "||" } else { "==" }
             )
         };
+        let area_name = area.clone().unwrap_or_default();
         log::info!(
-            "\n{}{}{}{}{}{}{}{}  {}==\n",
+            "\n{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}{}  {}==\n",
             get_row("[STATS] ".to_string(), false),
             if let Some(area) = area {
                 if area.is_empty() {
@@ -138,17 +222,146 @@ impl Stats {
                 ),
                 true
             ),
+            if let Some(refine) = &self.refine {
+                get_row(
+                    format!(
+                        "|| [REFINE] Removed: {} | Swapped: {} | Moved: {} | Covered: +{}",
+                        refine.removed,
+                        refine.swapped,
+                        refine.recentered,
+                        refine.covered_after - refine.covered_before,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if let Some(joint) = &self.joint {
+                get_row(
+                    format!(
+                        "|| [JOINT] Geofences: {} | Border Clusters: {} | Independent Clusters: {} | Saved: {}",
+                        joint.children,
+                        joint.border_clusters,
+                        joint.independent_clusters,
+                        joint.clusters_saved,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if let Some(seams) = &self.seams {
+                get_row(
+                    format!(
+                        "|| [SEAMS] Clusters: {} | Removed: {} | Recentered: {}",
+                        seams.seam_clusters, seams.removed, seams.recentered,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.cluster_split_level.is_some() || self.route_split_level.is_some() {
+                get_row(
+                    format!(
+                        "|| [SPLIT_LEVEL] Clustering: {} | Routing: {}",
+                        self.cluster_split_level
+                            .map_or("-".to_string(), |level| format!("auto ({})", level)),
+                        self.route_split_level
+                            .map_or("-".to_string(), |level| format!("auto ({})", level)),
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
+            if self.plugins.is_empty() {
+                "".to_string()
+            } else {
+                get_row(
+                    format!(
+                        "|| [PLUGINS] Reports: {} | Warnings: {}",
+                        self.plugins.len(),
+                        self.plugins
+                            .iter()
+                            .map(|report| report.warnings.len())
+                            .sum::<usize>(),
+                    ),
+                    true,
+                )
+            },
+            if self.plugin_errors.is_empty() {
+                "".to_string()
+            } else {
+                get_row(
+                    format!("|| [PLUGINS] Errors: {}", self.plugin_errors.len()),
+                    true,
+                )
+            },
+            if self.filler_points.is_empty() {
+                "".to_string()
+            } else {
+                get_row(
+                    format!("|| [HOPS] Filler Points: {}", self.filler_points.len()),
+                    true,
+                )
+            },
+            if self.leg_times.is_empty() {
+                "".to_string()
+            } else {
+                get_row(
+                    format!(
+                        "|| [TRAVEL] Duration: {:.0}s | Longest Wait: {:.0}s | Max Revisit: {:.0}s",
+                        self.total_duration,
+                        self.longest_wait,
+                        self.revisit_intervals
+                            .iter()
+                            .copied()
+                            .fold(0., Precision::max),
+                    ),
+                    true,
+                )
+            },
+            if self.spawns_live + self.spawns_missed > 0 {
+                get_row(
+                    format!(
+                        "|| [SPAWNS] Live: {} | Missed: {}",
+                        self.spawns_live, self.spawns_missed,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
             get_row(format!("|| [MYGOD_SCORE] {}", self.mygod_score,), true),
+            if self.weights.is_weighted() {
+                get_row(
+                    format!(
+                        "|| [WEIGHTED] Total: {:.1} | Covered: {:.1} | Score: {:.1}",
+                        self.total_weight, self.weight_covered, self.mygod_score_weighted,
+                    ),
+                    true,
+                )
+            } else {
+                "".to_string()
+            },
             WIDTH,
-        )
+        );
+        for (i, route) in self.routes.iter().enumerate() {
+            route.log(Some(format!("{}-{}", area_name, i + 1)));
+        }
     }
 
-    pub fn distance_stats(&mut self, clusters: &SingleVec) {
+    /// Open paths skip the leg from the last point back to the first
+    pub fn distance_stats(&mut self, clusters: &SingleVec, open_path: bool) {
         self.start_timer();
         log::info!("generating distance stats for {} points", clusters.len());
         self.total_distance = 0.;
         self.longest_distance = 0.;
         for (i, point) in clusters.iter().enumerate() {
+            if open_path && i == clusters.len() - 1 {
+                break;
+            }
             let point = Point::new(point[1], point[0]);
             let point2 = if i == clusters.len() - 1 {
                 Point::new(clusters[0][1], clusters[0][0])
@@ -168,6 +381,77 @@ impl Stats {
         self.stop_timer();
     }
 
+    /// Travel time of every leg, the total duration and how long it takes for the device to return
+    /// to each point. Open paths are assumed to be run back and forth
+    pub fn time_stats(&mut self, clusters: &SingleVec, travel: &Travel, open_path: bool) {
+        self.start_timer();
+        self.leg_times.clear();
+        self.revisit_intervals.clear();
+        let len = clusters.len();
+        let legs = if open_path {
+            len.saturating_sub(1)
+        } else {
+            len
+        };
+        for i in 0..legs {
+            let point = Point::new(clusters[i][1], clusters[i][0]);
+            let next = &clusters[(i + 1) % len];
+            let distance = Haversine.distance(point, Point::new(next[1], next[0]));
+            self.leg_times.push(travel.leg_time(distance));
+        }
+        self.total_duration = self.leg_times.iter().sum();
+        self.longest_wait = self.leg_times.iter().copied().fold(0., Precision::max);
+
+        let mut arrival = 0.;
+        for i in 0..len {
+            if i > 0 {
+                arrival += self.leg_times[i - 1];
+            }
+            self.revisit_intervals.push(if open_path {
+                (2. * arrival).max(2. * (self.total_duration - arrival))
+            } else {
+                self.total_duration
+            });
+        }
+        self.stop_timer();
+    }
+
+    /// Clears the routing stats before the stats of separate routes are added with [Stats::add_route]
+    pub fn reset_route_stats(&mut self) {
+        self.total_distance = 0.;
+        self.longest_distance = 0.;
+        self.spawns_live = 0;
+        self.spawns_missed = 0;
+        self.filler_points.clear();
+        self.leg_times.clear();
+        self.total_duration = 0.;
+        self.longest_wait = 0.;
+        self.revisit_intervals.clear();
+        self.route_split_level = None;
+    }
+
+    /// Adds the routing stats of a route that runs alongside the others and keeps it in `routes`
+    pub fn add_route(&mut self, route_stats: Stats) {
+        self.total_distance += route_stats.total_distance;
+        self.longest_distance = self.longest_distance.max(route_stats.longest_distance);
+        self.spawns_live += route_stats.spawns_live;
+        self.spawns_missed += route_stats.spawns_missed;
+        self.filler_points
+            .extend(route_stats.filler_points.iter().copied());
+        // routes run in parallel, so the slowest one decides the duration
+        self.total_duration = self.total_duration.max(route_stats.total_duration);
+        self.longest_wait = self.longest_wait.max(route_stats.longest_wait);
+        self.leg_times.extend(route_stats.leg_times.iter().copied());
+        self.revisit_intervals
+            .extend(route_stats.revisit_intervals.iter().copied());
+        // the finest level that any of the routes was split with
+        self.route_split_level = self.route_split_level.max(route_stats.route_split_level);
+        self.plugins.extend(route_stats.plugins.iter().cloned());
+        self.plugin_errors
+            .extend(route_stats.plugin_errors.iter().cloned());
+        self.routes.push(route_stats);
+    }
+
     pub fn set_cluster_time(&mut self, time: Instant) {
         self.cluster_time = time.elapsed().as_secs_f64();
         log::debug!("Cluster Time: {}s", self.cluster_time as Precision);
@@ -198,7 +482,7 @@ impl Stats {
 
         if points.is_empty() {
         } else {
-            let tree = rtree::spawn(radius, points);
+            let tree = rtree::spawn_with_radii(radius, points, &self.weights, &self.radii);
             let clusters: Vec<point::Point> = clusters
                 .into_iter()
                 .map(|c| point::Point::new(radius, 20, *c))
@@ -243,6 +527,10 @@ impl Stats {
             self.worst_cluster_count = worst_count;
             self.best_clusters = best_clusters;
             self.points_covered = points_covered.len();
+            if self.weights.is_weighted() {
+                self.total_weight = self.weights.total(points);
+                self.weight_covered = points_covered.iter().map(|p| p.weight).sum();
+            }
 
             if self.points_covered > self.total_points {
                 log::warn!(
@@ -265,7 +553,7 @@ impl Serialize for Stats {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Stats", 11)?;
+        let mut state = serializer.serialize_struct("Stats", 32)?;
         state.serialize_field("best_clusters", &self.best_clusters)?;
         state.serialize_field("best_cluster_point_count", &self.best_cluster_point_count)?;
         state.serialize_field("worst_cluster_point_count", &self.worst_cluster_point_count)?;
@@ -278,6 +566,98 @@ impl Serialize for Stats {
         state.serialize_field("total_distance", &self.total_distance)?;
         state.serialize_field("longest_distance", &self.longest_distance)?;
         state.serialize_field("mygod_score", &self.mygod_score)?;
+        if self.weights.is_weighted() {
+            state.serialize_field("total_weight", &self.total_weight)?;
+            state.serialize_field("weight_covered", &self.weight_covered)?;
+            state.serialize_field("mygod_score_weighted", &self.mygod_score_weighted)?;
+        } else {
+            state.skip_field("total_weight")?;
+            state.skip_field("weight_covered")?;
+            state.skip_field("mygod_score_weighted")?;
+        }
+        if self.spawns_live + self.spawns_missed == 0 {
+            state.skip_field("spawns_live")?;
+            state.skip_field("spawns_missed")?;
+        } else {
+            state.serialize_field("spawns_live", &self.spawns_live)?;
+            state.serialize_field("spawns_missed", &self.spawns_missed)?;
+        }
+        if self.filler_points.is_empty() {
+            state.skip_field("filler_points")?;
+        } else {
+            state.serialize_field("filler_points", &self.filler_points)?;
+        }
+        if self.leg_times.is_empty() {
+            state.skip_field("leg_times")?;
+            state.skip_field("total_duration")?;
+            state.skip_field("longest_wait")?;
+            state.skip_field("revisit_intervals")?;
+        } else {
+            state.serialize_field("leg_times", &self.leg_times)?;
+            state.serialize_field("total_duration", &self.total_duration)?;
+            state.serialize_field("longest_wait", &self.longest_wait)?;
+            state.serialize_field("revisit_intervals", &self.revisit_intervals)?;
+        }
+        if let Some(refine) = &self.refine {
+            state.serialize_field("refine", refine)?;
+        } else {
+            state.skip_field("refine")?;
+        }
+        if let Some(route_diff) = &self.route_diff {
+            state.serialize_field("route_diff", route_diff)?;
+        } else {
+            state.skip_field("route_diff")?;
+        }
+        if let Some(uncovered) = &self.uncovered {
+            state.serialize_field("uncovered", uncovered)?;
+        } else {
+            state.skip_field("uncovered")?;
+        }
+        if self.coverage_curve.is_empty() {
+            state.skip_field("coverage_curve")?;
+        } else {
+            state.serialize_field("coverage_curve", &self.coverage_curve)?;
+        }
+        if self.score_timeline.is_empty() {
+            state.skip_field("score_timeline")?;
+        } else {
+            state.serialize_field("score_timeline", &self.score_timeline)?;
+        }
+        if let Some(joint) = &self.joint {
+            state.serialize_field("joint", joint)?;
+        } else {
+            state.skip_field("joint")?;
+        }
+        if let Some(seams) = &self.seams {
+            state.serialize_field("seams", seams)?;
+        } else {
+            state.skip_field("seams")?;
+        }
+        if let Some(cluster_split_level) = self.cluster_split_level {
+            state.serialize_field("cluster_split_level", &cluster_split_level)?;
+        } else {
+            state.skip_field("cluster_split_level")?;
+        }
+        if let Some(route_split_level) = self.route_split_level {
+            state.serialize_field("route_split_level", &route_split_level)?;
+        } else {
+            state.skip_field("route_split_level")?;
+        }
+        if self.plugins.is_empty() {
+            state.skip_field("plugins")?;
+        } else {
+            state.serialize_field("plugins", &self.plugins)?;
+        }
+        if self.plugin_errors.is_empty() {
+            state.skip_field("plugin_errors")?;
+        } else {
+            state.serialize_field("plugin_errors", &self.plugin_errors)?;
+        }
+        if self.routes.is_empty() {
+            state.skip_field("routes")?;
+        } else {
+            state.serialize_field("routes", &self.routes)?;
+        }
         state.end()
     }
 }
@@ -298,6 +678,65 @@ impl<'a> AddAssign<&'a Self> for Stats {
         self.total_clusters += rhs.total_clusters;
         self.total_distance += rhs.total_distance;
         self.longest_distance += rhs.longest_distance;
+        self.spawns_live += rhs.spawns_live;
+        self.spawns_missed += rhs.spawns_missed;
+        self.total_weight += rhs.total_weight;
+        self.weight_covered += rhs.weight_covered;
+        self.weights.extend(&rhs.weights);
+        self.radii.extend(&rhs.radii);
+        match (&mut self.refine, &rhs.refine) {
+            (Some(refine), Some(rhs_refine)) => *refine += rhs_refine,
+            (None, Some(rhs_refine)) => self.refine = Some(rhs_refine.clone()),
+            _ => {}
+        }
+        match (&mut self.joint, &rhs.joint) {
+            (Some(joint), Some(rhs_joint)) => *joint += rhs_joint,
+            (None, Some(rhs_joint)) => self.joint = Some(rhs_joint.clone()),
+            _ => {}
+        }
+        match (&mut self.seams, &rhs.seams) {
+            (Some(seams), Some(rhs_seams)) => *seams += rhs_seams,
+            (None, Some(rhs_seams)) => self.seams = Some(rhs_seams.clone()),
+            _ => {}
+        }
+        self.cluster_split_level = self.cluster_split_level.or(rhs.cluster_split_level);
+        self.route_split_level = self.route_split_level.or(rhs.route_split_level);
+        self.plugins.extend(rhs.plugins.iter().cloned());
+        self.plugin_errors.extend(rhs.plugin_errors.iter().cloned());
+        self.filler_points.extend(rhs.filler_points.iter().copied());
+        self.leg_times.extend(rhs.leg_times.iter().copied());
+        // each area has its own device, so the slowest route decides the duration
+        self.total_duration = self.total_duration.max(rhs.total_duration);
+        self.longest_wait = self.longest_wait.max(rhs.longest_wait);
+        self.revisit_intervals
+            .extend(rhs.revisit_intervals.iter().copied());
+        self.routes.extend(rhs.routes.clone());
         self.set_score();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowest_route_decides_the_duration() {
+        let travel = Travel::Speed(10.);
+        let short: SingleVec = vec![[40., -74.], [40., -73.999]];
+        let long: SingleVec = vec![[40., -74.], [40., -73.99]];
+
+        let mut stats = Stats::new("Short".to_string(), 1);
+        stats.time_stats(&short, &travel, false);
+        let mut long_stats = Stats::new("Long".to_string(), 1);
+        long_stats.time_stats(&long, &travel, false);
+        let long_duration = long_stats.total_duration;
+        assert!(stats.total_duration < long_duration);
+
+        stats += &long_stats;
+        assert_eq!(stats.total_duration, long_duration);
+
+        let mut routes = Stats::new("Routes".to_string(), 1);
+        routes.add_route(long_stats);
+        assert_eq!(routes.total_duration, long_duration);
+    }
+}
@@ -6,11 +6,13 @@ use std::io::Write;
 use std::path::Path;
 
 use colored::Colorize;
-use geo::Coord;
+use geo::{Coord, MultiPolygon, Polygon};
 use geohash::encode;
+use geojson::{Geometry, Value};
 use hashbrown::HashSet;
-use model::api::{point_array::PointArray, single_vec::SingleVec};
+use model::api::{point_array::PointArray, single_vec::SingleVec, Precision};
 
+use crate::plugin::MANIFEST_SUFFIX;
 use crate::rtree::cluster::Cluster;
 use crate::stats::Stats;
 
@@ -77,6 +79,19 @@ pub fn centroid(coords: &SingleVec) -> PointArray {
     [lat.to_degrees(), lon.to_degrees()]
 }
 
+/// Polygons of a Polygon or MultiPolygon geometry, other geometries have none
+pub fn polygons(geometry: &Geometry) -> Vec<Polygon<Precision>> {
+    match geometry.value {
+        Value::Polygon(_) => Polygon::<Precision>::try_from(geometry)
+            .map(|polygon| vec![polygon])
+            .unwrap_or_default(),
+        Value::MultiPolygon(_) => MultiPolygon::<Precision>::try_from(geometry)
+            .map(|multi_polygon| multi_polygon.0)
+            .unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
 pub fn info_log(file_name: &str, message: String) -> String {
     format!(
         "\r{}{}Z {}  {}{} {}",
@@ -143,15 +158,13 @@ pub fn rotate_to_best(clusters: SingleVec, stats: &Stats) -> SingleVec {
     final_clusters.into()
 }
 
-pub fn get_plugin_list(path: &str) -> std::io::Result<Vec<String>> {
-    let path = Path::new(path);
-
+pub fn get_plugin_list(path: &Path) -> std::io::Result<Vec<String>> {
     fs::read_dir(path)?
         .map(|res| res.map(|e| e.path().display().to_string()))
         .filter_map(|path| {
             if let Ok(ext) = path {
                 let plugin = ext.split("/").last().unwrap_or("").to_string();
-                if plugin == ".gitkeep" {
+                if plugin == ".gitkeep" || plugin.ends_with(MANIFEST_SUFFIX) {
                     None
                 } else {
                     Some(Ok(plugin))
@@ -0,0 +1,76 @@
+use hashbrown::HashSet;
+use model::api::{Precision, point_array::PointArray, single_vec::SingleVec};
+
+use crate::coord_map::{CoordMap, key};
+
+/// Weight of each data point, looked up by its exact coordinates.
+/// Points without a weight, or every point when there are no weights at all, weigh `1`
+#[derive(Debug, Clone, Default)]
+pub struct Weights(CoordMap<Precision>);
+
+impl Weights {
+    /// `weights` are matched to `points` by index, missing weights default to `1`.
+    /// When several points share their coordinates, the heaviest one is kept
+    pub fn new(points: &SingleVec, weights: &[Precision]) -> Self {
+        let mut map = CoordMap::default();
+        for (point, weight) in points.iter().zip(weights.iter()) {
+            map.merge(point, weight.max(0.), |existing, weight| {
+                *existing = existing.max(weight)
+            });
+        }
+        Self(map)
+    }
+
+    pub fn is_weighted(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    pub fn get(&self, point: &PointArray) -> Precision {
+        if self.0.is_empty() {
+            1.
+        } else {
+            self.0.get(point).copied().unwrap_or(1.)
+        }
+    }
+
+    pub fn extend(&mut self, other: &Weights) {
+        self.0.extend(&other.0);
+    }
+
+    /// Points that share their coordinates share one weight, so it is only counted once
+    pub fn total(&self, points: &SingleVec) -> Precision {
+        if self.0.is_empty() {
+            points.len() as Precision
+        } else {
+            let mut seen = HashSet::new();
+            points
+                .iter()
+                .filter(|point| seen.insert(key(point)))
+                .map(|point| self.get(point))
+                .sum()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_a_weight_of_one() {
+        let points = vec![[40., -74.], [40.01, -74.], [40.02, -74.]];
+        let weights = Weights::new(&points, &[5., 2.]);
+        assert_eq!(weights.get(&points[0]), 5.);
+        assert_eq!(weights.get(&points[1]), 2.);
+        assert_eq!(weights.get(&points[2]), 1.);
+        assert_eq!(weights.total(&points), 8.);
+    }
+
+    #[test]
+    fn counts_the_weight_of_the_same_coordinates_once() {
+        let points = vec![[40., -74.], [40., -74.], [40.01, -74.]];
+        let weights = Weights::new(&points, &[5., 3., 2.]);
+        assert_eq!(weights.get(&points[1]), 5.);
+        assert_eq!(weights.total(&points), 7.);
+    }
+}
@@ -126,6 +126,7 @@ pub async fn start() -> io::Result<()> {
                                 .service(public::v1::calculate::route_stats)
                                 .service(public::v1::calculate::route_stats_category)
                                 .service(public::v1::calculate::reroute)
+                                .service(public::v1::calculate::route_update)
                                 .service(public::v1::calculate::calculate_area)
                                 .service(public::v1::calculate::cluster),
                         )
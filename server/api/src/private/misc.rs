@@ -8,7 +8,7 @@ use crate::{
 use actix_session::Session;
 use actix_web::http::header;
 
-use algorithms::{bootstrap, clustering, routing};
+use algorithms::{bootstrap, clustering, plugin::Manifests, routing};
 use geojson::Value;
 use model::{api::args::Auth, KojiDb};
 use serde_json::json;
@@ -29,6 +29,7 @@ async fn config(conn: web::Data<KojiDb>, session: Session) -> Result<HttpRespons
     let route_plugins = routing::routing_plugins();
     let clustering_plugins = clustering::clustering_plugins();
     let bootstrap_plugins = bootstrap::bootstrap_plugins();
+    let plugin_manifests = Manifests::load();
 
     Ok(HttpResponse::Ok().json(ConfigResponse {
         start_lat,
@@ -44,6 +45,7 @@ async fn config(conn: web::Data<KojiDb>, session: Session) -> Result<HttpRespons
         route_plugins,
         clustering_plugins,
         bootstrap_plugins,
+        plugin_manifests,
     }))
 }
 
@@ -1,14 +1,28 @@
+use std::time::Instant;
+
 use crate::utils::{request, response::Response};
 
 use super::*;
 
-use algorithms::{self, clustering, routing, stats::Stats};
+use algorithms::{
+    self,
+    bootstrap::radius::Corridor,
+    clustering::{self, joint, refine::RefineOptions, ClusterOptions},
+    exclusions::Exclusions,
+    ids::PointIds,
+    radii::Radii,
+    routing::{self, incremental, RouteOptions},
+    stats::Stats,
+    weights::Weights,
+};
 use geo::{ChamberlainDuquetteArea, MultiPolygon, Polygon};
 
 use geojson::Value;
 use model::{
     api::{
-        args::{Args, ArgsUnwrapped},
+        args::{ApiQueryArgs, Args, ArgsUnwrapped},
+        calc_mode::CalculationMode,
+        cluster_mode::ClusterMode,
         sort_by::SortBy,
         FeatureHelpers, GeoFormats, ToCollection, ToFeature, ToSingleVec,
     },
@@ -22,6 +36,8 @@ async fn bootstrap(
     conn: web::Data<KojiDb>,
     payload: web::Json<Args>,
 ) -> Result<HttpResponse, Error> {
+    let args = payload.into_inner().init(Some("bootstrap"));
+    let (plugin, _cancel) = utils::plugin_options(&args);
     let ArgsUnwrapped {
         area,
         benchmark_mode,
@@ -37,36 +53,81 @@ async fn bootstrap(
         sort_by,
         route_split_level,
         routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
         bootstrapping_args,
+        exclude_area,
+        lattice_trials,
+        corridor_overlap,
+        corridor_width,
+        seed,
         ..
-    } = payload.into_inner().init(Some("bootstrap"));
+    } = args;
 
     if area.features.is_empty() && instance.is_empty() && parent.is_none() {
         return Ok(
             HttpResponse::BadRequest().json(Response::send_error("no_area_and_empty_instance"))
         );
     }
+    if seed_with_time_limit(seed, &[routing_time]) {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("seed_with_time_limit")));
+    }
 
     let area = utils::create_or_find_collection(&instance, &conn, area, &parent, &vec![])
         .await
         .map_err(actix_web::error::ErrorInternalServerError)?;
 
-    let mut stats = Stats::new(format!("Bootstrap | {:?}", calculation_mode), 1);
-
-    let mut features: Vec<Feature> = algorithms::bootstrap::main(
-        area,
-        calculation_mode,
-        radius,
+    let route_options = RouteOptions {
         sort_by,
-        s2_level,
-        s2_size,
         route_split_level,
-        &mut stats,
-        &routing_args,
-        &bootstrapping_args,
-    );
+        routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
+        timed_spawns: vec![],
+        seed,
+        plugin: plugin.clone(),
+    };
+    let (features, stats) = web::block(move || {
+        let mut stats = Stats::new(format!("Bootstrap | {:?}", calculation_mode), 1);
+        let features = algorithms::bootstrap::main(
+            area,
+            calculation_mode,
+            radius,
+            lattice_trials,
+            Corridor {
+                overlap: corridor_overlap,
+                width: corridor_width,
+            },
+            s2_level,
+            s2_size,
+            &mut stats,
+            &route_options,
+            &Exclusions::new(&exclude_area, false),
+            &bootstrapping_args,
+            &plugin,
+        );
+        (features, stats)
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut features = match features {
+        Ok(features) => features,
+        Err(err) => return utils::response::calculation_error(err, stats, Some(instance)),
+    };
 
-    if parent.is_some() {
+    if parent.is_some() && devices == 1 {
         let mut condensed = vec![];
         features
             .into_iter()
@@ -152,6 +213,8 @@ async fn cluster(
 ) -> Result<HttpResponse, Error> {
     let (mode, category) = url.into_inner();
 
+    let args = payload.into_inner().init(Some(&mode));
+    let (plugin, _cancel) = utils::plugin_options(&args);
     let ArgsUnwrapped {
         area,
         benchmark_mode,
@@ -161,6 +224,8 @@ async fn cluster(
         instance,
         min_points,
         radius,
+        radius_gym,
+        radius_pokestop,
         return_type,
         save_to_db,
         save_to_scanner,
@@ -169,6 +234,14 @@ async fn cluster(
         tth,
         route_split_level,
         routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
         calculation_mode,
         s2_level,
         s2_size,
@@ -176,8 +249,20 @@ async fn cluster(
         max_clusters,
         clustering_args,
         center_clusters,
+        cluster_features,
+        weights,
+        weighted,
+        refine,
+        refine_iterations,
+        refine_time,
+        time_limit_ms,
+        lattice_trials,
+        seed,
+        exclude_area,
+        exclude_data_points,
+        joint_clustering,
         ..
-    } = payload.into_inner().init(Some(&mode));
+    } = args;
 
     if area.features.is_empty() && instance.is_empty() && data_points.is_empty() && parent.is_none()
     {
@@ -185,8 +270,24 @@ async fn cluster(
             HttpResponse::BadRequest().json(Response::send_error("no_area_instance_data_points"))
         );
     }
+    if seed_with_time_limit(seed, &[time_limit_ms, refine_time, routing_time]) {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("seed_with_time_limit")));
+    }
+    if time_limit_ms > 0
+        && (matches!(calculation_mode, CalculationMode::S2)
+            || matches!(cluster_mode, ClusterMode::Fastest | ClusterMode::Custom(_)))
+    {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error(
+            "time_limit_ms_not_supported_by_cluster_mode",
+        )));
+    }
+    // the OR-Tools plugin is only installed by its script, the native solver works anywhere
     let sort_by = if mode.eq("route") && sort_by == SortBy::Unset {
-        SortBy::Custom(String::from("tsp"))
+        if algorithms::plugin::Folder::Routing.contains("tsp") {
+            SortBy::Custom(String::from("tsp"))
+        } else {
+            SortBy::NativeTsp
+        }
     } else {
         sort_by
     };
@@ -215,14 +316,32 @@ async fn cluster(
         .await
         .map_err(actix_web::error::ErrorInternalServerError)?;
 
-    let data_points = if data_points.is_empty() {
-        utils::points_from_area(&area, &category, &conn, last_seen, tth)
-            .await
-            .map_err(actix_web::error::ErrorInternalServerError)?
-            .to_single_vec()
+    let (data_points, timed_spawns, weights, ids, radii) = if data_points.is_empty() {
+        let (points, radii) = utils::points_and_radii_from_area(
+            &area,
+            &category,
+            &conn,
+            last_seen,
+            tth,
+            radius_gym,
+            radius_pokestop,
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        let timed_spawns = utils::timed_spawns(&points);
+        let weights = if weighted {
+            utils::point_weights(&points)
+        } else {
+            vec![]
+        };
+        let ids: Vec<String> = points.iter().map(|point| point.i.clone()).collect();
+        (points.to_single_vec(), timed_spawns, weights, ids, radii)
     } else {
-        data_points
+        (data_points, vec![], weights, vec![], vec![])
     };
+    let weights = Weights::new(&data_points, &weights);
+    let radii = Radii::new(&data_points, &radii);
+    let point_ids = PointIds::new(&data_points, &ids);
 
     log::debug!(
         "[{}] Found Data Points: {}",
@@ -230,45 +349,140 @@ async fn cluster(
         data_points.len()
     );
 
-    let clusters = clustering::main(
-        &data_points,
+    let instance = if let Some(parent) = parent {
+        let model = geofence::Query::get_one(&conn.koji, parent.to_string())
+            .await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+        model.name
+    } else {
+        instance
+    };
+    let fences = if joint_clustering && area.features.len() > 1 {
+        Some(area.clone())
+    } else {
+        None
+    };
+    let cluster_options = ClusterOptions {
         cluster_mode,
-        radius,
         min_points,
-        &mut stats,
+        weights,
+        radii: radii.clone(),
+        ids: point_ids,
         cluster_split_level,
         max_clusters,
         calculation_mode,
         s2_level,
         s2_size,
-        area,
-        &clustering_args,
+        clustering_args,
         center_clusters,
-    );
-    let clusters = routing::main(
-        &data_points,
-        clusters,
-        &sort_by,
+        refine: refine.then(|| RefineOptions {
+            iterations: refine_iterations,
+            time_limit: refine_time,
+            ..Default::default()
+        }),
+        time_limit_ms,
+        lattice_trials,
+        exclusions: Exclusions::new(&exclude_area, exclude_data_points),
+        plugin: plugin.clone(),
+        seed,
+    };
+    let route_options = RouteOptions {
+        sort_by,
         route_split_level,
-        radius,
-        &mut stats,
-        &routing_args,
-    );
-
-    let mut feature = clusters
-        .to_feature(Some(enum_type.clone()))
-        .remove_last_coord();
+        routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
+        timed_spawns,
+        seed,
+        plugin: plugin.clone(),
+    };
+    let (features, stats, data_points) = web::block({
+        let instance = instance.clone();
+        let enum_type = enum_type.clone();
+        move || {
+            let clusters =
+                match clustering::main(&data_points, radius, area, &mut stats, &cluster_options) {
+                    Ok(clusters) => clusters,
+                    Err(err) => return (Err(err), stats, data_points),
+                };
+            let features = if let Some(fences) = fences {
+                let route_time = Instant::now();
+                let (children, mut joint_stats) = joint::split(
+                    &fences,
+                    &data_points,
+                    clusters,
+                    radius,
+                    &cluster_options.weights,
+                    &cluster_options.radii,
+                );
+                let independent = joint::cluster_independently(
+                    &fences,
+                    &children,
+                    radius,
+                    &cluster_options,
+                    &mut joint_stats,
+                    &mut stats.plugin_errors,
+                );
+                stats.joint = Some(joint_stats);
+                if let Err(err) = independent {
+                    return (Err(err), stats, data_points);
+                }
+                stats.reset_route_stats();
 
-    let instance = if let Some(parent) = parent {
-        let model = geofence::Query::get_one(&conn.koji, parent.to_string())
-            .await
-            .map_err(actix_web::error::ErrorInternalServerError)?;
-        model.name
-    } else {
-        instance
+                let mut features = vec![];
+                for child in children {
+                    let name = fences.features[child.index]
+                        .property("__name")
+                        .and_then(|name| name.as_str())
+                        .map(|name| name.to_string())
+                        .unwrap_or_else(|| format!("{}-{}", instance, child.index + 1));
+                    let mut child_stats = Stats::new(name.clone(), min_points);
+                    child_stats.weights = cluster_options.weights.clone();
+                    child_stats.radii = cluster_options.radii.clone();
+                    child_stats.cluster_stats(radius, &child.data_points, &child.clusters);
+                    child_stats.set_score();
+                    let routes = routing::multi(
+                        &child.data_points,
+                        child.clusters,
+                        radius,
+                        &mut child_stats,
+                        &route_options,
+                    );
+                    stats.add_route(child_stats);
+                    let routes = match routes {
+                        Ok(routes) => routes,
+                        Err(err) => return (Err(err), stats, data_points),
+                    };
+                    for mut feature in utils::route_features(routes, &name, &enum_type) {
+                        feature.add_instance_properties(Some(name.clone()), None);
+                        features.push(feature);
+                    }
+                }
+                stats.set_route_time(route_time);
+                Ok(features)
+            } else {
+                routing::multi(&data_points, clusters, radius, &mut stats, &route_options)
+                    .map(|routes| utils::route_features(routes, &instance, &enum_type))
+            };
+            (features, stats, data_points)
+        }
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let mut features = match features {
+        Ok(features) => features,
+        Err(err) => return utils::response::calculation_error(err, stats, Some(instance)),
     };
-    feature.add_instance_properties(Some(instance.to_string()), Some(enum_type));
-    let feature = feature.to_collection(Some(instance.clone()), None);
+    for feature in features.iter_mut() {
+        feature.add_instance_properties(Some(instance.to_string()), Some(enum_type.clone()));
+    }
+    let feature = features.to_collection(Some(instance.clone()), None);
 
     if !instance.is_empty() && save_to_db {
         route::Query::upsert_from_geometry(
@@ -300,6 +514,12 @@ async fn cluster(
             .map_err(actix_web::error::ErrorInternalServerError)?;
     }
 
+    let feature = if cluster_features {
+        utils::cluster_features(&feature, &data_points, &ids, radius, &radii)
+    } else {
+        feature
+    };
+
     Ok(utils::response::send(
         feature,
         return_type,
@@ -311,6 +531,8 @@ async fn cluster(
 
 #[post("/reroute")]
 async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
+    let args = payload.into_inner().init(Some("reroute"));
+    let (plugin, _cancel) = utils::plugin_options(&args);
     let ArgsUnwrapped {
         benchmark_mode,
         data_points,
@@ -322,8 +544,21 @@ async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
         sort_by,
         radius,
         routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
+        seed,
         ..
-    } = payload.into_inner().init(Some("reroute"));
+    } = args;
+
+    if seed_with_time_limit(seed, &[routing_time]) {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("seed_with_time_limit")));
+    }
     let mut stats = Stats::new(String::from("Reroute"), 1);
 
     // For legacy compatibility
@@ -334,18 +569,35 @@ async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
     };
     stats.total_clusters = clusters.len();
 
-    let clusters = routing::main(
-        &data_points,
-        clusters,
-        &sort_by,
+    let route_options = RouteOptions {
+        sort_by,
         route_split_level,
-        radius,
-        &mut stats,
-        &routing_args,
-    );
+        routing_args,
+        routing_time,
+        devices,
+        device_balance,
+        travel,
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
+        timed_spawns: vec![],
+        seed,
+        plugin,
+    };
+    let (routes, stats) = web::block(move || {
+        let routes = routing::multi(&data_points, clusters, radius, &mut stats, &route_options);
+        (routes, stats)
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let routes = match routes {
+        Ok(routes) => routes,
+        Err(err) => return utils::response::calculation_error(err, stats, Some(instance)),
+    };
 
-    let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
-    let feature = feature.to_collection(Some(instance.clone()), Some(mode));
+    let feature = utils::route_features(routes, &instance, &mode)
+        .to_collection(Some(instance.clone()), Some(mode));
 
     Ok(utils::response::send(
         feature,
@@ -356,6 +608,234 @@ async fn reroute(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
     ))
 }
 
+#[post("/route-update/{category}")]
+async fn route_update(
+    conn: web::Data<KojiDb>,
+    url: actix_web::web::Path<String>,
+    payload: web::Json<Args>,
+) -> Result<HttpResponse, Error> {
+    let args = payload.into_inner().init(Some("route-update"));
+    let (plugin, _cancel) = utils::plugin_options(&args);
+    let ArgsUnwrapped {
+        benchmark_mode,
+        data_points,
+        radius,
+        radius_gym,
+        radius_pokestop,
+        min_points,
+        cluster_mode,
+        cluster_split_level,
+        clustering_args,
+        last_seen,
+        tth,
+        open_path,
+        return_type,
+        sort_by,
+        route_split_level,
+        routing_args,
+        routing_time,
+        max_hop_distance,
+        start_point,
+        end_point,
+        route_id,
+        save_to_db,
+        s2_level,
+        s2_size,
+        travel,
+        weights,
+        weighted,
+        refine,
+        refine_iterations,
+        refine_time,
+        exclude_area,
+        exclude_data_points,
+        lattice_trials,
+        seed,
+        ..
+    } = args;
+    let category = url.into_inner();
+
+    if seed_with_time_limit(seed, &[refine_time, routing_time]) {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("seed_with_time_limit")));
+    }
+
+    let route_id = if let Some(route_id) = route_id {
+        route_id
+    } else {
+        return Ok(HttpResponse::BadRequest().json(Response::send_error("no_route_id")));
+    };
+    let model = route::Query::get_one(&conn.koji, route_id.to_string())
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if !route_matches_category(&model.mode, &category) {
+        return Ok(HttpResponse::BadRequest()
+            .json(Response::send_error("route_does_not_match_category")));
+    }
+    let existing = Feature::from(
+        geojson::Geometry::from_json_value(model.geometry.clone())
+            .map_err(actix_web::error::ErrorInternalServerError)?,
+    )
+    .to_single_vec();
+
+    let (data_points, weights, ids, radii) = if data_points.is_empty() {
+        let area = geofence::Query::get_one_feature(
+            &conn.koji,
+            model.geofence_id.to_string(),
+            &ApiQueryArgs::default(),
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .to_collection(None, None);
+        let (points, radii) = utils::points_and_radii_from_area(
+            &area,
+            &category,
+            &conn,
+            last_seen,
+            tth,
+            radius_gym,
+            radius_pokestop,
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        let weights = if weighted {
+            utils::point_weights(&points)
+        } else {
+            vec![]
+        };
+        let ids: Vec<String> = points.iter().map(|point| point.i.clone()).collect();
+        (points.to_single_vec(), weights, ids, radii)
+    } else {
+        (data_points, weights, vec![], vec![])
+    };
+    let weights = Weights::new(&data_points, &weights);
+    let radii = Radii::new(&data_points, &radii);
+    let ids = PointIds::new(&data_points, &ids);
+
+    let route_options = RouteOptions {
+        sort_by,
+        route_split_level,
+        routing_args,
+        routing_time,
+        travel: travel.clone(),
+        max_hop_distance,
+        start_point,
+        end_point,
+        open_path,
+        seed,
+        plugin: plugin.clone(),
+        ..Default::default()
+    };
+    let cluster_options = ClusterOptions {
+        cluster_mode: cluster_mode.clone(),
+        min_points,
+        weights: weights.clone(),
+        radii: radii.clone(),
+        ids,
+        cluster_split_level,
+        calculation_mode: CalculationMode::Radius,
+        s2_level,
+        s2_size,
+        clustering_args,
+        refine: refine.then(|| RefineOptions {
+            iterations: refine_iterations,
+            time_limit: refine_time,
+            ..Default::default()
+        }),
+        lattice_trials,
+        exclusions: Exclusions::new(&exclude_area, exclude_data_points),
+        plugin: plugin.clone(),
+        seed,
+        ..Default::default()
+    };
+    let (updated, mut stats) = web::block(move || {
+        let mut stats = Stats::new(format!("Route Update | {:?}", cluster_mode), min_points);
+        stats.weights = weights;
+        stats.radii = radii.clone();
+        let mut plugin_errors = vec![];
+        let updated = incremental::update(
+            existing,
+            &data_points,
+            radius,
+            &radii,
+            &mut stats,
+            &route_options,
+            |uncovered| {
+                let mut uncovered_stats = Stats::new("Route Update".to_string(), min_points);
+                let clusters = clustering::main(
+                    uncovered,
+                    radius,
+                    FeatureCollection::default(),
+                    &mut uncovered_stats,
+                    &cluster_options,
+                );
+                plugin_errors = uncovered_stats.plugin_errors;
+                clusters
+            },
+        );
+        stats.plugin_errors.extend(plugin_errors);
+        (updated, stats)
+    })
+    .await
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+    let (updated, diff) = match updated {
+        Ok(updated) => updated,
+        Err(err) => return utils::response::calculation_error(err, stats, Some(model.name)),
+    };
+    stats.route_diff = Some(diff);
+    if let Some(travel) = &travel {
+        stats.time_stats(&updated, travel, open_path);
+    }
+
+    let mut feature = updated
+        .to_feature(Some(model.mode.clone()))
+        .remove_last_coord();
+    feature.add_instance_properties(Some(model.name.clone()), Some(model.mode.clone()));
+
+    if save_to_db {
+        let geometry = feature
+            .geometry
+            .clone()
+            .ok_or_else(|| actix_web::error::ErrorInternalServerError("route_has_no_geometry"))?;
+        route::Query::update(
+            &conn.koji,
+            model.id,
+            route::Model {
+                geometry: json!(geometry),
+                ..model.clone()
+            },
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+    Ok(utils::response::send(
+        feature.to_collection(Some(model.name.clone()), Some(model.mode)),
+        return_type,
+        Some(stats),
+        benchmark_mode,
+        Some(model.name),
+    ))
+}
+
+/// Whether the mode of the route is one that is generated from the data points of `category`
+fn route_matches_category(mode: &Type, category: &str) -> bool {
+    match category {
+        "gym" => matches!(mode, Type::CircleRaid | Type::CircleSmartRaid),
+        "pokestop" => *mode == Type::CircleQuest,
+        "fort" => matches!(
+            mode,
+            Type::CircleRaid | Type::CircleSmartRaid | Type::CircleQuest
+        ),
+        "spawnpoint" => matches!(mode, Type::CirclePokemon | Type::CircleSmartPokemon),
+        _ => false,
+    }
+}
+
+/// Wall clock limits stop a search after a different amount of work on every machine,
+/// so they can't be combined with a seed
+fn seed_with_time_limit(seed: Option<u64>, time_limits: &[u64]) -> bool {
+    seed.is_some() && time_limits.iter().any(|limit| *limit > 0)
+}
+
 #[post("/route-stats")]
 async fn route_stats(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
     let ArgsUnwrapped {
@@ -365,6 +845,11 @@ async fn route_stats(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
         radius,
         mode,
         min_points,
+        open_path,
+        travel,
+        weights,
+        s2_level,
+        include_uncovered,
         ..
     } = payload.into_inner().init(Some("route-stats"));
 
@@ -373,13 +858,29 @@ async fn route_stats(payload: web::Json<Args>) -> Result<HttpResponse, Error> {
             .json(Response::send_error("no_clusters_or_data_points_found")));
     }
     let mut stats = Stats::new(format!("Route Stats | {:?}", mode), min_points);
+    stats.weights = Weights::new(&data_points, &weights);
 
-    stats.distance_stats(&clusters);
+    stats.distance_stats(&clusters, open_path);
+    if let Some(travel) = &travel {
+        stats.time_stats(&clusters, travel, open_path);
+    }
     if !data_points.is_empty() {
         stats.cluster_stats(radius, &data_points, &clusters);
         stats.set_score();
     }
 
+    if include_uncovered && !data_points.is_empty() {
+        stats.uncovered = Some(utils::uncovered_feature(
+            radius,
+            &data_points,
+            &clusters,
+            &stats.radii,
+            &instance,
+            &mode,
+            s2_level,
+        ));
+    }
+
     let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
     let feature = feature.to_collection(Some(instance.clone()), Some(mode));
 
@@ -403,12 +904,20 @@ async fn route_stats_category(
         data_points,
         instance,
         radius,
+        radius_gym,
+        radius_pokestop,
         mode,
         area,
         parent,
         last_seen,
         tth,
         min_points,
+        open_path,
+        travel,
+        weights,
+        weighted,
+        s2_level,
+        include_uncovered,
         ..
     } = payload.into_inner().init(Some("route-stats"));
     let category = url.into_inner();
@@ -417,13 +926,26 @@ async fn route_stats_category(
         .await
         .map_err(actix_web::error::ErrorInternalServerError)?;
 
-    let data_points = if !data_points.is_empty() {
-        data_points
+    let (data_points, weights, radii) = if !data_points.is_empty() {
+        (data_points, weights, vec![])
     } else {
-        utils::points_from_area(&area, &category, &conn, last_seen, tth)
-            .await
-            .map_err(actix_web::error::ErrorInternalServerError)?
-            .to_single_vec()
+        let (points, radii) = utils::points_and_radii_from_area(
+            &area,
+            &category,
+            &conn,
+            last_seen,
+            tth,
+            radius_gym,
+            radius_pokestop,
+        )
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        let weights = if weighted {
+            utils::point_weights(&points)
+        } else {
+            vec![]
+        };
+        (points.to_single_vec(), weights, radii)
     };
 
     if clusters.is_empty() && data_points.is_empty() {
@@ -432,13 +954,30 @@ async fn route_stats_category(
     }
 
     let mut stats = Stats::new(format!("Route Stats | {:?}", mode), min_points);
+    stats.weights = Weights::new(&data_points, &weights);
+    stats.radii = Radii::new(&data_points, &radii);
 
-    stats.distance_stats(&clusters);
+    stats.distance_stats(&clusters, open_path);
+    if let Some(travel) = &travel {
+        stats.time_stats(&clusters, travel, open_path);
+    }
     if !data_points.is_empty() {
         stats.cluster_stats(radius, &data_points, &clusters);
         stats.set_score();
     }
 
+    if include_uncovered && !data_points.is_empty() {
+        stats.uncovered = Some(utils::uncovered_feature(
+            radius,
+            &data_points,
+            &clusters,
+            &stats.radii,
+            &instance,
+            &mode,
+            s2_level,
+        ));
+    }
+
     let feature = clusters.to_feature(Some(mode.clone())).remove_last_coord();
     let feature = feature.to_collection(Some(instance.clone()), Some(mode));
 
@@ -1,6 +1,6 @@
 use super::*;
 
-use algorithms::{bootstrap, clustering, routing};
+use algorithms::{bootstrap, clustering, plugin::Manifests, routing};
 use serde_json::json;
 
 #[get("/")]
@@ -9,5 +9,6 @@ async fn main() -> Result<HttpResponse, Error> {
         "routing": routing::all_routing_options(),
         "clustering": clustering::all_clustering_options(),
         "bootstrap": bootstrap::all_bootstrap_options(),
+        "plugins": Manifests::load(),
     })))
 }
@@ -1,17 +1,27 @@
 use super::*;
 
+use algorithms::{
+    coverage,
+    plugin::{CancelGuard, PluginOptions, DEFAULT_TIMEOUT, DEFAULT_WASM_FUEL, DEFAULT_WASM_MEMORY},
+    radii::Radii,
+    routing::spawn_time::TimedSpawn,
+};
 use geo::Point;
 use geojson::{Geometry, Value};
 use model::{
     api::{
-        args::{ApiQueryArgs, SpawnpointTth, UnknownId},
+        args::{ApiQueryArgs, ArgsUnwrapped, SpawnpointTth, UnknownId},
         single_vec::SingleVec,
-        BBox, ToCollection,
+        BBox, FeatureHelpers, Precision, ToCollection, ToFeature, ToSingleVec,
+    },
+    db::{
+        area, geofence, gym, instance, pokestop, sea_orm_active_enums::Type, spawnpoint,
+        GenericData,
     },
-    db::{area, geofence, gym, instance, pokestop, spawnpoint, GenericData},
     error::ModelError,
     KojiDb, ScannerType,
 };
+use serde_json::json;
 
 pub mod auth;
 pub mod error;
@@ -105,8 +115,7 @@ pub async fn points_from_area(
             "pokestop" => pokestop::Query::area(&conn.scanner, &area, last_seen).await,
             "spawnpoint" => spawnpoint::Query::area(&conn.scanner, &area, last_seen, tth).await,
             "fort" => {
-                let gyms = gym::Query::area(&conn.scanner, &area, last_seen).await?;
-                let pokestops = pokestop::Query::area(&conn.scanner, &area, last_seen).await?;
+                let (gyms, pokestops) = forts_from_area(area, conn, last_seen).await?;
                 Ok(gyms.into_iter().chain(pokestops.into_iter()).collect())
             }
             _ => Err(DbErr::Custom("Invalid Category".to_string())),
@@ -115,3 +124,160 @@ pub async fn points_from_area(
         Ok(vec![])
     }
 }
+
+/// Gyms and pokestops of the `fort` category, kept apart
+async fn forts_from_area(
+    area: &FeatureCollection,
+    conn: &KojiDb,
+    last_seen: u32,
+) -> Result<(Vec<GenericData>, Vec<GenericData>), DbErr> {
+    let gyms = gym::Query::area(&conn.scanner, area, last_seen).await?;
+    let pokestops = pokestop::Query::area(&conn.scanner, area, last_seen).await?;
+    Ok((gyms, pokestops))
+}
+
+/// Same as [points_from_area], along with the radius of each point from the radius of its category.
+/// Points of a category without a radius get `0`, so that they use the radius of the request
+pub async fn points_and_radii_from_area(
+    area: &FeatureCollection,
+    category: &String,
+    conn: &KojiDb,
+    last_seen: u32,
+    tth: SpawnpointTth,
+    radius_gym: Option<Precision>,
+    radius_pokestop: Option<Precision>,
+) -> Result<(Vec<GenericData>, Vec<Precision>), DbErr> {
+    if category == "fort" && !area.features.is_empty() {
+        let (gyms, pokestops) = forts_from_area(area, conn, last_seen).await?;
+        let radii = std::iter::repeat_n(radius_gym.unwrap_or(0.), gyms.len())
+            .chain(std::iter::repeat_n(
+                radius_pokestop.unwrap_or(0.),
+                pokestops.len(),
+            ))
+            .collect();
+        let points = gyms.into_iter().chain(pokestops).collect();
+        Ok((points, radii))
+    } else {
+        let points = points_from_area(area, category, conn, last_seen, tth).await?;
+        let radius = match category.as_str() {
+            "gym" => radius_gym,
+            "pokestop" => radius_pokestop,
+            _ => None,
+        };
+        let radii = radius
+            .map(|radius| vec![radius; points.len()])
+            .unwrap_or_default();
+        Ok((points, radii))
+    }
+}
+
+/// Plugin limits of the request, the plugins are killed when the guard is dropped,
+/// e.g. when the client disconnects and actix drops the handler
+pub fn plugin_options(args: &ArgsUnwrapped) -> (PluginOptions, CancelGuard) {
+    let plugin = PluginOptions {
+        timeout: args.plugin_timeout.unwrap_or(DEFAULT_TIMEOUT),
+        wasm_fuel: args.wasm_fuel.unwrap_or(DEFAULT_WASM_FUEL),
+        wasm_memory: args.wasm_memory_limit.unwrap_or(DEFAULT_WASM_MEMORY) << 20,
+        ..Default::default()
+    };
+    let cancel = plugin.cancel.guard();
+    (plugin, cancel)
+}
+
+/// Spawnpoints with a known despawn time, used for timing based routing
+pub fn timed_spawns(points: &[GenericData]) -> Vec<TimedSpawn> {
+    points
+        .iter()
+        .filter_map(|point| {
+            point.d.map(|despawn_sec| TimedSpawn {
+                point: point.p,
+                despawn_sec,
+            })
+        })
+        .collect()
+}
+
+/// Weight of each point, `1` when the point has no weight
+pub fn point_weights(points: &[GenericData]) -> Vec<Precision> {
+    points.iter().map(|point| point.w.unwrap_or(1.)).collect()
+}
+
+/// Converts routes into features, when there is more than one route,
+/// the name of each is suffixed with the device number
+pub fn route_features(routes: Vec<SingleVec>, name: &str, enum_type: &Type) -> Vec<Feature> {
+    let multiple = routes.len() > 1;
+    routes
+        .into_iter()
+        .enumerate()
+        .map(|(i, route)| {
+            let mut feature = route
+                .to_feature(Some(enum_type.clone()))
+                .remove_last_coord();
+            if multiple {
+                feature.set_property("__name", format!("{}-{}", name, i + 1));
+            }
+            feature
+        })
+        .collect()
+}
+
+/// Splits the routes into a Point feature for each cluster with the data points it covers.
+/// Points are identified by `ids` when there are any, otherwise by their index in `data_points`
+pub fn cluster_features(
+    routes: &FeatureCollection,
+    data_points: &SingleVec,
+    ids: &[String],
+    radius: Precision,
+    radii: &Radii,
+) -> FeatureCollection {
+    let mut names = vec![];
+    let mut clusters = SingleVec::new();
+    for feature in routes.features.iter() {
+        let name = feature.property("__name").cloned();
+        for point in feature.clone().to_single_vec() {
+            names.push(name.clone());
+            clusters.push(point);
+        }
+    }
+    coverage::clusters(radius, data_points, &clusters, radii)
+        .into_iter()
+        .zip(names)
+        .map(|(cluster, name)| {
+            let mut feature = cluster.center.to_feature(None);
+            if let Some(name) = name {
+                feature.set_property("__name", name);
+            }
+            feature.set_property("covered", cluster.covered);
+            feature.set_property("unique", cluster.unique);
+            feature.set_property(
+                "ids",
+                cluster
+                    .points
+                    .iter()
+                    .map(|&i| ids.get(i).cloned().unwrap_or_else(|| i.to_string()))
+                    .collect::<Vec<_>>(),
+            );
+            feature.set_property("nearest_distance", cluster.nearest_distance);
+            feature
+        })
+        .collect()
+}
+
+/// MultiPoint of the data points that none of the clusters cover,
+/// with the number of them in each S2 cell of `s2_level`
+pub fn uncovered_feature(
+    radius: Precision,
+    data_points: &SingleVec,
+    clusters: &SingleVec,
+    radii: &Radii,
+    name: &str,
+    enum_type: &Type,
+    s2_level: u8,
+) -> Feature {
+    let uncovered = coverage::uncovered(radius, data_points, clusters, radii);
+    let mut feature = uncovered.clone().to_feature(Some(enum_type.clone()));
+    feature.set_property("__name", format!("{}-uncovered", name));
+    feature.set_property("uncovered", uncovered.len());
+    feature.set_property("cells", json!(coverage::count_by_cell(&uncovered, s2_level)));
+    feature
+}
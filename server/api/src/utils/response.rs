@@ -1,7 +1,7 @@
 use super::*;
 
 use actix_web::HttpResponse;
-use algorithms::stats::Stats;
+use algorithms::{plugin::Manifests, stats::Stats};
 use geojson::JsonValue;
 use model::api::{Precision, ToGeometry, ToSql};
 use serde::Serialize;
@@ -23,6 +23,7 @@ pub struct ConfigResponse {
     pub route_plugins: Vec<String>,
     pub clustering_plugins: Vec<String>,
     pub bootstrap_plugins: Vec<String>,
+    pub plugin_manifests: Manifests,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -94,3 +95,30 @@ pub fn send(
         stats,
     })
 }
+
+/// Responds with [plugin_error] when a plugin failed, any other error of the calculation
+/// is returned as an internal server error
+pub fn calculation_error(
+    err: std::io::Error,
+    stats: Stats,
+    area: Option<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    if stats.plugin_errors.is_empty() {
+        Err(actix_web::error::ErrorInternalServerError(err))
+    } else {
+        Ok(plugin_error(stats, area))
+    }
+}
+
+/// A plugin failed, the result is not returned since it doesn't come from the requested plugin.
+/// The errors are in `stats.plugin_errors`
+pub fn plugin_error(stats: Stats, area: Option<String>) -> HttpResponse {
+    stats.log(area);
+    HttpResponse::InternalServerError().json(Response {
+        message: format!("plugin_error: {}", stats.plugin_errors.join(" | ")),
+        status: "error".to_string(),
+        status_code: 500,
+        data: None,
+        stats: Some(stats),
+    })
+}
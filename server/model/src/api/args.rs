@@ -1,4 +1,7 @@
-use super::{calc_mode::CalculationMode, cluster_mode::ClusterMode, sort_by::SortBy, *};
+use super::{
+    calc_mode::CalculationMode, cluster_mode::ClusterMode, sort_by::SortBy,
+    split_level::SplitLevel, *,
+};
 
 use crate::{
     api::text::TextHelpers,
@@ -181,6 +184,43 @@ pub enum SpawnpointTth {
     Unknown,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum DeviceBalance {
+    HopCount,
+    Distance,
+}
+
+/// How long a device takes to travel between two points
+#[derive(Debug, Clone)]
+pub enum Travel {
+    /// Meters per second
+    Speed(Precision),
+    /// `[distance, seconds]` pairs sorted by distance
+    Cooldown(Vec<[Precision; 2]>),
+}
+
+impl Default for Travel {
+    fn default() -> Self {
+        Travel::Speed(10.)
+    }
+}
+
+impl Travel {
+    /// Seconds needed to travel `distance` meters, legs longer than the
+    /// cooldown table use the last cooldown
+    pub fn leg_time(&self, distance: Precision) -> Precision {
+        match self {
+            Travel::Speed(speed) => distance / speed,
+            Travel::Cooldown(table) => table
+                .iter()
+                .find(|[max_distance, _]| distance <= *max_distance)
+                .or(table.last())
+                .map(|[_, seconds]| *seconds)
+                .unwrap_or(0.),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum DataPointsArg {
@@ -240,26 +280,85 @@ pub struct Args {
     pub cluster_mode: Option<ClusterMode>,
     /// BruteForce cluster mode tweak, determines how points are split up for multithreading
     ///
-    /// Accepts 1-30
+    /// Accepts 1-20 or `auto`, which picks the level from the number of data points,
+    /// how dense they are and the number of cores, the cores are ignored when `seed` is set
     ///
     /// Default: `10`
-    pub cluster_split_level: Option<u64>,
+    pub cluster_split_level: Option<SplitLevel>,
     /// Data points to cluster or reroute.
     /// Overrides any inputted area.
     ///
     /// Accepts [DataPointsArg]
     pub data_points: Option<DataPointsArg>,
+    /// Teleport cooldowns as `[distance, seconds]` pairs, in meters,
+    /// e.g. `[[1000, 30], [5000, 120], [25000, 600]]`.
+    /// Takes priority over `speed_mps` for travel times
+    ///
+    /// Default: `None`
+    pub cooldown_table: Option<Vec<[Precision; 2]>>,
     /// Clusters to run through the stat producer.
     ///
     /// Accepts [DataPointsArg]
     pub clusters: Option<DataPointsArg>,
-    /// Number of devices to use in VRP routing
+    /// Fraction of the diameter that neighboring circles share when bootstrapping along
+    /// LineString and MultiLineString features, from `0` to `0.9`
+    ///
+    /// Default: `0.25`
+    pub corridor_overlap: Option<Precision>,
+    /// Width, in meters, of the corridor that is covered around LineString and MultiLineString
+    /// features when bootstrapping. Parallel lanes of circles are added until it is covered,
+    /// which requires a `corridor_overlap` greater than `0`
+    ///
+    /// Default: `0` (a single lane along the line)
+    pub corridor_width: Option<Precision>,
+    /// Number of devices to split the route between,
+    /// each device receives its own route.
+    /// When there are fewer clusters than devices, each cluster is a route of its own
+    /// and fewer routes are returned.
+    /// Splitting the clusters and routing every device share the same `routing_time`
     ///
     /// Default: `1`
-    ///
-    /// Deprecated
     pub devices: Option<usize>,
-    /// The maximum amount of clusters to return
+    /// How the clusters are balanced between devices when `devices` is greater than 1
+    ///
+    /// Accepts [DeviceBalance] - case sensitive
+    ///
+    /// Default: `Distance`
+    pub device_balance: Option<DeviceBalance>,
+    /// The route ends at the cluster closest to this coordinate, `[lat, lon]`.
+    /// Only used when `open_path` is true
+    ///
+    /// Default: `None`
+    pub end_point: Option<point_array::PointArray>,
+    /// Areas that no cluster or bootstrap circle may be placed in, such as water or private land.
+    /// Interior rings of the `area` polygons are excluded as well
+    ///
+    /// Accepts an optional [GeoFormats]
+    ///
+    /// Default: `None`
+    pub exclude_area: Option<GeoFormats>,
+    /// Leaves the data points inside `exclude_area` out of clustering
+    ///
+    /// Default: `false`
+    pub exclude_data_points: Option<bool>,
+    /// Clusters the data points of every geofence in `area` together, so that circles along
+    /// shared borders aren't placed once per geofence.
+    /// Each cluster is then given to the geofence whose data points it covers the most
+    /// and every geofence is routed on its own.
+    /// Every geofence is also clustered on its own to count the circles saved in `stats.joint`,
+    /// so the clustering takes about twice as long
+    ///
+    /// Default: `false`
+    pub joint_clustering: Option<bool>,
+    /// Maximum distance, in meters, between two consecutive points of a route.
+    /// Longer legs are avoided by reordering when possible,
+    /// otherwise filler points are inserted along them
+    ///
+    /// Default: `0` (unlimited)
+    pub max_hop_distance: Option<Precision>,
+    /// The maximum amount of clusters to return.
+    /// When set, every cluster mode returns the clusters that cover the most points,
+    /// or weight, ordered by how much each one adds to the coverage
     ///
     /// Default: [USIZE::MAX]
     pub max_clusters: Option<usize>,
@@ -287,9 +386,17 @@ pub struct Args {
     ///
     /// Default: `0`
     pub last_seen: Option<u32>,
+    /// Number of hex lattice arrangements, shifted and rotated, that are tried by the `Honeycomb`
+    /// cluster mode and by radius bootstrapping. Bootstrapping keeps the one with the fewest circles,
+    /// `Honeycomb` keeps the one that covers the most data points, or weight, per circle.
+    /// Accepts 1-64
+    ///
+    /// Default: `1` (only the lattice without a shift or rotation, starting at the north-east corner of the bounding box)
+    pub lattice_trials: Option<usize>,
     /// Internally used, unstable
     pub mode: Option<String>,
-    /// Minimum number of points to use in the clustering algorithms
+    /// Minimum number of points to use in the clustering algorithms.
+    /// When the data points are weighted, this is the minimum weight a cluster has to cover
     ///
     /// Default: `1`
     pub min_points: Option<usize>,
@@ -297,21 +404,79 @@ pub struct Args {
     /// Only available when `fast: false`
     /// Deprecated
     pub only_unique: Option<bool>,
+    /// Produces an open path instead of a loop,
+    /// the leg from the last point back to the first is not included in the distance stats
+    ///
+    /// Default: `false`
+    pub open_path: Option<bool>,
     /// The ID or name of the parent property, this will search the database for any properties that have their `parent` property set to this value.
     ///
     /// Default: `None`
     pub parent: Option<UnknownId>,
+    /// Seconds that a custom clustering, routing or bootstrap plugin may run
+    /// before it is killed along with any processes it started
+    ///
+    /// Default: `600`
+    pub plugin_timeout: Option<u64>,
     /// Radius of the circle to be used in clustering/routing,
     /// in meters
     ///
     /// Default: `70`
     pub radius: Option<Precision>,
+    /// Radius, in meters, of the gyms. Gyms are then covered within this radius instead of `radius`,
+    /// which lets `fort` mix gyms and pokestops with different interaction ranges.
+    /// Used when clustering and by the route stats of a category, but not by `Fastest`, clustering plugins or the S2 calculation mode, which log a warning instead
+    ///
+    /// Default: `None` (uses `radius`)
+    pub radius_gym: Option<Precision>,
+    /// Radius, in meters, of the pokestops, the same as `radius_gym`
+    ///
+    /// Default: `None` (uses `radius`)
+    pub radius_pokestop: Option<Precision>,
+    /// Runs a local search after clustering that removes redundant clusters,
+    /// replaces pairs of clusters with a single one and re-centers clusters onto uncovered points.
+    /// Never reduces the number of covered points
+    ///
+    /// Default: `false`
+    pub refine: Option<bool>,
+    /// Maximum number of refinement passes, `0` runs until nothing changes
+    ///
+    /// Default: `10`
+    pub refine_iterations: Option<usize>,
+    /// Amount of time, in seconds, for the refinement to run
+    ///
+    /// Default: `0` (unlimited)
+    pub refine_time: Option<u64>,
+    /// Wall clock budget for clustering, in milliseconds.
+    /// A quick `Fast` round, or `Balanced` for the other modes, runs first and always completes.
+    /// Its result is refined and clustering is repeated with denser `Balanced` candidates for as long
+    /// as the next round is expected to finish in time, a round still running at the deadline is dropped.
+    /// The best solution is returned and the score after each round is in `stats.score_timeline`.
+    /// Rejected with `Fastest`, custom plugins and the S2 calculation mode
+    ///
+    /// Default: `0` (off)
+    pub time_limit_ms: Option<u64>,
+    /// Makes the output reproducible, identical input and args return identical clusters and routes
+    /// regardless of the machine or the number of threads. The seed is used by every randomized step,
+    /// `Random` sorting and the kicks of the native TSP and `SpawnTime` solvers.
+    /// The `auto` split levels then ignore the number of cores.
+    /// Every search then stops on work limits, such as a cap on the kicks of the native TSP solver,
+    /// and the automatic routing time is ignored.
+    /// Rejected together with `time_limit_ms`, `refine_time` or `routing_time`.
+    /// Timings in the stats and custom plugins are not covered
+    ///
+    /// Default: `None`
+    pub seed: Option<u64>,
     /// The return type for the data
     ///
     /// Accepts [ReturnTypeArg]
     ///
     /// Default: `SingleVec`
     pub return_type: Option<String>,
+    /// The ID or name of a saved route to update with new data points
+    ///
+    /// Default: `None`
+    pub route_id: Option<UnknownId>,
     /// Manual chunking to split TSP routing.
     ///
     /// Default: 1
@@ -324,17 +489,17 @@ pub struct Args {
     pub routing_args: Option<String>,
     /// Geohash precision level for splitting up routing into multiple threads
     ///
-    /// Recommend using 4 for Gyms, 5 for Pokestops, and 6 for Spawnpoints
+    /// Recommend using 4 for Gyms, 5 for Pokestops, and 6 for Spawnpoints,
+    /// or `auto` to pick the level from the number of clusters, how dense they are
+    /// and the number of cores, the cores are ignored when `seed` is set
     ///
     /// Default: `1`
-    pub route_split_level: Option<u64>,
-    /// Amount of time for the TSP solver to run
-    ///
-    /// Default: `0` (auto)
+    pub route_split_level: Option<SplitLevel>,
+    /// Amount of time, in seconds, for the native TSP solver to run
     ///
-    /// Deprecated
+    /// Default: `0` (auto, scales with the number of clusters)
     pub routing_time: Option<i64>,
-    /// S2 Level to use for calculation mode
+    /// S2 Level to use for calculation mode and for grouping the `include_uncovered` points of the route stats
     ///
     /// Accepts 10-20
     ///
@@ -366,6 +531,16 @@ pub struct Args {
     ///
     /// Default: `GeoHash`
     pub sort_by: Option<SortBy>,
+    /// Travel speed of the device, in meters per second,
+    /// used for timing based routing such as `SpawnTime` and the travel time stats
+    ///
+    /// Default: `None` (`10` for `SpawnTime`)
+    pub speed_mps: Option<Precision>,
+    /// The route starts at the cluster closest to this coordinate, `[lat, lon]`,
+    /// such as where a device spawns
+    ///
+    /// Default: `None`
+    pub start_point: Option<point_array::PointArray>,
     /// Filter spawnpoints by confirmed, unconfirmed, or all
     ///
     /// Accepts [SpawnpointTth] - case sensitive
@@ -376,6 +551,38 @@ pub struct Args {
     ///
     /// Default: `false`
     pub center_clusters: Option<bool>,
+    /// Returns each cluster as its own Point feature instead of a MultiPoint per route.
+    /// The features are in route order and have the `covered` and `unique` point counts,
+    /// the `ids` of the covered points and the `nearest_distance`, in meters, to the closest cluster.
+    /// Routes saved with `save_to_db` or `save_to_scanner` are unaffected
+    ///
+    /// Default: `false`
+    pub cluster_features: Option<bool>,
+    /// Adds a MultiPoint of the data points that the route doesn't cover to the route stats,
+    /// with the number of them in each S2 cell of `s2_level`
+    ///
+    /// Default: `false`
+    pub include_uncovered: Option<bool>,
+    /// Weight of each data point, matched to `data_points` by index.
+    /// Clustering then maximizes the covered weight instead of the number of covered points
+    ///
+    /// Default: `None`
+    pub weights: Option<Vec<Precision>>,
+    /// Weights the data points that are loaded from the scanner database,
+    /// gyms by the level of their current raid, other points weigh `1`.
+    /// Ignored when `weights` are provided
+    ///
+    /// Default: `false`
+    pub weighted: Option<bool>,
+    /// Fuel of a `.wasm` plugin, roughly the number of instructions it may execute before it is stopped.
+    /// `plugin_timeout` applies to them as well
+    ///
+    /// Default: `10000000000`
+    pub wasm_fuel: Option<u64>,
+    /// Memory, in MiB, that a `.wasm` plugin may use, up to `4096`
+    ///
+    /// Default: `256`
+    pub wasm_memory_limit: Option<usize>,
 }
 
 pub struct ArgsUnwrapped {
@@ -383,17 +590,35 @@ pub struct ArgsUnwrapped {
     pub benchmark_mode: bool,
     pub calculation_mode: CalculationMode,
     pub cluster_mode: ClusterMode,
-    pub cluster_split_level: u64,
+    pub cluster_split_level: SplitLevel,
     pub max_clusters: usize,
+    pub max_hop_distance: Precision,
     pub clusters: single_vec::SingleVec,
     pub data_points: single_vec::SingleVec,
+    pub corridor_overlap: Precision,
+    pub corridor_width: Precision,
     pub devices: usize,
+    pub device_balance: DeviceBalance,
+    pub end_point: Option<point_array::PointArray>,
+    pub exclude_area: FeatureCollection,
+    pub exclude_data_points: bool,
     pub generations: usize,
     pub instance: String,
+    pub joint_clustering: bool,
+    pub lattice_trials: usize,
     pub min_points: usize,
+    pub open_path: bool,
     pub radius: Precision,
+    pub radius_gym: Option<Precision>,
+    pub radius_pokestop: Option<Precision>,
+    pub refine: bool,
+    pub refine_iterations: usize,
+    pub refine_time: u64,
+    pub time_limit_ms: u64,
+    pub seed: Option<u64>,
     pub return_type: ReturnTypeArg,
     pub parent: Option<UnknownId>,
+    pub plugin_timeout: Option<u64>,
     pub last_seen: u32,
     pub s2_level: u8,
     pub s2_size: u8,
@@ -401,29 +626,37 @@ pub struct ArgsUnwrapped {
     pub save_to_scanner: bool,
     pub simplify: bool,
     pub sort_by: SortBy,
+    pub travel: Option<Travel>,
+    pub start_point: Option<point_array::PointArray>,
     pub tth: SpawnpointTth,
     pub mode: Type,
-    pub route_split_level: u64,
+    pub route_id: Option<UnknownId>,
+    pub route_split_level: SplitLevel,
     pub routing_args: String,
+    pub routing_time: u64,
     pub clustering_args: String,
     pub bootstrapping_args: String,
     pub center_clusters: bool,
+    pub cluster_features: bool,
+    pub include_uncovered: bool,
+    pub weights: Vec<Precision>,
+    pub weighted: bool,
+    pub wasm_fuel: Option<u64>,
+    pub wasm_memory_limit: Option<usize>,
 }
 
-fn validate_s2_cell(value_to_check: Option<u64>, label: &str) -> u64 {
-    if let Some(cell_level) = value_to_check {
-        if cell_level.le(&20) && cell_level.ge(&0) {
-            cell_level
-        } else {
+fn validate_s2_cell(value_to_check: Option<SplitLevel>, label: &str) -> SplitLevel {
+    match value_to_check {
+        Some(SplitLevel::Level(cell_level)) if cell_level > 20 => {
             log::warn!(
                 "{} only supports 0-20, {} was provided, defaulting to 0",
                 label,
                 cell_level
             );
-            0
+            SplitLevel::Level(0)
         }
-    } else {
-        0
+        Some(split_level) => split_level,
+        None => SplitLevel::Level(0),
     }
 }
 
@@ -453,26 +686,48 @@ impl Args {
             cluster_mode,
             cluster_split_level,
             max_clusters,
+            max_hop_distance,
             s2_size,
             clusters,
+            cooldown_table,
+            corridor_overlap,
+            corridor_width,
             data_points,
             devices,
+            device_balance,
+            end_point,
+            exclude_area,
+            exclude_data_points,
             fast,
             generations,
             instance,
+            joint_clustering,
+            lattice_trials,
             min_points,
             radius,
+            radius_gym,
+            radius_pokestop,
+            refine,
+            refine_iterations,
+            refine_time,
+            time_limit_ms,
+            seed,
             return_type,
             routing_time,
             only_unique,
+            open_path,
             parent,
+            plugin_timeout,
             last_seen,
             save_to_db,
             save_to_scanner,
             route_chunk_size,
+            route_id,
             simplify,
             geometry_type,
             sort_by,
+            speed_mps,
+            start_point,
             tth,
             mode,
             route_split_level,
@@ -480,6 +735,12 @@ impl Args {
             clustering_args,
             bootstrapping_args,
             center_clusters,
+            cluster_features,
+            include_uncovered,
+            weights,
+            weighted,
+            wasm_fuel,
+            wasm_memory_limit,
         } = self;
         let enum_type = get_enum_by_geometry_string(geometry_type);
         let (area, default_return_type) = if let Some(area) = area {
@@ -525,10 +786,12 @@ impl Args {
         });
         let cluster_split_level = validate_s2_cell(cluster_split_level, "cluster_split_level");
         let data_points = resolve_data_points(data_points);
-        let devices = devices.unwrap_or(1);
+        let devices = devices.unwrap_or(1).max(1);
+        let device_balance = device_balance.unwrap_or(DeviceBalance::Distance);
         let generations = generations.unwrap_or(1);
         let instance = instance.unwrap_or("".to_string());
         let min_points = min_points.unwrap_or(1);
+        let open_path = open_path.unwrap_or(false);
         let radius = radius.unwrap_or(70.0);
         let return_type = if let Some(return_type) = return_type {
             get_return_type(return_type, &default_return_type)
@@ -544,17 +807,44 @@ impl Args {
         } else {
             usize::MAX
         };
+        let max_hop_distance = max_hop_distance.unwrap_or(0.).max(0.);
         let center_clusters = center_clusters.unwrap_or(false);
+        let cluster_features = cluster_features.unwrap_or(false);
+        let include_uncovered = include_uncovered.unwrap_or(false);
+        let exclude_area = exclude_area
+            .map(|exclude_area| exclude_area.to_collection(None, None))
+            .unwrap_or_default();
+        let exclude_data_points = exclude_data_points.unwrap_or(false);
+        let joint_clustering = joint_clustering.unwrap_or(false);
+        let lattice_trials = lattice_trials.unwrap_or(1).clamp(1, 64);
+        let corridor_overlap = corridor_overlap.unwrap_or(0.25).clamp(0., 0.9);
+        let corridor_width = corridor_width.unwrap_or(0.).max(0.);
+        let weights = weights.unwrap_or_default();
+        let refine = refine.unwrap_or(false);
+        let refine_iterations = refine_iterations.unwrap_or(10);
+        let refine_time = refine_time.unwrap_or(0);
+        let time_limit_ms = time_limit_ms.unwrap_or(0);
+        let weighted = weighted.unwrap_or(false);
+        let wasm_memory_limit = wasm_memory_limit.map(|limit| limit.clamp(1, 4096));
         let clusters = resolve_data_points(clusters);
         let last_seen = last_seen.unwrap_or(0);
+        let plugin_timeout = plugin_timeout.map(|timeout| timeout.max(1));
         let save_to_db = save_to_db.unwrap_or(false);
         let save_to_scanner = save_to_scanner.unwrap_or(false);
         let simplify = simplify.unwrap_or(false);
         let sort_by = sort_by.unwrap_or(SortBy::Unset);
+        let travel = match cooldown_table {
+            Some(mut table) if !table.is_empty() => {
+                table.sort_by(|a, b| a[0].total_cmp(&b[0]));
+                Some(Travel::Cooldown(table))
+            }
+            _ => speed_mps.filter(|speed| *speed > 0.).map(Travel::Speed),
+        };
         let tth = tth.unwrap_or(SpawnpointTth::All);
         let mode = get_enum(mode);
         let route_split_level = validate_s2_cell(route_split_level, "route_split_level");
         let routing_args = routing_args.unwrap_or("".to_string());
+        let routing_time = routing_time.unwrap_or(0).max(0) as u64;
 
         let mut clustering_args = clustering_args.unwrap_or("".to_string());
         clustering_args += &format!(" --radius {}", radius);
@@ -567,9 +857,6 @@ impl Args {
         if route_chunk_size.is_some() {
             log::warn!("route_chunk_size is now deprecated, please use route_split_level")
         }
-        if routing_time.is_some() {
-            log::warn!("routing_time is now deprecated, please use route_split_level")
-        }
         if only_unique.is_some() {
             log::warn!("only_unique is now deprecated and does nothing");
         }
@@ -579,30 +866,58 @@ impl Args {
             cluster_mode,
             clusters,
             max_clusters,
+            max_hop_distance,
             cluster_split_level,
             s2_level,
             calculation_mode,
             s2_size,
             data_points,
+            corridor_overlap,
+            corridor_width,
             devices,
+            device_balance,
+            end_point,
+            exclude_area,
+            exclude_data_points,
             generations,
             parent,
+            plugin_timeout,
             instance,
+            joint_clustering,
+            lattice_trials,
             min_points,
+            open_path,
             radius,
+            radius_gym,
+            radius_pokestop,
+            refine,
+            refine_iterations,
+            refine_time,
+            time_limit_ms,
+            seed,
             return_type,
             last_seen,
             save_to_db,
             save_to_scanner,
             simplify,
             sort_by,
+            travel,
+            start_point,
             tth,
             mode,
+            route_id,
             route_split_level,
             routing_args,
+            routing_time,
             clustering_args,
             bootstrapping_args,
             center_clusters,
+            cluster_features,
+            include_uncovered,
+            weights,
+            weighted,
+            wasm_fuel,
+            wasm_memory_limit,
         }
     }
 }
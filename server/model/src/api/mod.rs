@@ -21,6 +21,7 @@ pub mod poracle;
 pub mod single_struct;
 pub mod single_vec;
 pub mod sort_by;
+pub mod split_level;
 pub mod text;
 
 pub type Precision = f64;
@@ -8,6 +8,8 @@ pub enum SortBy {
     Random,
     S2Cell,
     LatLon,
+    NativeTsp,
+    SpawnTime,
     Custom(String),
 }
 
@@ -19,6 +21,8 @@ impl PartialEq for SortBy {
             (SortBy::PointCount, SortBy::PointCount) => true,
             (SortBy::Random, SortBy::Random) => true,
             (SortBy::S2Cell, SortBy::S2Cell) => true,
+            (SortBy::NativeTsp, SortBy::NativeTsp) => true,
+            (SortBy::SpawnTime, SortBy::SpawnTime) => true,
             _ => false,
         }
     }
@@ -41,6 +45,8 @@ impl<'de> Deserialize<'de> for SortBy {
             "random" => Ok(SortBy::Random),
             "s2" | "s2cell" => Ok(SortBy::S2Cell),
             "latlon" => Ok(SortBy::LatLon),
+            "native_tsp" | "nativetsp" | "native" => Ok(SortBy::NativeTsp),
+            "spawn_time" | "spawntime" | "despawn" => Ok(SortBy::SpawnTime),
             "" | "none" | "unset" => Ok(SortBy::Unset),
             // This is for backwards compatibility since the custom below would end up with a value of "TSP"
             "tsp" => Ok(SortBy::Custom("tsp".to_string())),
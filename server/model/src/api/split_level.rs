@@ -0,0 +1,41 @@
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitLevel {
+    /// Picked from the number of points and how dense they are
+    Auto,
+    Level(u64),
+}
+
+impl Default for SplitLevel {
+    fn default() -> Self {
+        SplitLevel::Level(0)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SplitLevelInput {
+    Number(u64),
+    String(String),
+}
+
+impl<'de> Deserialize<'de> for SplitLevel {
+    fn deserialize<D>(deserializer: D) -> Result<SplitLevel, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        match SplitLevelInput::deserialize(deserializer)? {
+            SplitLevelInput::Number(level) => Ok(SplitLevel::Level(level)),
+            SplitLevelInput::String(s) => match s.to_lowercase().as_str() {
+                "auto" => Ok(SplitLevel::Auto),
+                level => level.parse::<u64>().map(SplitLevel::Level).map_err(|_| {
+                    serde::de::Error::custom(format!(
+                        "split level must be a number or `auto`, received `{}`",
+                        s
+                    ))
+                }),
+            },
+        }
+    }
+}
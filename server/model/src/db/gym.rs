@@ -108,13 +108,13 @@ impl Query {
         let items = Entity::find()
             .from_raw_sql(Statement::from_sql_and_values(
                 DbBackend::MySql,
-                format!("SELECT lat, lon FROM gym WHERE enabled = 1 AND deleted = 0 AND updated >= {} AND ({}) LIMIT 2000000", last_seen, sql_raw(area)).as_str(),
+                format!("SELECT lat, lon, raid_level, raid_end_timestamp FROM gym WHERE enabled = 1 AND deleted = 0 AND updated >= {} AND ({}) LIMIT 2000000", last_seen, sql_raw(area)).as_str(),
                 vec![],
             ))
-            .into_model::<api::point_struct::PointStruct>()
+            .into_model::<Gym>()
             .all(conn)
             .await?;
-        Ok(utils::normalize::fort(items, "g"))
+        Ok(utils::normalize::gym(items))
     }
 
     pub async fn stats(
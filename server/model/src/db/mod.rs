@@ -89,6 +89,14 @@ pub struct Spawnpoint {
     pub despawn_sec: Option<u16>,
 }
 
+#[derive(Debug, Clone, FromQueryResult)]
+pub struct Gym {
+    pub lat: f64,
+    pub lon: f64,
+    pub raid_level: Option<u8>,
+    pub raid_end_timestamp: Option<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum RdmInstanceArea {
@@ -107,11 +115,22 @@ pub struct RdmInstance {
 pub struct GenericData {
     pub i: String,
     pub p: [f64; 2],
+    /// Despawn second of the hour, only known for confirmed spawnpoints
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub d: Option<u16>,
+    /// Weight of the point when clustering with `weighted`, such as the raid level of a gym
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<f64>,
 }
 
 impl GenericData {
     pub fn new(i: String, lat: f64, lon: f64) -> Self {
-        GenericData { i, p: [lat, lon] }
+        GenericData {
+            i,
+            p: [lat, lon],
+            d: None,
+            w: None,
+        }
     }
 }
 
@@ -1,3 +1,4 @@
+use chrono::Utc;
 use serde_json::json;
 
 use super::*;
@@ -15,12 +16,31 @@ pub fn fort(items: api::single_struct::SingleStruct, prefix: &str) -> Vec<db::Ge
         .collect()
 }
 
+/// Gyms weigh the level of their raid while it lasts, `1` otherwise
+pub fn gym(items: Vec<db::Gym>) -> Vec<db::GenericData> {
+    let now = Utc::now().timestamp();
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(i, item)| db::GenericData {
+            w: Some(
+                item.raid_level
+                    .filter(|_| item.raid_end_timestamp.is_some_and(|end| end as i64 > now))
+                    .unwrap_or(0)
+                    .max(1) as f64,
+            ),
+            ..db::GenericData::new(format!("g{}", i), item.lat, item.lon)
+        })
+        .collect()
+}
+
 pub fn spawnpoint(items: Vec<db::Spawnpoint>) -> Vec<db::GenericData> {
     items
         .into_iter()
         .enumerate()
-        .map(|(i, item)| {
-            db::GenericData::new(
+        .map(|(i, item)| db::GenericData {
+            d: item.despawn_sec,
+            ..db::GenericData::new(
                 format!(
                     "{}{}",
                     if item.despawn_sec.is_some() { "v" } else { "u" },